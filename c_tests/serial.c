@@ -0,0 +1,38 @@
+#define REG_SIOCNT   (*(volatile unsigned short *)0x4000128)
+#define REG_SIODATA8 (*(volatile unsigned short *)0x400012A)
+#define REG_RCNT     (*(volatile unsigned short *)0x4000134)
+
+#define UART_115200       0x0003
+#define UART_SEND_FULL    0x0010
+#define UART_RECV_EMPTY   0x0020
+#define UART_8BIT         0x0080
+#define UART_FIFO         0x0100
+#define UART_SEND         0x0400
+#define UART_RECV         0x0800
+#define UART_MODE         0x3000
+
+void serial_putc(char c) {
+    while (REG_SIOCNT & UART_SEND_FULL);
+    REG_SIODATA8 = c;
+}
+
+void serial_puts(const char *s) {
+    while (*s) {
+        serial_putc(*s++);
+    }
+}
+
+int main() {
+    REG_RCNT = 0;
+    REG_SIOCNT = UART_MODE | UART_115200 | UART_8BIT | UART_FIFO | UART_SEND | UART_RECV;
+    serial_puts("Hello over serial!\r\n");
+
+    // Echo back whatever comes in until a newline
+    char c = 0;
+    while (c != '\r' && c != '\n') {
+        while (REG_SIOCNT & UART_RECV_EMPTY);
+        c = REG_SIODATA8;
+        serial_putc(c);
+    }
+    return c;
+}
@@ -77,6 +77,11 @@ impl Cpu {
     }
 
     pub fn step(&mut self, ram: &mut Mem, cycle: usize) -> Option<()> {
+        // Halted until the interrupt controller sees an enabled IRQ flagged
+        if ram.halted() {
+            return Some(());
+        }
+
         let state = self.get_state();
         let mode = self.get_mode();
 
@@ -94,7 +99,7 @@ impl Cpu {
         //         return None;
         //     }
         // }
-        self.irq_input = ram.get_byte(0x4000202) != 0 && ram.get_byte(0x4000208) & 1 == 1;
+        self.irq_input = ram.irq_line();
 
         if self.fiq_input && !self.get_status_bit(BIT_F) || self.irq_input && !self.get_status_bit(BIT_I) {
             let mode = if self.fiq_input { Mode::Fiq } else { Mode::Irq };
@@ -324,13 +329,13 @@ impl Cpu {
                             0b01 => {
                                 debug_string = "LDRH";
                                 self.regs[rd_index] =
-                                    ram.get_halfword(memory_address as usize).little_endian()
+                                    ram.load_halfword(memory_address as usize).little_endian()
                                         as u32;
                             }
                             0b10 => {
                                 debug_string = "LDRSB";
                                 self.regs[rd_index] =
-                                    ram.get_byte(memory_address as usize) as i8 as u32;
+                                    ram.load_byte(memory_address as usize) as i8 as u32;
                             }
                             0b11 => {
                                 debug_string = "LDRSH";
@@ -366,12 +371,12 @@ impl Cpu {
                     if byte {
                         // Byte swap
                         debug_string = "SWPB";
-                        self.regs[rd_index] = ram.get_byte(rn as usize) as u32;
+                        self.regs[rd_index] = ram.load_byte(rn as usize) as u32;
                         ram.set_byte(rn as usize, (rm & 0xff) as u8);
                     } else {
                         // Word swap
                         debug_string = "SWP";
-                        self.regs[rd_index] = ram.get_word(rn as usize).little_endian();
+                        self.regs[rd_index] = ram.load_word(rn as usize).little_endian();
                         ram.set_word(rn as usize, Word::from_u32_le(rm));
                     }
                 } else if opcode >> 5 == 0 && opcode & 0b1100 == 0b1000 && !sets_flags {
@@ -634,10 +639,10 @@ impl Cpu {
                         // Load
                         if byte_quantity {
                             debug_string = "LDRB";
-                            self.regs[rd_index] = ram.get_byte(memory_address as usize) as u32;
+                            self.regs[rd_index] = ram.load_byte(memory_address as usize) as u32;
                         } else {
                             debug_string = "LDR";
-                            let word = ram.get_word(memory_address as usize).little_endian();
+                            let word = ram.load_word(memory_address as usize).little_endian();
                             self.regs[rd_index] = word.rotate_right((memory_address % 4) * 8);
                         }
                         if rd_index == 15 {
@@ -716,7 +721,7 @@ impl Cpu {
                                     self.get_register_index(mode, bit)
                                 };
                                 self.regs[index] =
-                                    ram.get_word(memory_address as usize).little_endian();
+                                    ram.load_word(memory_address as usize).little_endian();
                                 memory_address += 4;
                                 if bit == 15 {
                                     branching = true;
@@ -1127,7 +1132,7 @@ impl Cpu {
                 debug_string = "LDR";
                 rd_index = (instruction >> 8 & 0b111) as usize;
                 let address = ((instruction & 0xff) << 2) + ((self.regs[15] >> 2) << 2);
-                self.regs[rd_index] = ram.get_word(address as usize).little_endian();
+                self.regs[rd_index] = ram.load_word(address as usize).little_endian();
             } else if opcode >> 1 == 5 && instruction >> 9 & 1 == 0 {
                 // Load/store with register offset
                 let load = instruction >> 11 & 1 == 1;
@@ -1137,10 +1142,10 @@ impl Cpu {
                 if load {
                     if byte {
                         debug_string = "LDRB";
-                        self.regs[rd_index] = ram.get_byte(memory_address as usize) as u32;
+                        self.regs[rd_index] = ram.load_byte(memory_address as usize) as u32;
                     } else {
                         debug_string = "LDR";
-                        let data = ram.get_word(memory_address as usize).little_endian();
+                        let data = ram.load_word(memory_address as usize).little_endian();
                         self.regs[rd_index] = data.rotate_right((memory_address % 4) * 8);
                     }
                 } else if byte {
@@ -1164,17 +1169,17 @@ impl Cpu {
                     }
                     1 => {
                         debug_string = "LDSB";
-                        self.regs[rd_index] = ram.get_byte(memory_address as usize) as i8 as u32;
+                        self.regs[rd_index] = ram.load_byte(memory_address as usize) as i8 as u32;
                     }
                     2 => {
                         debug_string = "LDRH";
                         self.regs[rd_index] =
-                            ram.get_halfword(memory_address as usize).little_endian() as u32;
+                            ram.load_halfword(memory_address as usize).little_endian() as u32;
                     }
                     3 => {
                         debug_string = "LDSH";
                         self.regs[rd_index] =
-                            ram.get_halfword(memory_address as usize).little_endian() as i16 as u32;
+                            ram.load_halfword(memory_address as usize).little_endian() as i16 as u32;
                     }
                     _ => panic!(),
                 }
@@ -1190,10 +1195,10 @@ impl Cpu {
                     // Load
                     self.regs[rd_index] = if byte {
                         debug_string = "LDRB";
-                        ram.get_byte(memory_address as usize) as u32
+                        ram.load_byte(memory_address as usize) as u32
                     } else {
                         debug_string = "LDR";
-                        ram.get_word(memory_address as usize)
+                        ram.load_word(memory_address as usize)
                             .little_endian()
                             .rotate_right((memory_address % 4) * 8)
                     };
@@ -1215,7 +1220,7 @@ impl Cpu {
                     // Load
                     debug_string = "LDRH";
                     self.regs[rd_index] =
-                        ram.get_halfword(memory_address as usize).little_endian() as u32;
+                        ram.load_halfword(memory_address as usize).little_endian() as u32;
                 } else {
                     // Store
                     debug_string = "STRH";
@@ -1233,7 +1238,7 @@ impl Cpu {
                 if instruction >> 11 & 1 == 1 {
                     // Load
                     debug_string = "LDR";
-                    self.regs[rd_index] = ram.get_word((sp + offset) as usize).little_endian();
+                    self.regs[rd_index] = ram.load_word((sp + offset) as usize).little_endian();
                 } else {
                     // Store
                     debug_string = "STR";
@@ -1271,12 +1276,12 @@ impl Cpu {
                     let mut memory_address = self.regs[sp_index];
                     for bit in 0..8 {
                         if instruction >> bit & 1 == 1 {
-                            self.regs[bit] = ram.get_word(memory_address as usize).little_endian();
+                            self.regs[bit] = ram.load_word(memory_address as usize).little_endian();
                             memory_address += 4;
                         }
                     }
                     if pc_lr {
-                        self.regs[15] = ram.get_word(memory_address as usize).little_endian();
+                        self.regs[15] = ram.load_word(memory_address as usize).little_endian();
                         memory_address += 4;
                         self.decode_stage = NOP;
                         self.execute_stage = NOP;
@@ -1319,7 +1324,7 @@ impl Cpu {
                     debug_string = "LDMIA";
                     for bit in 0..8 {
                         if instruction >> bit & 1 == 1 {
-                            self.regs[bit] = ram.get_word(memory_address as usize).little_endian();
+                            self.regs[bit] = ram.load_word(memory_address as usize).little_endian();
                             memory_address += 4;
                         }
                     }
@@ -1444,6 +1449,12 @@ impl Cpu {
         Some(())
     }
 
+    // Address of the instruction about to execute
+    pub fn pc(&self) -> u32 {
+        let offset = if let State::Arm = self.get_state() { 8 } else { 4 };
+        self.regs[15].saturating_sub(offset)
+    }
+
     pub fn toggle_debug(&mut self) {
         self.debug = !self.debug;
     }
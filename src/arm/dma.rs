@@ -0,0 +1,308 @@
+pub const DMA_START: usize = 0x40000B0;
+pub const DMA_END: usize = 0x40000DF;
+
+const CHANNEL_SIZE: usize = 12;
+const REG_SOURCE: usize = 0x0;
+const REG_DEST: usize = 0x4;
+const REG_COUNT: usize = 0x8;
+const REG_CONTROL: usize = 0xA;
+
+const CNT_DEST_CONTROL_BIT: u8 = 5;
+const CNT_SOURCE_CONTROL_BIT: u8 = 7;
+const CNT_REPEAT_BIT: u8 = 9;
+const CNT_WORD_BIT: u8 = 10;
+const CNT_TIMING_BIT: u8 = 12;
+const CNT_IRQ_BIT: u8 = 14;
+const CNT_ENABLE_BIT: u8 = 15;
+
+// Channel specific address widths and count sizes
+const SOURCE_MASKS: [u32; 4] = [0x07FF_FFFF, 0x0FFF_FFFF, 0x0FFF_FFFF, 0x0FFF_FFFF];
+const DEST_MASKS: [u32; 4] = [0x07FF_FFFF, 0x07FF_FFFF, 0x07FF_FFFF, 0x0FFF_FFFF];
+const MAX_COUNTS: [u32; 4] = [0x4000, 0x4000, 0x4000, 0x10000];
+
+// Sound FIFO transfers ignore the count and move four words at a time
+const FIFO_TRANSFER_WORDS: usize = 4;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum DmaTiming {
+    Immediate,
+    VBlank,
+    HBlank,
+    Special,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum AddressControl {
+    Increment,
+    Decrement,
+    Fixed,
+    IncrementReload,
+}
+
+impl AddressControl {
+    fn from_bits(bits: u16) -> Self {
+        match bits & 0b11 {
+            0 => AddressControl::Increment,
+            1 => AddressControl::Decrement,
+            2 => AddressControl::Fixed,
+            _ => AddressControl::IncrementReload,
+        }
+    }
+
+    fn step(self, unit: usize) -> isize {
+        match self {
+            AddressControl::Increment | AddressControl::IncrementReload => unit as isize,
+            AddressControl::Decrement => -(unit as isize),
+            AddressControl::Fixed => 0,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Default)]
+struct Channel {
+    source: u32,
+    dest: u32,
+    count: u16,
+    control: u16,
+    internal_source: u32,
+    internal_dest: u32,
+    pending: bool,
+    fifo: bool,
+}
+
+impl Channel {
+    fn bit(&self, bit: u8) -> bool {
+        self.control >> bit & 1 == 1
+    }
+
+    fn timing(&self) -> DmaTiming {
+        match self.control >> CNT_TIMING_BIT & 0b11 {
+            0 => DmaTiming::Immediate,
+            1 => DmaTiming::VBlank,
+            2 => DmaTiming::HBlank,
+            _ => DmaTiming::Special,
+        }
+    }
+}
+
+// One burst for Mem to carry out, since only it can do the reads and writes
+pub struct Transfer {
+    pub channel: usize,
+    pub source: usize,
+    pub dest: usize,
+    pub count: usize,
+    pub word: bool,
+    pub source_step: isize,
+    pub dest_step: isize,
+}
+
+impl Transfer {
+    // Rough cost: two setup cycles, then a read and a write per unit
+    pub fn cycles(&self) -> usize {
+        2 + 2 * self.count
+    }
+}
+
+#[derive(Default)]
+pub struct Dma {
+    channels: [Channel; 4],
+}
+
+impl Dma {
+    pub fn new() -> Self {
+        Dma {
+            channels: [Channel::default(); 4],
+        }
+    }
+
+    pub fn read(&self, offset: usize) -> Option<u16> {
+        match offset % CHANNEL_SIZE {
+            REG_CONTROL => Some(self.channels[offset / CHANNEL_SIZE].control),
+            // Everything else is write only
+            _ => Some(0),
+        }
+    }
+
+    pub fn write(&mut self, offset: usize, data: u16) {
+        let index = offset / CHANNEL_SIZE;
+        let channel = &mut self.channels[index];
+        match offset % CHANNEL_SIZE {
+            REG_SOURCE => channel.source = channel.source & 0xFFFF_0000 | data as u32,
+            0x2 => channel.source = channel.source & 0xFFFF | (data as u32) << 16,
+            REG_DEST => channel.dest = channel.dest & 0xFFFF_0000 | data as u32,
+            0x6 => channel.dest = channel.dest & 0xFFFF | (data as u32) << 16,
+            REG_COUNT => channel.count = data,
+            _ => {
+                let was_enabled = channel.bit(CNT_ENABLE_BIT);
+                channel.control = data;
+                if !channel.bit(CNT_ENABLE_BIT) {
+                    channel.pending = false;
+                } else if !was_enabled {
+                    channel.internal_source = channel.source & SOURCE_MASKS[index];
+                    channel.internal_dest = channel.dest & DEST_MASKS[index];
+                    // Special timing on DMA1/2 feeds the sound FIFOs
+                    channel.fifo = (index == 1 || index == 2) && channel.timing() == DmaTiming::Special;
+                    channel.pending = channel.timing() == DmaTiming::Immediate;
+                }
+            }
+        }
+    }
+
+    pub fn trigger(&mut self, timing: DmaTiming) {
+        for channel in self.channels.iter_mut() {
+            if channel.bit(CNT_ENABLE_BIT) && channel.timing() == timing && !channel.fifo {
+                channel.pending = true;
+            }
+        }
+    }
+
+    // A sound FIFO at the given address is running low
+    pub fn trigger_fifo(&mut self, fifo_addr: u32) {
+        for channel in self.channels.iter_mut() {
+            if channel.bit(CNT_ENABLE_BIT) && channel.fifo && channel.internal_dest == fifo_addr {
+                channel.pending = true;
+            }
+        }
+    }
+
+    // Highest priority pending channel first
+    pub fn next_transfer(&mut self) -> Option<Transfer> {
+        let index = self.channels.iter().position(|channel| channel.pending)?;
+        let channel = &mut self.channels[index];
+        channel.pending = false;
+        let (word, count, dest_control) = if channel.fifo {
+            (true, FIFO_TRANSFER_WORDS, AddressControl::Fixed)
+        } else {
+            let count = match channel.count as u32 & (MAX_COUNTS[index] - 1) {
+                0 => MAX_COUNTS[index],
+                count => count,
+            };
+            (
+                channel.bit(CNT_WORD_BIT),
+                count as usize,
+                AddressControl::from_bits(channel.control >> CNT_DEST_CONTROL_BIT),
+            )
+        };
+        let unit = if word { 4 } else { 2 };
+        let source_control = AddressControl::from_bits(channel.control >> CNT_SOURCE_CONTROL_BIT);
+        Some(Transfer {
+            channel: index,
+            source: (channel.internal_source & !(unit as u32 - 1)) as usize,
+            dest: (channel.internal_dest & !(unit as u32 - 1)) as usize,
+            count,
+            word,
+            source_step: source_control.step(unit),
+            dest_step: dest_control.step(unit),
+        })
+    }
+
+    // Stores where the transfer left off and returns whether it should raise an IRQ
+    pub fn finish(&mut self, transfer: &Transfer) -> bool {
+        let index = transfer.channel;
+        let channel = &mut self.channels[index];
+        let count = transfer.count as isize;
+        channel.internal_source = (transfer.source as isize + transfer.source_step * count) as u32 & SOURCE_MASKS[index];
+        channel.internal_dest = (transfer.dest as isize + transfer.dest_step * count) as u32 & DEST_MASKS[index];
+        if channel.bit(CNT_REPEAT_BIT) && channel.timing() != DmaTiming::Immediate {
+            if AddressControl::from_bits(channel.control >> CNT_DEST_CONTROL_BIT) == AddressControl::IncrementReload {
+                channel.internal_dest = channel.dest & DEST_MASKS[index];
+            }
+        } else {
+            channel.control &= !(1 << CNT_ENABLE_BIT);
+        }
+        channel.bit(CNT_IRQ_BIT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENABLE: u16 = 1 << CNT_ENABLE_BIT;
+
+    fn start(dma: &mut Dma, index: usize, source: u32, dest: u32, count: u16, control: u16) {
+        let base = index * CHANNEL_SIZE;
+        // Only turning a channel on latches its addresses
+        dma.write(base + REG_CONTROL, 0);
+        dma.write(base + REG_SOURCE, source as u16);
+        dma.write(base + REG_SOURCE + 2, (source >> 16) as u16);
+        dma.write(base + REG_DEST, dest as u16);
+        dma.write(base + REG_DEST + 2, (dest >> 16) as u16);
+        dma.write(base + REG_COUNT, count);
+        dma.write(base + REG_CONTROL, control | ENABLE);
+    }
+
+    #[test]
+    fn a_zero_count_means_the_channel_maximum() {
+        let mut dma = Dma::new();
+        for &(index, count, expected) in &[(0, 0, 0x4000), (0, 0xC001, 1), (3, 0, 0x10000), (3, 0x8001, 0x8001)] {
+            start(&mut dma, index, 0x2000000, 0x3000000, count, 0);
+            let transfer = dma.next_transfer().unwrap();
+            assert_eq!((transfer.channel, transfer.count), (index, expected));
+        }
+    }
+
+    #[test]
+    fn address_control_picks_the_steps() {
+        let mut dma = Dma::new();
+        // Halfwords: source decrementing, destination fixed
+        start(&mut dma, 0, 0x2000101, 0x3000003, 4, 1 << CNT_SOURCE_CONTROL_BIT | 2 << CNT_DEST_CONTROL_BIT);
+        let transfer = dma.next_transfer().unwrap();
+        assert_eq!((transfer.source, transfer.dest), (0x2000100, 0x3000002));
+        assert_eq!((transfer.source_step, transfer.dest_step), (-2, 0));
+        assert!(!transfer.word);
+
+        // Words: source fixed, destination incrementing
+        start(&mut dma, 3, 0x8000002, 0x6000000, 4, 2 << CNT_SOURCE_CONTROL_BIT | 1 << CNT_WORD_BIT);
+        let transfer = dma.next_transfer().unwrap();
+        assert_eq!((transfer.source, transfer.dest), (0x8000000, 0x6000000));
+        assert_eq!((transfer.source_step, transfer.dest_step), (0, 4));
+        assert!(transfer.word);
+        assert!(dma.next_transfer().is_none());
+    }
+
+    #[test]
+    fn repeating_channels_reload_the_destination_and_stay_enabled() {
+        let mut dma = Dma::new();
+        let control = 1 << CNT_REPEAT_BIT | 3 << CNT_DEST_CONTROL_BIT | 1 << CNT_TIMING_BIT | 1 << CNT_IRQ_BIT;
+        start(&mut dma, 0, 0x2000000, 0x3000000, 8, control);
+        assert!(dma.next_transfer().is_none());
+        dma.trigger(DmaTiming::HBlank);
+        assert!(dma.next_transfer().is_none());
+
+        dma.trigger(DmaTiming::VBlank);
+        let transfer = dma.next_transfer().unwrap();
+        assert!(dma.finish(&transfer));
+        dma.trigger(DmaTiming::VBlank);
+        let transfer = dma.next_transfer().unwrap();
+        assert_eq!((transfer.source, transfer.dest), (0x2000010, 0x3000000));
+        assert_eq!(dma.read(REG_CONTROL), Some(control | ENABLE));
+
+        // Without repeat the channel turns itself off
+        start(&mut dma, 0, 0x2000000, 0x3000000, 8, 0);
+        let transfer = dma.next_transfer().unwrap();
+        assert!(!dma.finish(&transfer));
+        assert_eq!(dma.read(REG_CONTROL), Some(0));
+    }
+
+    #[test]
+    fn fifo_channels_move_four_words_to_a_fixed_destination() {
+        let mut dma = Dma::new();
+        let control = 1 << CNT_REPEAT_BIT | 3 << CNT_TIMING_BIT;
+        start(&mut dma, 1, 0x2000000, 0x40000A0, 1, control);
+        dma.trigger(DmaTiming::Special);
+        assert!(dma.next_transfer().is_none());
+        dma.trigger_fifo(0x40000A4);
+        assert!(dma.next_transfer().is_none());
+
+        dma.trigger_fifo(0x40000A0);
+        let transfer = dma.next_transfer().unwrap();
+        assert_eq!(transfer.count, FIFO_TRANSFER_WORDS);
+        assert!(transfer.word);
+        assert_eq!((transfer.source_step, transfer.dest_step), (4, 0));
+        dma.finish(&transfer);
+        dma.trigger_fifo(0x40000A0);
+        let transfer = dma.next_transfer().unwrap();
+        assert_eq!((transfer.source, transfer.dest), (0x2000010, 0x40000A0));
+    }
+}
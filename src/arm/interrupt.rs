@@ -0,0 +1,173 @@
+pub const REG_IE: usize = 0x4000200;
+pub const REG_IF: usize = 0x4000202;
+pub const REG_IME: usize = 0x4000208;
+pub const REG_HALTCNT: usize = 0x4000300; // high byte of the POSTFLG halfword
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Interrupt {
+    VBlank,
+    HBlank,
+    VCounter,
+    Timer0,
+    Timer1,
+    Timer2,
+    Timer3,
+    Serial,
+    Dma0,
+    Dma1,
+    Dma2,
+    Dma3,
+    Keypad,
+    GamePak
+}
+
+impl Interrupt {
+    fn bit(self) -> u16 {
+        1 << match self {
+            Interrupt::VBlank => 0,
+            Interrupt::HBlank => 1,
+            Interrupt::VCounter => 2,
+            Interrupt::Timer0 => 3,
+            Interrupt::Timer1 => 4,
+            Interrupt::Timer2 => 5,
+            Interrupt::Timer3 => 6,
+            Interrupt::Serial => 7,
+            Interrupt::Dma0 => 8,
+            Interrupt::Dma1 => 9,
+            Interrupt::Dma2 => 10,
+            Interrupt::Dma3 => 11,
+            Interrupt::Keypad => 12,
+            Interrupt::GamePak => 13
+        }
+    }
+}
+
+// IF latches every request. IE and IME only gate the IRQ line into the CPU, and halt
+// ends as soon as an enabled interrupt is flagged, whatever IME says.
+#[derive(Default)]
+pub struct InterruptController {
+    enable: u16,
+    flags: u16,
+    master_enable: bool,
+    halted: bool,
+}
+
+impl InterruptController {
+    pub fn new() -> Self {
+        InterruptController {
+            enable: 0,
+            flags: 0,
+            master_enable: false,
+            halted: false,
+        }
+    }
+
+    pub fn request(&mut self, kind: Interrupt) {
+        self.flags |= kind.bit();
+        self.update_halt();
+    }
+
+    pub fn irq_line(&self) -> bool {
+        self.master_enable && self.enable & self.flags != 0
+    }
+
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+
+    fn update_halt(&mut self) {
+        if self.enable & self.flags != 0 {
+            self.halted = false;
+        }
+    }
+
+    pub fn read(&self, byte_index: usize) -> Option<u16> {
+        match byte_index {
+            REG_IE => Some(self.enable),
+            REG_IF => Some(self.flags),
+            REG_IME => Some(self.master_enable as u16),
+            _ => None,
+        }
+    }
+
+    // mask has the bits of data that were actually written, so byte writes to IF only
+    // acknowledge the byte they hit
+    pub fn write(&mut self, byte_index: usize, data: u16, mask: u16) {
+        match byte_index {
+            REG_IE => {
+                self.enable = data & 0x3FFF;
+                self.update_halt();
+            }
+            REG_IF => self.flags &= !(data & mask),
+            REG_IME => self.master_enable = data & 1 == 1,
+            // Stop mode (bit 7 set) is treated like halt
+            REG_HALTCNT if mask & 0xFF00 != 0 => {
+                self.halted = true;
+                self.update_halt();
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writing_1_to_if_acknowledges() {
+        let mut interrupts = InterruptController::new();
+        interrupts.request(Interrupt::VBlank);
+        interrupts.request(Interrupt::Timer0);
+        assert_eq!(interrupts.read(REG_IF), Some(0b1001));
+        interrupts.write(REG_IF, 0b0001, 0xFFFF);
+        assert_eq!(interrupts.read(REG_IF), Some(0b1000));
+        interrupts.write(REG_IF, 0, 0xFFFF);
+        assert_eq!(interrupts.read(REG_IF), Some(0b1000));
+    }
+
+    #[test]
+    fn byte_writes_to_if_only_acknowledge_their_byte() {
+        let mut interrupts = InterruptController::new();
+        interrupts.request(Interrupt::VBlank);
+        interrupts.request(Interrupt::Dma0);
+        // A store to 0x4000202 merged with whatever was in the other byte
+        interrupts.write(REG_IF, 0xFFFF, 0x00FF);
+        assert_eq!(interrupts.read(REG_IF), Some(0x0100));
+        interrupts.write(REG_IF, 0xFFFF, 0xFF00);
+        assert_eq!(interrupts.read(REG_IF), Some(0));
+    }
+
+    #[test]
+    fn ime_gates_the_irq_line_but_not_if() {
+        let mut interrupts = InterruptController::new();
+        interrupts.write(REG_IE, 1, 0xFFFF);
+        interrupts.request(Interrupt::VBlank);
+        assert!(!interrupts.irq_line());
+        interrupts.write(REG_IME, 1, 0xFFFF);
+        assert!(interrupts.irq_line());
+        interrupts.write(REG_IF, 1, 0xFFFF);
+        assert!(!interrupts.irq_line());
+    }
+
+    #[test]
+    fn halt_ends_on_an_enabled_flag_even_with_ime_off() {
+        let mut interrupts = InterruptController::new();
+        interrupts.write(REG_IE, 1, 0xFFFF);
+        interrupts.write(REG_HALTCNT, 0, 0xFF00);
+        assert!(interrupts.halted());
+        interrupts.request(Interrupt::HBlank);
+        assert!(interrupts.halted());
+        interrupts.request(Interrupt::VBlank);
+        assert!(!interrupts.halted());
+        assert!(!interrupts.irq_line());
+
+        // Halting with an enabled flag already set does nothing
+        interrupts.write(REG_HALTCNT, 0, 0xFF00);
+        assert!(!interrupts.halted());
+        // Nor does a write that only touches POSTFLG
+        interrupts.write(REG_IF, 0xFFFF, 0xFFFF);
+        interrupts.write(REG_HALTCNT, 0, 0x00FF);
+        assert!(!interrupts.halted());
+    }
+}
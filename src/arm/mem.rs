@@ -2,27 +2,47 @@ use anyhow::Result;
 use std::io::{ErrorKind, Read, Write};
 
 use crate::arm::common::{HalfWord, Word};
+use crate::arm::dma::{Dma, DmaTiming, Transfer, DMA_END, DMA_START};
+use crate::arm::interrupt::{InterruptController, REG_HALTCNT, REG_IE, REG_IF, REG_IME};
+use crate::arm::timer::{Timers, TIMER_END, TIMER_START};
+use crate::audio::fifo::SoundFifo;
+use crate::cart::backup::{Backup, SaveType};
+use crate::cart::gpio::{Gpio, GPIO_END, GPIO_START};
+use crate::cart::rtc::ClockSource;
+use crate::graphics::affine::{self, REFERENCE_END, REFERENCE_START};
+use crate::graphics::timing::{DISPSTAT_FLAGS, REG_DISPSTAT, REG_VCOUNT};
+use crate::link::joybus::{ConsolePort, JOYBUS_END, JOYBUS_START};
+use crate::link::sio::{Link, Sio, REG_RCNT, SIO_END, SIO_START};
+use crate::link::uart::HostPort;
+
+pub use crate::arm::interrupt::Interrupt;
+
+const IO_START: usize = 0x4000000;
+const IO_END: usize = 0x40003FE;
+const REG_SOUNDCNT_H: usize = 0x4000082;
+const REG_KEYINPUT: usize = 0x4000130;
+const FIFO_ADDRS: [usize; 2] = [0x40000A0, 0x40000A4];
+const ROM_START: usize = 0x8000000;
+const EEPROM_START: usize = 0xD000000;
+const EEPROM_END: usize = 0xDFFFFFF;
+const BACKUP_START: usize = 0xE000000;
 
 pub struct Mem {
     mem: Vec<u8>,
-}
-
-#[derive(Copy, Clone, PartialEq, Debug)]
-pub enum Interrupt {
-    VBlank,
-    HBlank,
-    VCounter,
-    Timer0,
-    Timer1,
-    Timer2,
-    Timer3,
-    Serial,
-    Dma0,
-    Dma1,
-    Dma2,
-    Dma3,
-    Keypad,
-    GamePak
+    rom_len: usize,
+    backup: Backup,
+    gpio: Option<Gpio>,
+    timers: Timers,
+    dma: Dma,
+    stall_cycles: usize,
+    fifos: [SoundFifo; 2],
+    interrupts: InterruptController,
+    sio: Sio,
+    // Set by the display as it moves through the frame
+    display_flags: u16,
+    vcount: u16,
+    // BG2/BG3 reference points written since the display last looked
+    affine_writes: u8,
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -45,7 +65,25 @@ impl Mem {
         unsafe {
             vec.set_len(size);
         }
-        Mem { mem: vec }
+        let mut mem = Mem {
+            mem: vec,
+            rom_len: 0,
+            backup: Backup::None,
+            gpio: None,
+            timers: Timers::new(),
+            dma: Dma::new(),
+            stall_cycles: 0,
+            fifos: [SoundFifo::new(), SoundFifo::new()],
+            interrupts: InterruptController::new(),
+            sio: Sio::new(),
+            display_flags: 0,
+            vcount: 0,
+            affine_writes: 0,
+        };
+        // KEYINPUT is active low, so nothing is held at power on
+        mem.mem[REG_KEYINPUT] = 0xFF;
+        mem.mem[REG_KEYINPUT + 1] = 0x03;
+        mem
     }
 
     pub fn load(&mut self, first_byte: usize, mut file: impl Read) -> std::io::Result<()> {
@@ -58,6 +96,218 @@ impl Mem {
         Ok(())
     }
 
+    pub fn load_rom(&mut self, rom: &[u8]) {
+        let len = rom.len().min(0x2000000);
+        self.mem[ROM_START..ROM_START + len].copy_from_slice(&rom[..len]);
+        self.rom_len = len;
+    }
+
+    pub fn set_save_type(&mut self, save_type: SaveType) {
+        self.backup = Backup::new(save_type);
+    }
+
+    pub fn backup(&self) -> &Backup {
+        &self.backup
+    }
+
+    pub fn backup_mut(&mut self) -> &mut Backup {
+        &mut self.backup
+    }
+
+    pub fn attach_rtc(&mut self, clock: ClockSource) {
+        self.gpio = Some(Gpio::new(clock));
+    }
+
+    pub fn attach_link(&mut self, link: Link) {
+        self.sio.attach(link);
+    }
+
+    pub fn attach_serial_port(&mut self, port: HostPort) {
+        self.sio.attach_uart(port);
+    }
+
+    pub fn attach_joybus(&mut self, port: ConsolePort) {
+        self.sio.attach_joybus(port);
+    }
+
+    pub fn step(&mut self, cycles: usize) {
+        self.stall_cycles = self.stall_cycles.saturating_sub(cycles);
+        let overflows = self.timers.step(cycles);
+        for (i, &count) in overflows.iter().enumerate() {
+            if count > 0 {
+                self.timer_overflow(i, count);
+            }
+        }
+        if let Some(gpio) = &mut self.gpio {
+            if gpio.step(cycles) {
+                self.request_irq(Interrupt::GamePak);
+            }
+        }
+        if self.sio.step(cycles) {
+            self.request_irq(Interrupt::Serial);
+        }
+        while let Some(transfer) = self.dma.next_transfer() {
+            self.run_dma(transfer);
+        }
+    }
+
+    pub fn set_display_status(&mut self, flags: u16, vcount: u16) {
+        self.display_flags = flags & DISPSTAT_FLAGS;
+        self.vcount = vcount;
+    }
+
+    // One bit per affine::reference_index
+    pub fn take_affine_writes(&mut self) -> u8 {
+        std::mem::take(&mut self.affine_writes)
+    }
+
+    // The CPU sits out while DMA owns the bus
+    pub fn stalled(&self) -> bool {
+        self.stall_cycles > 0
+    }
+
+    pub fn trigger_dma(&mut self, timing: DmaTiming) {
+        self.dma.trigger(timing);
+    }
+
+    fn run_dma(&mut self, transfer: Transfer) {
+        // The EEPROM size is only known from how many bits the game sends it
+        if transfer.channel == 3 && self.is_eeprom_access(transfer.dest) {
+            match transfer.count {
+                9 | 73 => self.backup.set_eeprom_addr_bits(6),
+                17 | 81 => self.backup.set_eeprom_addr_bits(14),
+                _ => {}
+            }
+        }
+        let mut source = transfer.source;
+        let mut dest = transfer.dest;
+        for _ in 0..transfer.count {
+            if transfer.word {
+                let data = self.get_word(source);
+                self.set_word(dest, data);
+            } else {
+                let data = self.get_halfword(source);
+                self.set_halfword(dest, data);
+            }
+            source = (source as isize + transfer.source_step) as usize;
+            dest = (dest as isize + transfer.dest_step) as usize;
+        }
+        self.stall_cycles += transfer.cycles();
+        if self.dma.finish(&transfer) {
+            let kind = match transfer.channel {
+                0 => Interrupt::Dma0,
+                1 => Interrupt::Dma1,
+                2 => Interrupt::Dma2,
+                _ => Interrupt::Dma3,
+            };
+            self.request_irq(kind);
+        }
+    }
+
+    fn timer_overflow(&mut self, timer: usize, count: usize) {
+        let sound_control = u16::from_le_bytes([self.mem[REG_SOUNDCNT_H], self.mem[REG_SOUNDCNT_H + 1]]);
+        for (index, fifo) in self.fifos.iter_mut().enumerate() {
+            if (sound_control >> (10 + 4 * index) & 1) as usize != timer {
+                continue;
+            }
+            for _ in 0..count {
+                fifo.pop();
+            }
+            if fifo.needs_refill() {
+                self.dma.trigger_fifo(FIFO_ADDRS[index] as u32);
+            }
+        }
+        if self.timers.irq_enabled(timer) {
+            let kind = match timer {
+                0 => Interrupt::Timer0,
+                1 => Interrupt::Timer1,
+                2 => Interrupt::Timer2,
+                _ => Interrupt::Timer3,
+            };
+            self.request_irq(kind);
+        }
+    }
+
+    // I/O registers with side effects. Writes land in mem first, so byte writes are merged
+    // with the last value written before the register sees them; mask says which bytes
+    // the write really touched.
+    #[inline(always)]
+    fn io_read(&self, byte_index: usize) -> Option<u16> {
+        match byte_index {
+            REG_IE | REG_IF | REG_IME => self.interrupts.read(byte_index),
+            REG_DISPSTAT => {
+                let settings = u16::from_le_bytes([self.mem[REG_DISPSTAT], self.mem[REG_DISPSTAT + 1]]);
+                Some(settings & !DISPSTAT_FLAGS | self.display_flags)
+            }
+            REG_VCOUNT => Some(self.vcount),
+            TIMER_START..=TIMER_END => Some(self.timers.read(byte_index - TIMER_START)),
+            DMA_START..=DMA_END => self.dma.read(byte_index - DMA_START),
+            SIO_START..=SIO_END => Some(self.sio.read(byte_index - SIO_START)),
+            REG_RCNT => Some(self.sio.rcnt()),
+            JOYBUS_START..=JOYBUS_END => self.sio.read_joybus(byte_index - JOYBUS_START),
+            _ => None,
+        }
+    }
+
+    #[inline(always)]
+    fn io_write(&mut self, byte_index: usize, mask: u16) {
+        if !(IO_START..=IO_END).contains(&byte_index) {
+            return;
+        }
+        let data = u16::from_le_bytes([self.mem[byte_index], self.mem[byte_index + 1]]);
+        match byte_index {
+            REG_IE | REG_IF | REG_IME | REG_HALTCNT => self.interrupts.write(byte_index, data, mask),
+            TIMER_START..=TIMER_END => self.timers.write(byte_index - TIMER_START, data),
+            DMA_START..=DMA_END => self.dma.write(byte_index - DMA_START, data),
+            SIO_START..=SIO_END => self.sio.write(byte_index - SIO_START, data, mask),
+            REG_RCNT => self.sio.set_rcnt(data),
+            REFERENCE_START..=REFERENCE_END => {
+                if let Some(index) = affine::reference_index(byte_index) {
+                    self.affine_writes |= 1 << index;
+                }
+            }
+            JOYBUS_START..=JOYBUS_END => self.sio.write_joybus(byte_index - JOYBUS_START, data, mask),
+            REG_SOUNDCNT_H => {
+                for fifo in 0..2 {
+                    if data >> (11 + 4 * fifo) & 1 == 1 {
+                        self.fifos[fifo].reset();
+                    }
+                }
+                // The reset bits always read back as zero
+                self.mem[REG_SOUNDCNT_H + 1] &= !0b1000_1000;
+            }
+            0x40000A0..=0x40000A7 => self.fifos[(byte_index - FIFO_ADDRS[0]) / 4].push(data, mask),
+            _ => {}
+        }
+    }
+
+    #[inline(always)]
+    fn gpio_read(&self, byte_index: usize) -> Option<u16> {
+        match &self.gpio {
+            Some(gpio) if (GPIO_START..=GPIO_END).contains(&byte_index) => gpio.read(byte_index - GPIO_START),
+            _ => None,
+        }
+    }
+
+    #[inline(always)]
+    fn gpio_write(&mut self, byte_index: usize, data: u16) -> bool {
+        match &mut self.gpio {
+            Some(gpio) if (GPIO_START..=GPIO_END).contains(&byte_index) => {
+                gpio.write(byte_index - GPIO_START, data);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Carts of 16MB or less see EEPROM across all of 0xD000000, bigger ones only at the top
+    #[inline(always)]
+    fn is_eeprom_access(&self, byte_index: usize) -> bool {
+        self.backup.is_eeprom()
+            && (EEPROM_START..=EEPROM_END).contains(&byte_index)
+            && (self.rom_len <= 0x1000000 || byte_index >= 0xDFFFF00)
+    }
+
     pub fn save(&self, first_byte: usize, last_byte: usize, mut file: impl Write) -> std::io::Result<()> {
         let buf = &self.mem[first_byte..=last_byte];
         file.write_all(buf)
@@ -65,8 +315,8 @@ impl Mem {
 
     #[inline(always)]
     pub fn set_byte(&mut self, mut byte_index: usize, data: u8) {
-        if byte_index == 0x4000202 {
-            self.mem[byte_index] &= !data;
+        if byte_index >= BACKUP_START {
+            self.backup.write_byte(byte_index - BACKUP_START, data);
             return;
         }
         if (0x3FFFF00..=0x3FFFFFF).contains(&byte_index) {
@@ -76,14 +326,21 @@ impl Mem {
         } else if (0xC000000..=0xDFFFFFF).contains(&byte_index) {
             byte_index -= 0x4000000;
         }
+        if self.gpio_write(byte_index, data as u16) {
+            return;
+        }
         self.mem[byte_index] = data;
+        self.io_write(byte_index & !1, if byte_index & 1 == 0 { 0x00FF } else { 0xFF00 });
     }
 
     #[inline(always)]
     pub fn set_halfword(&mut self, mut byte_index: usize, data: HalfWord) {
-        if byte_index == 0x4000202 {
-            self.mem[byte_index] &= !data.bytes[0];
-            self.mem[byte_index + 1] &= !data.bytes[1];
+        if self.is_eeprom_access(byte_index) {
+            self.backup.write_eeprom(data.little_endian());
+            return;
+        }
+        if byte_index >= BACKUP_START {
+            self.backup.write_byte(byte_index - BACKUP_START, data.bytes[0]);
             return;
         }
         if (0x3FFFF00..=0x3FFFFFF).contains(&byte_index) {
@@ -93,17 +350,18 @@ impl Mem {
         } else if (0xC000000..=0xDFFFFFF).contains(&byte_index) {
             byte_index -= 0x4000000;
         }
+        if self.gpio_write(byte_index, data.little_endian()) {
+            return;
+        }
         self.mem[byte_index] = data.bytes[0];
         self.mem[byte_index + 1] = data.bytes[1];
+        self.io_write(byte_index, 0xFFFF);
     }
 
     #[inline(always)]
     pub fn set_word(&mut self, mut byte_index: usize, data: Word) {
-        if byte_index == 0x4000202 {
-            self.mem[byte_index] &= !data.bytes[0];
-            self.mem[byte_index + 1] &= !data.bytes[1];
-            self.mem[byte_index + 2] = data.bytes[2];
-            self.mem[byte_index + 3] = data.bytes[3];
+        if byte_index >= BACKUP_START {
+            self.backup.write_byte(byte_index - BACKUP_START, data.bytes[0]);
             return;
         }
         if (0x3FFFF00..=0x3FFFFFF).contains(&byte_index) {
@@ -113,14 +371,23 @@ impl Mem {
         } else if (0xC000000..=0xDFFFFFF).contains(&byte_index) {
             byte_index -= 0x4000000;
         }
+        if self.gpio_write(byte_index, data.little_endian() as u16) {
+            self.gpio_write(byte_index + 2, (data.little_endian() >> 16) as u16);
+            return;
+        }
         self.mem[byte_index] = data.bytes[0];
         self.mem[byte_index + 1] = data.bytes[1];
         self.mem[byte_index + 2] = data.bytes[2];
         self.mem[byte_index + 3] = data.bytes[3];
+        self.io_write(byte_index, 0xFFFF);
+        self.io_write(byte_index + 2, 0xFFFF);
     }
 
     #[inline(always)]
     pub fn get_byte(&self, mut byte_index: usize) -> u8 {
+        if byte_index >= BACKUP_START {
+            return self.backup.read_byte(byte_index - BACKUP_START);
+        }
         if (0x3FFFF00..=0x3FFFFFF).contains(&byte_index) {
             byte_index -= 0xFF8000;
         } else if (0xA000000..=0xBFFFFFF).contains(&byte_index) {
@@ -128,11 +395,22 @@ impl Mem {
         } else if (0xC000000..=0xDFFFFFF).contains(&byte_index) {
             byte_index -= 0x4000000;
         }
+        if let Some(data) = self.io_read(byte_index & !1).or_else(|| self.gpio_read(byte_index & !1)) {
+            return (data >> (8 * (byte_index & 1))) as u8;
+        }
         self.mem[byte_index]
     }
 
     #[inline(always)]
     pub fn get_halfword(&self, mut byte_index: usize) -> HalfWord {
+        if self.is_eeprom_access(byte_index) {
+            return HalfWord::from_u16_le(self.backup.read_eeprom());
+        }
+        if byte_index >= BACKUP_START {
+            // The backup bus is 8 bits wide, so the byte shows up in every lane
+            let byte = self.backup.read_byte(byte_index - BACKUP_START);
+            return HalfWord { bytes: [byte, byte] };
+        }
         if (0x3FFFF00..=0x3FFFFFF).contains(&byte_index) {
             byte_index -= 0xFF8000;
         } else if (0xA000000..=0xBFFFFFF).contains(&byte_index) {
@@ -140,6 +418,9 @@ impl Mem {
         } else if (0xC000000..=0xDFFFFFF).contains(&byte_index) {
             byte_index -= 0x4000000;
         }
+        if let Some(data) = self.io_read(byte_index).or_else(|| self.gpio_read(byte_index)) {
+            return HalfWord::from_u16_le(data);
+        }
         HalfWord {
             bytes: [self.mem[byte_index], self.mem[byte_index + 1]],
         }
@@ -147,6 +428,10 @@ impl Mem {
 
     #[inline(always)]
     pub fn get_word(&self, mut byte_index: usize) -> Word {
+        if byte_index >= BACKUP_START {
+            let byte = self.backup.read_byte(byte_index - BACKUP_START);
+            return Word { bytes: [byte; 4] };
+        }
         if (0x3FFFF00..=0x3FFFFFF).contains(&byte_index) {
             byte_index -= 0xFF8000;
         } else if (0xA000000..=0xBFFFFFF).contains(&byte_index) {
@@ -154,6 +439,13 @@ impl Mem {
         } else if (0xC000000..=0xDFFFFFF).contains(&byte_index) {
             byte_index -= 0x4000000;
         }
+        if let Some(low) = self.io_read(byte_index).or_else(|| self.gpio_read(byte_index)) {
+            let high = self
+                .io_read(byte_index + 2)
+                .or_else(|| self.gpio_read(byte_index + 2))
+                .unwrap_or_else(|| u16::from_le_bytes([self.mem[byte_index + 2], self.mem[byte_index + 3]]));
+            return Word::from_u32_le(low as u32 | (high as u32) << 16);
+        }
         Word {
             bytes: [
                 self.mem[byte_index],
@@ -164,6 +456,35 @@ impl Mem {
         }
     }
 
+    // Loads by the CPU. Unlike the getters, which debugging, cheats and RAM search use too,
+    // these can consume data from registers like SIODATA8.
+    pub fn load_byte(&mut self, byte_index: usize) -> u8 {
+        let data = self.get_byte(byte_index);
+        self.io_loaded(byte_index & !1);
+        data
+    }
+
+    pub fn load_halfword(&mut self, byte_index: usize) -> HalfWord {
+        let data = self.get_halfword(byte_index);
+        self.io_loaded(byte_index);
+        data
+    }
+
+    pub fn load_word(&mut self, byte_index: usize) -> Word {
+        let data = self.get_word(byte_index);
+        self.io_loaded(byte_index);
+        self.io_loaded(byte_index + 2);
+        data
+    }
+
+    fn io_loaded(&mut self, byte_index: usize) {
+        if (SIO_START..=SIO_END).contains(&byte_index) {
+            self.sio.loaded(byte_index - SIO_START);
+        } else if (JOYBUS_START..=JOYBUS_END).contains(&byte_index) {
+            self.sio.joybus_loaded(byte_index - JOYBUS_START);
+        }
+    }
+
     pub fn key_event(&mut self, key: Key, down: bool) {
         let bit = match key {
             Key::A => 0,
@@ -196,31 +517,14 @@ impl Mem {
     }
 
     pub fn request_irq(&mut self, kind: Interrupt) {
-        if self.mem[0x4000208] & 1 == 0 { // IME
-            return;
-        }
-        let bit = match kind {
-            Interrupt::VBlank => 0,
-            Interrupt::HBlank => 1,
-            Interrupt::VCounter => 2,
-            Interrupt::Timer0 => 3,
-            Interrupt::Timer1 => 4,
-            Interrupt::Timer2 => 5,
-            Interrupt::Timer3 => 6,
-            Interrupt::Serial => 7,
-            Interrupt::Dma0 => 8,
-            Interrupt::Dma1 => 9,
-            Interrupt::Dma2 => 10,
-            Interrupt::Dma3 => 11,
-            Interrupt::Keypad => 12,
-            Interrupt::GamePak => 13
-        };
-        if self.mem[0x4000200] >> bit & 1 == 1 { // IE
-            if bit < 8 {
-                self.mem[0x4000202] |= 1 << bit;
-            } else {
-                self.mem[0x4000203] |= 1 << (bit - 8);
-            }
-        }
+        self.interrupts.request(kind);
+    }
+
+    pub fn irq_line(&self) -> bool {
+        self.interrupts.irq_line()
+    }
+
+    pub fn halted(&self) -> bool {
+        self.interrupts.halted()
     }
 }
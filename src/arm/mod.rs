@@ -1,3 +1,6 @@
 pub mod common;
 pub mod cpu;
+pub mod dma;
+pub mod interrupt;
 pub mod mem;
+pub mod timer;
@@ -0,0 +1,106 @@
+pub const TIMER_START: usize = 0x4000100;
+pub const TIMER_END: usize = 0x400010F;
+
+const PRESCALER_SHIFTS: [u32; 4] = [0, 6, 8, 10]; // 1, 64, 256 and 1024 cycles per tick
+
+const CNT_PRESCALER_MASK: u16 = 0b11;
+const CNT_CASCADE_BIT: u8 = 2;
+const CNT_IRQ_BIT: u8 = 6;
+const CNT_ENABLE_BIT: u8 = 7;
+
+#[derive(Copy, Clone, Default)]
+struct Timer {
+    reload: u16,
+    counter: u16,
+    control: u16,
+    prescaler_cycles: usize,
+}
+
+impl Timer {
+    fn enabled(&self) -> bool {
+        self.control >> CNT_ENABLE_BIT & 1 == 1
+    }
+
+    fn cascade(&self) -> bool {
+        self.control >> CNT_CASCADE_BIT & 1 == 1
+    }
+
+    // Returns how many times the counter wrapped
+    fn count(&mut self, mut ticks: usize) -> usize {
+        let mut overflows = 0;
+        while ticks > 0 {
+            let to_overflow = 0x10000 - self.counter as usize;
+            if ticks < to_overflow {
+                self.counter += ticks as u16;
+                break;
+            }
+            ticks -= to_overflow;
+            self.counter = self.reload;
+            overflows += 1;
+        }
+        overflows
+    }
+}
+
+#[derive(Default)]
+pub struct Timers {
+    timers: [Timer; 4],
+}
+
+impl Timers {
+    pub fn new() -> Self {
+        Timers {
+            timers: [Timer::default(); 4],
+        }
+    }
+
+    // TMxCNT_L reads back the live counter, not the reload value that was written
+    pub fn read(&self, offset: usize) -> u16 {
+        let timer = &self.timers[offset / 4];
+        if offset.is_multiple_of(4) {
+            timer.counter
+        } else {
+            timer.control
+        }
+    }
+
+    pub fn write(&mut self, offset: usize, data: u16) {
+        let timer = &mut self.timers[offset / 4];
+        if offset.is_multiple_of(4) {
+            timer.reload = data;
+            return;
+        }
+        let was_enabled = timer.enabled();
+        timer.control = data & 0b1100_0111;
+        if !was_enabled && timer.enabled() {
+            timer.counter = timer.reload;
+            timer.prescaler_cycles = 0;
+        }
+    }
+
+    pub fn irq_enabled(&self, index: usize) -> bool {
+        self.timers[index].control >> CNT_IRQ_BIT & 1 == 1
+    }
+
+    // Advances all four timers, returning the number of overflows of each
+    pub fn step(&mut self, cycles: usize) -> [usize; 4] {
+        let mut overflows = [0; 4];
+        for i in 0..4 {
+            let timer = &mut self.timers[i];
+            if !timer.enabled() {
+                continue;
+            }
+            let ticks = if i > 0 && timer.cascade() {
+                overflows[i - 1]
+            } else {
+                let shift = PRESCALER_SHIFTS[(timer.control & CNT_PRESCALER_MASK) as usize];
+                timer.prescaler_cycles += cycles;
+                let ticks = timer.prescaler_cycles >> shift;
+                timer.prescaler_cycles &= (1 << shift) - 1;
+                ticks
+            };
+            overflows[i] = timer.count(ticks);
+        }
+        overflows
+    }
+}
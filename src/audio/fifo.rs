@@ -0,0 +1,67 @@
+use std::collections::VecDeque;
+
+const FIFO_CAPACITY: usize = 32;
+const REFILL_LEVEL: usize = 16;
+
+// Direct Sound sample queue, fed by DMA and drained on timer overflows
+#[derive(Default)]
+pub struct SoundFifo {
+    samples: VecDeque<i8>,
+    sample: i8,
+}
+
+impl SoundFifo {
+    pub fn new() -> Self {
+        SoundFifo {
+            samples: VecDeque::with_capacity(FIFO_CAPACITY),
+            sample: 0,
+        }
+    }
+
+    // Only the bytes in mask were really written, the rest is stale register contents
+    pub fn push(&mut self, data: u16, mask: u16) {
+        for (&byte, &written) in data.to_le_bytes().iter().zip(mask.to_le_bytes().iter()) {
+            if written != 0 && self.samples.len() < FIFO_CAPACITY {
+                self.samples.push_back(byte as i8);
+            }
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.samples.clear();
+        self.sample = 0;
+    }
+
+    // The last sample keeps playing if the FIFO runs dry
+    pub fn pop(&mut self) {
+        if let Some(sample) = self.samples.pop_front() {
+            self.sample = sample;
+        }
+    }
+
+    pub fn needs_refill(&self) -> bool {
+        self.samples.len() <= REFILL_LEVEL
+    }
+
+    pub fn sample(&self) -> i8 {
+        self.sample
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn byte_writes_push_only_the_written_byte() {
+        let mut fifo = SoundFifo::new();
+        fifo.push(0x2211, 0xFF00);
+        fifo.push(0x4433, 0xFFFF);
+        let mut samples = Vec::new();
+        for _ in 0..3 {
+            fifo.pop();
+            samples.push(fifo.sample() as u8);
+        }
+        assert_eq!(samples, vec![0x22, 0x33, 0x44]);
+    }
+}
@@ -1,3 +1,4 @@
 pub mod apu;
 pub mod channels;
+pub mod fifo;
 pub mod waves;
@@ -0,0 +1,49 @@
+use anyhow::{bail, Context, Result};
+use flate2::read::GzDecoder;
+use std::fs;
+use std::io::{Cursor, Read};
+use std::path::Path;
+use zip::ZipArchive;
+
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+const GZIP_MAGIC: &[u8] = b"\x1F\x8B";
+
+// Reads a ROM or BIOS image, unpacking it first if it is a zip or gzip archive. Zips use
+// the named entry if given, otherwise the first entry ending in the given extension.
+pub fn read_image(path: &Path, entry: Option<&str>, extension: &str) -> Result<Vec<u8>> {
+    let data = fs::read(path).with_context(|| format!("could not read {}", path.display()))?;
+    if data.starts_with(ZIP_MAGIC) {
+        read_zip(data, entry, extension).with_context(|| format!("could not unpack {}", path.display()))
+    } else if data.starts_with(GZIP_MAGIC) {
+        let mut contents = Vec::new();
+        GzDecoder::new(&data[..])
+            .read_to_end(&mut contents)
+            .with_context(|| format!("could not decompress {}", path.display()))?;
+        Ok(contents)
+    } else {
+        Ok(data)
+    }
+}
+
+fn read_zip(data: Vec<u8>, entry: Option<&str>, extension: &str) -> Result<Vec<u8>> {
+    let mut archive = ZipArchive::new(Cursor::new(data))?;
+    let name = match entry {
+        Some(name) => name.to_string(),
+        None => {
+            let names: Vec<String> = archive.file_names().map(String::from).collect();
+            let suffix = format!(".{}", extension.to_ascii_lowercase());
+            match names.iter().find(|name| name.to_ascii_lowercase().ends_with(&suffix)) {
+                Some(name) => name.clone(),
+                // A lone file is used whatever it is called
+                None if names.len() == 1 => names[0].clone(),
+                None => bail!("archive has no .{} entry", extension),
+            }
+        }
+    };
+    let mut file = archive
+        .by_name(&name)
+        .with_context(|| format!("archive has no entry named {}", name))?;
+    let mut contents = Vec::with_capacity(file.size() as usize);
+    file.read_to_end(&mut contents)?;
+    Ok(contents)
+}
@@ -0,0 +1,447 @@
+use anyhow::{anyhow, Result};
+use std::cell::Cell;
+use std::io::{Read, Write};
+use std::str::FromStr;
+
+const SRAM_SIZE: usize = 0x8000;
+const FLASH_BANK_SIZE: usize = 0x10000;
+const FLASH_SECTOR_SIZE: usize = 0x1000;
+const EEPROM_SMALL_SIZE: usize = 0x200;
+const EEPROM_LARGE_SIZE: usize = 0x2000;
+
+// Manufacturer and device IDs reported in flash ID mode
+const FLASH64K_ID: (u8, u8) = (0x32, 0x1B); // Panasonic MN63F805MNP
+const FLASH128K_ID: (u8, u8) = (0x62, 0x13); // Sanyo LE26FV10N1TS
+
+const FLASH_CMD_ADDR_1: usize = 0x5555;
+const FLASH_CMD_ADDR_2: usize = 0x2AAA;
+
+// Library ID strings that Nintendo's SDK links into every ROM using a backup device
+const SAVE_SIGNATURES: [(&[u8], SaveType); 6] = [
+    (b"EEPROM_V", SaveType::Eeprom),
+    (b"SRAM_V", SaveType::Sram),
+    (b"SRAM_F_V", SaveType::Sram),
+    (b"FLASH_V", SaveType::Flash64K),
+    (b"FLASH512_V", SaveType::Flash64K),
+    (b"FLASH1M_V", SaveType::Flash128K),
+];
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SaveType {
+    None,
+    Sram,
+    Flash64K,
+    Flash128K,
+    Eeprom, // size picked up from the DMA transfer length
+    Eeprom512,
+    Eeprom8K,
+}
+
+impl SaveType {
+    pub fn detect(rom: &[u8]) -> SaveType {
+        // The ID strings are always word aligned
+        for offset in (0..rom.len()).step_by(4) {
+            for (signature, save_type) in SAVE_SIGNATURES.iter() {
+                if rom[offset..].starts_with(signature) {
+                    return *save_type;
+                }
+            }
+        }
+        SaveType::None
+    }
+}
+
+impl FromStr for SaveType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(SaveType::None),
+            "sram" => Ok(SaveType::Sram),
+            "flash" | "flash64" | "flash64k" | "flash512" => Ok(SaveType::Flash64K),
+            "flash128" | "flash128k" | "flash1m" => Ok(SaveType::Flash128K),
+            "eeprom" => Ok(SaveType::Eeprom),
+            "eeprom512" => Ok(SaveType::Eeprom512),
+            "eeprom8k" => Ok(SaveType::Eeprom8K),
+            _ => Err(anyhow!("unknown save type '{}' (expected none, sram, flash64k, flash128k, eeprom, eeprom512 or eeprom8k)", s)),
+        }
+    }
+}
+
+pub enum Backup {
+    None,
+    Sram(Vec<u8>),
+    Flash(Flash),
+    Eeprom(Eeprom),
+}
+
+impl Backup {
+    pub fn new(save_type: SaveType) -> Self {
+        match save_type {
+            SaveType::None => Backup::None,
+            SaveType::Sram => Backup::Sram(vec![0xFF; SRAM_SIZE]),
+            SaveType::Flash64K => Backup::Flash(Flash::new(1, FLASH64K_ID)),
+            SaveType::Flash128K => Backup::Flash(Flash::new(2, FLASH128K_ID)),
+            SaveType::Eeprom => Backup::Eeprom(Eeprom::new(None)),
+            SaveType::Eeprom512 => Backup::Eeprom(Eeprom::new(Some(6))),
+            SaveType::Eeprom8K => Backup::Eeprom(Eeprom::new(Some(14))),
+        }
+    }
+
+    pub fn is_eeprom(&self) -> bool {
+        matches!(self, Backup::Eeprom(_))
+    }
+
+    // Accesses to 0xE000000..=0xFFFFFFF, offset from the start of the region
+    pub fn read_byte(&self, offset: usize) -> u8 {
+        match self {
+            Backup::Sram(data) => data[offset % SRAM_SIZE],
+            Backup::Flash(flash) => flash.read(offset),
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write_byte(&mut self, offset: usize, data: u8) {
+        match self {
+            Backup::Sram(sram) => sram[offset % SRAM_SIZE] = data,
+            Backup::Flash(flash) => flash.write(offset, data),
+            _ => {}
+        }
+    }
+
+    pub fn read_eeprom(&self) -> u16 {
+        match self {
+            Backup::Eeprom(eeprom) => eeprom.read_bit(),
+            _ => 0xFFFF,
+        }
+    }
+
+    pub fn write_eeprom(&mut self, data: u16) {
+        if let Backup::Eeprom(eeprom) = self {
+            eeprom.write_bit(data);
+        }
+    }
+
+    pub fn set_eeprom_addr_bits(&mut self, addr_bits: usize) {
+        if let Backup::Eeprom(eeprom) = self {
+            eeprom.set_addr_bits(addr_bits);
+        }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        match self {
+            Backup::None => &[],
+            Backup::Sram(data) => data,
+            Backup::Flash(flash) => &flash.data,
+            Backup::Eeprom(eeprom) => eeprom.data(),
+        }
+    }
+
+    pub fn load(&mut self, mut file: impl Read) -> Result<()> {
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)?;
+        if let Backup::Eeprom(eeprom) = self {
+            // An existing save file settles the EEPROM size
+            if eeprom.addr_bits.is_none() {
+                eeprom.addr_bits = Some(if contents.len() > EEPROM_SMALL_SIZE { 14 } else { 6 });
+            }
+        }
+        let data = match self {
+            Backup::None => return Ok(()),
+            Backup::Sram(data) => data,
+            Backup::Flash(flash) => &mut flash.data,
+            Backup::Eeprom(eeprom) => eeprom.data_mut(),
+        };
+        let len = contents.len().min(data.len());
+        data[..len].copy_from_slice(&contents[..len]);
+        Ok(())
+    }
+
+    pub fn save(&self, mut file: impl Write) -> std::io::Result<()> {
+        file.write_all(self.data())
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum FlashState {
+    Ready,
+    Command1,
+    Command2,
+}
+
+pub struct Flash {
+    data: Vec<u8>,
+    id: (u8, u8),
+    bank: usize,
+    state: FlashState,
+    id_mode: bool,
+    erase_armed: bool,
+    write_armed: bool,
+    bank_switch_armed: bool,
+}
+
+impl Flash {
+    fn new(banks: usize, id: (u8, u8)) -> Self {
+        Flash {
+            data: vec![0xFF; banks * FLASH_BANK_SIZE],
+            id,
+            bank: 0,
+            state: FlashState::Ready,
+            id_mode: false,
+            erase_armed: false,
+            write_armed: false,
+            bank_switch_armed: false,
+        }
+    }
+
+    fn read(&self, offset: usize) -> u8 {
+        let offset = offset % FLASH_BANK_SIZE;
+        if self.id_mode && offset < 2 {
+            return if offset == 0 { self.id.0 } else { self.id.1 };
+        }
+        self.data[self.bank * FLASH_BANK_SIZE + offset]
+    }
+
+    fn write(&mut self, offset: usize, data: u8) {
+        let offset = offset % FLASH_BANK_SIZE;
+        if self.write_armed {
+            // Flash can only clear bits, erasing is the only way to set them again
+            self.data[self.bank * FLASH_BANK_SIZE + offset] &= data;
+            self.write_armed = false;
+            return;
+        }
+        if self.bank_switch_armed {
+            if offset == 0 {
+                self.bank = data as usize & 1;
+                if self.bank * FLASH_BANK_SIZE >= self.data.len() {
+                    self.bank = 0;
+                }
+            }
+            self.bank_switch_armed = false;
+            return;
+        }
+        self.state = match (self.state, offset, data) {
+            (FlashState::Ready, FLASH_CMD_ADDR_1, 0xAA) => FlashState::Command1,
+            (FlashState::Command1, FLASH_CMD_ADDR_2, 0x55) => FlashState::Command2,
+            (FlashState::Command2, _, command) => {
+                self.command(offset, command);
+                FlashState::Ready
+            }
+            _ => FlashState::Ready,
+        };
+    }
+
+    fn command(&mut self, offset: usize, command: u8) {
+        if self.erase_armed {
+            self.erase_armed = false;
+            match command {
+                0x10 if offset == FLASH_CMD_ADDR_1 => {
+                    for byte in self.data.iter_mut() {
+                        *byte = 0xFF;
+                    }
+                }
+                0x30 => {
+                    let start = self.bank * FLASH_BANK_SIZE + (offset & !(FLASH_SECTOR_SIZE - 1));
+                    for byte in self.data[start..start + FLASH_SECTOR_SIZE].iter_mut() {
+                        *byte = 0xFF;
+                    }
+                }
+                _ => {}
+            }
+            return;
+        }
+        if offset != FLASH_CMD_ADDR_1 {
+            return;
+        }
+        match command {
+            0x90 => self.id_mode = true,
+            0xF0 => self.id_mode = false,
+            0x80 => self.erase_armed = true,
+            0xA0 => self.write_armed = true,
+            0xB0 if self.data.len() > FLASH_BANK_SIZE => self.bank_switch_armed = true,
+            _ => {}
+        }
+    }
+}
+
+pub struct Eeprom {
+    data: Vec<u8>,
+    addr_bits: Option<usize>,
+    input: Vec<bool>,
+    output: Cell<u64>,
+    output_bits: Cell<usize>,
+}
+
+impl Eeprom {
+    fn new(addr_bits: Option<usize>) -> Self {
+        Eeprom {
+            data: vec![0xFF; EEPROM_LARGE_SIZE],
+            addr_bits,
+            input: Vec::with_capacity(81),
+            output: Cell::new(0),
+            output_bits: Cell::new(0),
+        }
+    }
+
+    fn set_addr_bits(&mut self, addr_bits: usize) {
+        if self.addr_bits.is_none() {
+            self.addr_bits = Some(addr_bits);
+        }
+    }
+
+    fn data(&self) -> &[u8] {
+        match self.addr_bits {
+            Some(6) => &self.data[..EEPROM_SMALL_SIZE],
+            _ => &self.data,
+        }
+    }
+
+    fn data_mut(&mut self) -> &mut [u8] {
+        match self.addr_bits {
+            Some(6) => &mut self.data[..EEPROM_SMALL_SIZE],
+            _ => &mut self.data,
+        }
+    }
+
+    // Serial reads return 4 junk bits followed by 64 data bits, MSB first. Bit 0 reads
+    // as 1 ("ready") while idle.
+    fn read_bit(&self) -> u16 {
+        let remaining = self.output_bits.get();
+        if remaining == 0 {
+            return 1;
+        }
+        self.output_bits.set(remaining - 1);
+        if remaining > 64 {
+            return 0;
+        }
+        (self.output.get() >> (remaining - 1) & 1) as u16
+    }
+
+    fn write_bit(&mut self, data: u16) {
+        self.input.push(data & 1 == 1);
+        if self.input.len() < 2 {
+            return;
+        }
+        let read_request = self.input[0] && self.input[1];
+        let write_request = self.input[0] && !self.input[1];
+        if !read_request && !write_request {
+            self.input.clear();
+            return;
+        }
+        // Until the size is known (from the DMA length or an existing save) assume 8K
+        let addr_bits = self.addr_bits.unwrap_or(14);
+        let expected = if read_request { 2 + addr_bits + 1 } else { 2 + addr_bits + 64 + 1 };
+        if self.input.len() < expected {
+            return;
+        }
+        // Only the low 10 bits of a 14 bit address are decoded
+        let block = self.input[2..2 + addr_bits]
+            .iter()
+            .fold(0usize, |acc, &bit| acc << 1 | bit as usize)
+            & 0x3FF;
+        let start = block * 8 % self.data().len();
+        if read_request {
+            let mut value = 0u64;
+            for byte in self.data[start..start + 8].iter() {
+                value = value << 8 | *byte as u64;
+            }
+            self.output.set(value);
+            self.output_bits.set(68);
+        } else {
+            let mut value = self.input[2 + addr_bits..2 + addr_bits + 64]
+                .iter()
+                .fold(0u64, |acc, &bit| acc << 1 | bit as u64);
+            for byte in self.data[start..start + 8].iter_mut().rev() {
+                *byte = value as u8;
+                value >>= 8;
+            }
+        }
+        self.input.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flash_command(backup: &mut Backup, command: u8) {
+        backup.write_byte(FLASH_CMD_ADDR_1, 0xAA);
+        backup.write_byte(FLASH_CMD_ADDR_2, 0x55);
+        backup.write_byte(FLASH_CMD_ADDR_1, command);
+    }
+
+    fn eeprom_bits(backup: &mut Backup, value: u64, bits: usize) {
+        for bit in (0..bits).rev() {
+            backup.write_eeprom((value >> bit & 1) as u16);
+        }
+    }
+
+    #[test]
+    fn detects_save_type_from_library_id() {
+        let mut rom = vec![0u8; 0x100];
+        rom[0x40..0x49].copy_from_slice(b"FLASH1M_V");
+        assert_eq!(SaveType::detect(&rom), SaveType::Flash128K);
+        // Off a word boundary it is just data
+        let mut rom = vec![0u8; 0x100];
+        rom[0x41..0x49].copy_from_slice(b"EEPROM_V");
+        assert_eq!(SaveType::detect(&rom), SaveType::None);
+    }
+
+    #[test]
+    fn flash_reports_id_only_in_id_mode() {
+        let mut backup = Backup::new(SaveType::Flash128K);
+        flash_command(&mut backup, 0x90);
+        assert_eq!((backup.read_byte(0), backup.read_byte(1)), FLASH128K_ID);
+        flash_command(&mut backup, 0xF0);
+        assert_eq!(backup.read_byte(0), 0xFF);
+    }
+
+    #[test]
+    fn flash_write_only_clears_bits_until_erased() {
+        let mut backup = Backup::new(SaveType::Flash64K);
+        flash_command(&mut backup, 0xA0);
+        backup.write_byte(0x1234, 0x5A);
+        assert_eq!(backup.read_byte(0x1234), 0x5A);
+        flash_command(&mut backup, 0xA0);
+        backup.write_byte(0x1234, 0xA5);
+        assert_eq!(backup.read_byte(0x1234), 0x00);
+
+        flash_command(&mut backup, 0x80);
+        backup.write_byte(FLASH_CMD_ADDR_1, 0xAA);
+        backup.write_byte(FLASH_CMD_ADDR_2, 0x55);
+        backup.write_byte(0x1000, 0x30);
+        assert_eq!(backup.read_byte(0x1234), 0xFF);
+    }
+
+    #[test]
+    fn flash_bank_switch_selects_upper_64k() {
+        let mut backup = Backup::new(SaveType::Flash128K);
+        flash_command(&mut backup, 0xB0);
+        backup.write_byte(0, 1);
+        flash_command(&mut backup, 0xA0);
+        backup.write_byte(0x10, 0x42);
+        assert_eq!(backup.data()[FLASH_BANK_SIZE + 0x10], 0x42);
+        assert_eq!(backup.data()[0x10], 0xFF);
+    }
+
+    #[test]
+    fn eeprom_reads_back_a_written_block() {
+        let mut backup = Backup::new(SaveType::Eeprom512);
+        // Write request, block 3, 64 data bits, stop bit
+        eeprom_bits(&mut backup, 0b10, 2);
+        eeprom_bits(&mut backup, 3, 6);
+        eeprom_bits(&mut backup, 0x0123_4567_89AB_CDEF, 64);
+        eeprom_bits(&mut backup, 0, 1);
+        assert_eq!(&backup.data()[24..32], &[0x01, 0x23, 0x45, 0x67, 0x89, 0xAB, 0xCD, 0xEF]);
+
+        eeprom_bits(&mut backup, 0b11, 2);
+        eeprom_bits(&mut backup, 3, 6);
+        eeprom_bits(&mut backup, 0, 1);
+        for _ in 0..4 {
+            assert_eq!(backup.read_eeprom(), 0);
+        }
+        let value = (0..64).fold(0u64, |acc, _| acc << 1 | backup.read_eeprom() as u64);
+        assert_eq!(value, 0x0123_4567_89AB_CDEF);
+        assert_eq!(backup.read_eeprom(), 1);
+    }
+}
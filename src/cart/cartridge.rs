@@ -0,0 +1,279 @@
+use anyhow::{bail, Context, Result};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cart::backup::SaveType;
+use crate::cart::gpio;
+use crate::cart::rtc::ClockSource;
+
+const HEADER_SIZE: usize = 0xC0;
+const LOGO_START: usize = 0x04;
+const LOGO_END: usize = 0xA0;
+const TITLE_START: usize = 0xA0;
+const GAME_CODE_START: usize = 0xAC;
+const MAKER_CODE_START: usize = 0xB0;
+const FIXED_VALUE_ADDR: usize = 0xB2;
+const VERSION_ADDR: usize = 0xBC;
+const CHECKSUM_ADDR: usize = 0xBD;
+
+const SETTINGS_DIR: &str = "games";
+
+// Compressed Nintendo logo the BIOS checks before booting a cartridge
+const NINTENDO_LOGO: [u8; LOGO_END - LOGO_START] = [
+    0x24, 0xFF, 0xAE, 0x51, 0x69, 0x9A, 0xA2, 0x21, 0x3D, 0x84, 0x82, 0x0A, 0x84, 0xE4, 0x09, 0xAD,
+    0x11, 0x24, 0x8B, 0x98, 0xC0, 0x81, 0x7F, 0x21, 0xA3, 0x52, 0xBE, 0x19, 0x93, 0x09, 0xCE, 0x20,
+    0x10, 0x46, 0x4A, 0x4A, 0xF8, 0x27, 0x31, 0xEC, 0x58, 0xC7, 0xE8, 0x33, 0x82, 0xE3, 0xCE, 0xBF,
+    0x85, 0xF4, 0xDF, 0x94, 0xCE, 0x4B, 0x09, 0xC1, 0x94, 0x56, 0x8A, 0xC0, 0x13, 0x72, 0xA7, 0xFC,
+    0x9F, 0x84, 0x4D, 0x73, 0xA3, 0xCA, 0x9A, 0x61, 0x58, 0x97, 0xA3, 0x27, 0xFC, 0x03, 0x98, 0x76,
+    0x23, 0x1D, 0xC7, 0x61, 0x03, 0x04, 0xAE, 0x56, 0xBF, 0x38, 0x84, 0x00, 0x40, 0xA7, 0x0E, 0xFD,
+    0xFF, 0x52, 0xFE, 0x03, 0x6F, 0x95, 0x30, 0xF1, 0x97, 0xFB, 0xC0, 0x85, 0x60, 0xD6, 0x80, 0x25,
+    0xA9, 0x63, 0xBE, 0x03, 0x01, 0x4E, 0x38, 0xE2, 0xF9, 0xA2, 0x34, 0xFF, 0xBB, 0x3E, 0x03, 0x44,
+    0x78, 0x00, 0x90, 0xCB, 0x88, 0x11, 0x3A, 0x94, 0x65, 0xC0, 0x7C, 0x63, 0x87, 0xF0, 0x3C, 0xAF,
+    0xD6, 0x25, 0xE4, 0x8B, 0x38, 0x0A, 0xAC, 0x72, 0x21, 0xD4, 0xF8, 0x07,
+];
+
+// Games whose library IDs are missing or misleading, keyed by game code without the region
+const KNOWN_GAMES: [(&str, Option<SaveType>, bool); 5] = [
+    ("AXV", Some(SaveType::Flash128K), true), // Pokemon Ruby
+    ("AXP", Some(SaveType::Flash128K), true), // Pokemon Sapphire
+    ("BPE", Some(SaveType::Flash128K), true), // Pokemon Emerald
+    ("BPR", Some(SaveType::Flash128K), false), // Pokemon FireRed
+    ("BPG", Some(SaveType::Flash128K), false), // Pokemon LeafGreen
+];
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum HeaderWarning {
+    BadEntryPoint(u32),
+    BadLogo,
+    BadFixedValue(u8),
+    BadChecksum { expected: u8, found: u8 },
+}
+
+impl fmt::Display for HeaderWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HeaderWarning::BadEntryPoint(instruction) => {
+                write!(f, "entry point {:#010X} is not an ARM branch instruction", instruction)
+            }
+            HeaderWarning::BadLogo => write!(f, "Nintendo logo does not match, the BIOS will refuse to boot"),
+            HeaderWarning::BadFixedValue(value) => write!(f, "fixed header byte is {:#04X} instead of 0x96", value),
+            HeaderWarning::BadChecksum { expected, found } => write!(
+                f,
+                "header checksum is {:#04X} but should be {:#04X}, the BIOS will refuse to boot",
+                found, expected
+            ),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct GameSettings {
+    pub save_type: Option<SaveType>,
+    pub rtc: Option<ClockSource>,
+}
+
+pub struct Cartridge {
+    rom: Vec<u8>,
+    pub entry_point: u32,
+    pub title: String,
+    pub game_code: String,
+    pub maker_code: String,
+    pub version: u8,
+    pub checksum: u8,
+}
+
+fn header_string(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .take_while(|&&byte| byte != 0)
+        .map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '?' })
+        .collect::<String>()
+        .trim_end()
+        .to_string()
+}
+
+impl Cartridge {
+    pub fn new(rom: Vec<u8>) -> Result<Self> {
+        if rom.len() < HEADER_SIZE {
+            bail!("ROM is {} bytes, too small to hold a {} byte cartridge header", rom.len(), HEADER_SIZE);
+        }
+        Ok(Cartridge {
+            entry_point: u32::from_le_bytes([rom[0], rom[1], rom[2], rom[3]]),
+            title: header_string(&rom[TITLE_START..GAME_CODE_START]),
+            game_code: header_string(&rom[GAME_CODE_START..MAKER_CODE_START]),
+            maker_code: header_string(&rom[MAKER_CODE_START..FIXED_VALUE_ADDR]),
+            version: rom[VERSION_ADDR],
+            checksum: rom[CHECKSUM_ADDR],
+            rom,
+        })
+    }
+
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    pub fn expected_checksum(&self) -> u8 {
+        let sum = self.rom[TITLE_START..CHECKSUM_ADDR]
+            .iter()
+            .fold(0u8, |acc, &byte| acc.wrapping_add(byte));
+        0u8.wrapping_sub(sum).wrapping_sub(0x19)
+    }
+
+    pub fn validate(&self) -> Vec<HeaderWarning> {
+        let mut warnings = Vec::new();
+        if self.entry_point >> 24 != 0xEA {
+            warnings.push(HeaderWarning::BadEntryPoint(self.entry_point));
+        }
+        if self.rom[LOGO_START..LOGO_END] != NINTENDO_LOGO[..] {
+            warnings.push(HeaderWarning::BadLogo);
+        }
+        if self.rom[FIXED_VALUE_ADDR] != 0x96 {
+            warnings.push(HeaderWarning::BadFixedValue(self.rom[FIXED_VALUE_ADDR]));
+        }
+        let expected = self.expected_checksum();
+        if self.checksum != expected {
+            warnings.push(HeaderWarning::BadChecksum { expected, found: self.checksum });
+        }
+        warnings
+    }
+
+    pub fn window_title(&self) -> String {
+        if self.title.is_empty() {
+            return String::from("GBAEmu");
+        }
+        format!("GBAEmu - {} ({})", self.title, self.game_code)
+    }
+
+    // Saves are named after the header so renaming the ROM file keeps the save attached.
+    // A <rom>.sav from before that keeps being used until the header named one exists.
+    pub fn save_path(&self, rom_path: &Path) -> PathBuf {
+        let directory = rom_path.parent().unwrap_or_else(|| Path::new(""));
+        let legacy = rom_path.with_extension("sav");
+        if self.game_code.is_empty() {
+            return legacy;
+        }
+        let title: String = self
+            .title
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        let path = directory.join(format!("{}-{}-{:02}.sav", title, self.game_code, self.version));
+        if !path.exists() && legacy.exists() {
+            return legacy;
+        }
+        path
+    }
+
+    // Built-in overrides first, then games/<game code>.cfg on top
+    pub fn settings(&self) -> Result<GameSettings> {
+        let mut settings = GameSettings::default();
+        for (code, save_type, rtc) in KNOWN_GAMES.iter() {
+            if self.game_code.starts_with(code) {
+                settings.save_type = *save_type;
+                if *rtc {
+                    settings.rtc = Some(ClockSource::Host);
+                }
+            }
+        }
+        if gpio::has_rtc(&self.rom) {
+            settings.rtc.get_or_insert(ClockSource::Host);
+        }
+
+        let path = Path::new(SETTINGS_DIR).join(format!("{}.cfg", self.game_code));
+        if self.game_code.is_empty() || !path.exists() {
+            return Ok(settings);
+        }
+        let contents = fs::read_to_string(&path).with_context(|| format!("could not read {}", path.display()))?;
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = match line.find('=') {
+                Some(index) => (line[..index].trim(), line[index + 1..].trim()),
+                None => bail!("{}:{}: expected key = value", path.display(), number + 1),
+            };
+            match key {
+                "save_type" => settings.save_type = Some(value.parse()?),
+                "rtc" if value == "none" => settings.rtc = None,
+                "rtc" => settings.rtc = Some(value.parse()?),
+                _ => bail!("{}:{}: unknown setting '{}'", path.display(), number + 1, key),
+            }
+        }
+        Ok(settings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    // A header the BIOS would accept, with the given title and game code
+    fn rom(title: &str, game_code: &str) -> Vec<u8> {
+        let mut rom = vec![0; HEADER_SIZE];
+        rom[..4].copy_from_slice(&0xEA00002Eu32.to_le_bytes());
+        rom[LOGO_START..LOGO_END].copy_from_slice(&NINTENDO_LOGO);
+        rom[TITLE_START..TITLE_START + title.len()].copy_from_slice(title.as_bytes());
+        rom[GAME_CODE_START..GAME_CODE_START + game_code.len()].copy_from_slice(game_code.as_bytes());
+        rom[FIXED_VALUE_ADDR] = 0x96;
+        let sum = rom[TITLE_START..CHECKSUM_ADDR].iter().fold(0u8, |acc, &byte| acc.wrapping_add(byte));
+        rom[CHECKSUM_ADDR] = 0u8.wrapping_sub(sum).wrapping_sub(0x19);
+        rom
+    }
+
+    #[test]
+    fn checksum_is_the_complement_of_0xa0_to_0xbc() {
+        let cart = Cartridge::new(vec![0; HEADER_SIZE]).unwrap();
+        assert_eq!(cart.expected_checksum(), 0xE7);
+
+        let mut header = rom("", "");
+        header[TITLE_START] = 0x10;
+        header[VERSION_ADDR] = 0x20;
+        // 0x96 + 0x10 + 0x20 + 0x19 = 0xDF
+        assert_eq!(Cartridge::new(header.clone()).unwrap().expected_checksum(), 0x21);
+        header[CHECKSUM_ADDR] = 0x21;
+        assert_eq!(Cartridge::new(header.clone()).unwrap().validate(), vec![]);
+        header[CHECKSUM_ADDR] = 0x22;
+        assert_eq!(
+            Cartridge::new(header).unwrap().validate(),
+            vec![HeaderWarning::BadChecksum { expected: 0x21, found: 0x22 }]
+        );
+    }
+
+    #[test]
+    fn a_changed_logo_is_reported() {
+        let mut header = rom("GAME", "ABCE");
+        assert_eq!(Cartridge::new(header.clone()).unwrap().validate(), vec![]);
+        header[LOGO_START + 0x10] ^= 1;
+        assert_eq!(Cartridge::new(header).unwrap().validate(), vec![HeaderWarning::BadLogo]);
+    }
+
+    #[test]
+    fn known_games_ignore_the_region() {
+        let emerald = Cartridge::new(rom("POKEMON EMER", "BPEE")).unwrap().settings().unwrap();
+        assert_eq!(emerald.save_type, Some(SaveType::Flash128K));
+        assert_eq!(emerald.rtc, Some(ClockSource::Host));
+        let fire_red = Cartridge::new(rom("POKEMON FIRE", "BPRJ")).unwrap().settings().unwrap();
+        assert_eq!(fire_red.save_type, Some(SaveType::Flash128K));
+        assert_eq!(fire_red.rtc, None);
+        assert_eq!(Cartridge::new(rom("GAME", "ABCE")).unwrap().settings().unwrap(), GameSettings::default());
+    }
+
+    #[test]
+    fn save_path_keeps_using_an_existing_rom_named_save() {
+        let dir = env::temp_dir().join(format!("gbaemu-save-path-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let rom_path = dir.join("game.gba");
+        let cart = Cartridge::new(rom("MY GAME", "ABCE")).unwrap();
+        let named = dir.join("MY_GAME-ABCE-00.sav");
+        assert_eq!(cart.save_path(&rom_path), named);
+
+        fs::write(dir.join("game.sav"), []).unwrap();
+        assert_eq!(cart.save_path(&rom_path), dir.join("game.sav"));
+        fs::write(&named, []).unwrap();
+        assert_eq!(cart.save_path(&rom_path), named);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
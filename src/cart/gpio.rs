@@ -0,0 +1,67 @@
+use crate::cart::rtc::{ClockSource, Rtc};
+
+pub const GPIO_START: usize = 0x80000C4;
+pub const GPIO_END: usize = 0x80000C9;
+
+const REG_DATA: usize = 0x0;
+const REG_DIRECTION: usize = 0x2;
+const REG_CONTROL: usize = 0x4;
+
+// Library ID string linked into games that talk to the cartridge RTC
+const RTC_SIGNATURE: &[u8] = b"SIIRTC_V";
+
+pub fn has_rtc(rom: &[u8]) -> bool {
+    (0..rom.len()).step_by(4).any(|offset| rom[offset..].starts_with(RTC_SIGNATURE))
+}
+
+// 4 bit GPIO port in the cartridge header area, with the RTC hanging off its pins
+pub struct Gpio {
+    data: u8,
+    direction: u8,
+    readable: bool,
+    rtc: Rtc,
+}
+
+impl Gpio {
+    pub fn new(clock: ClockSource) -> Self {
+        Gpio {
+            data: 0,
+            direction: 0,
+            readable: false,
+            rtc: Rtc::new(clock),
+        }
+    }
+
+    // None means the port is write only and the ROM underneath shows through
+    pub fn read(&self, offset: usize) -> Option<u16> {
+        if !self.readable {
+            return None;
+        }
+        match offset & !1 {
+            REG_DATA => {
+                let pins = (self.rtc.sio() as u8) << 1;
+                Some(((self.data & self.direction | pins & !self.direction) & 0xF) as u16)
+            }
+            REG_DIRECTION => Some(self.direction as u16),
+            REG_CONTROL => Some(self.readable as u16),
+            _ => None,
+        }
+    }
+
+    pub fn write(&mut self, offset: usize, data: u16) {
+        match offset & !1 {
+            REG_DATA => {
+                // Only pins configured as outputs are driven by the GBA
+                self.data = self.data & !self.direction | data as u8 & self.direction & 0xF;
+                self.rtc.write_pins(self.data);
+            }
+            REG_DIRECTION => self.direction = data as u8 & 0xF,
+            REG_CONTROL => self.readable = data & 1 == 1,
+            _ => {}
+        }
+    }
+
+    pub fn step(&mut self, cycles: usize) -> bool {
+        self.rtc.step(cycles)
+    }
+}
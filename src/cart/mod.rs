@@ -0,0 +1,6 @@
+pub mod archive;
+pub mod backup;
+pub mod cartridge;
+pub mod gpio;
+pub mod patch;
+pub mod rtc;
@@ -0,0 +1,427 @@
+use anyhow::{bail, Context, Result};
+use std::convert::TryFrom;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+const PATCH_EXTENSIONS: [&str; 3] = ["ips", "ups", "bps"];
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+const UPS_MAGIC: &[u8] = b"UPS1";
+const BPS_MAGIC: &[u8] = b"BPS1";
+
+// Source, target and patch CRC32s
+const FOOTER_SIZE: usize = 12;
+// The most ROM the cartridge bus can address
+const MAX_TARGET_SIZE: usize = 0x2000000;
+
+// Looks for game.ips or game.gba.ips (and the UPS/BPS equivalents) next to the ROM
+pub fn find_patch(rom_path: &Path) -> Option<PathBuf> {
+    for extension in PATCH_EXTENSIONS.iter() {
+        let replaced = rom_path.with_extension(extension);
+        if replaced.exists() {
+            return Some(replaced);
+        }
+        let mut appended = OsString::from(rom_path.as_os_str());
+        appended.push(".");
+        appended.push(extension);
+        let appended = PathBuf::from(appended);
+        if appended.exists() {
+            return Some(appended);
+        }
+    }
+    None
+}
+
+pub fn apply(rom: Vec<u8>, patch: &[u8]) -> Result<Vec<u8>> {
+    if patch.starts_with(IPS_MAGIC) {
+        apply_ips(rom, patch)
+    } else if patch.starts_with(UPS_MAGIC) {
+        apply_ups(&rom, patch)
+    } else if patch.starts_with(BPS_MAGIC) {
+        apply_bps(&rom, patch)
+    } else {
+        bail!("unrecognised patch format (expected an IPS, UPS or BPS file)")
+    }
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { crc >> 1 ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+struct PatchReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> PatchReader<'a> {
+    fn new(data: &'a [u8], pos: usize) -> Self {
+        PatchReader { data, pos }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self.pos.checked_add(len).context("patch ends unexpectedly")?;
+        let bytes = self.data.get(self.pos..end).context("patch ends unexpectedly")?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn big_endian(&mut self, len: usize) -> Result<usize> {
+        Ok(self.bytes(len)?.iter().fold(0, |acc, &byte| acc << 8 | byte as usize))
+    }
+
+    // UPS and BPS share the same variable length integer encoding
+    fn varint(&mut self) -> Result<usize> {
+        let mut value = 0usize;
+        let mut shift = 1usize;
+        loop {
+            let byte = self.byte()?;
+            value = ((byte & 0x7F) as usize)
+                .checked_mul(shift)
+                .and_then(|part| value.checked_add(part))
+                .context("patch has a number too large to use")?;
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift.checked_shl(7).filter(|&shift| shift != 0).context("patch has a number too large to use")?;
+            value = value.checked_add(shift).context("patch has a number too large to use")?;
+        }
+    }
+}
+
+fn read_footer(patch: &[u8]) -> Result<(u32, u32)> {
+    if patch.len() < 4 + FOOTER_SIZE {
+        bail!("patch is too short to hold its checksums");
+    }
+    let footer = &patch[patch.len() - FOOTER_SIZE..];
+    let word = |i: usize| u32::from_le_bytes([footer[i], footer[i + 1], footer[i + 2], footer[i + 3]]);
+    let patch_crc = crc32(&patch[..patch.len() - 4]);
+    if patch_crc != word(8) {
+        bail!("patch is corrupt: CRC32 is {:08X} but should be {:08X}", patch_crc, word(8));
+    }
+    Ok((word(0), word(4)))
+}
+
+fn check_source(rom: &[u8], expected_size: usize, expected_crc: u32) -> Result<()> {
+    let rom_crc = crc32(rom);
+    if rom.len() != expected_size || rom_crc != expected_crc {
+        bail!(
+            "patch was made for a different ROM: expected {} bytes with CRC32 {:08X}, got {} bytes with CRC32 {:08X}",
+            expected_size,
+            expected_crc,
+            rom.len(),
+            rom_crc
+        );
+    }
+    Ok(())
+}
+
+// Checked before allocating anything, since the size comes straight from the patch
+fn check_target_size(target_size: usize) -> Result<()> {
+    if target_size > MAX_TARGET_SIZE {
+        bail!("patched ROM would be {} bytes, more than the {} a cartridge can hold", target_size, MAX_TARGET_SIZE);
+    }
+    Ok(())
+}
+
+fn check_target(target: &[u8], expected_crc: u32) -> Result<()> {
+    let target_crc = crc32(target);
+    if target_crc != expected_crc {
+        bail!("patched ROM has CRC32 {:08X} but should be {:08X}", target_crc, expected_crc);
+    }
+    Ok(())
+}
+
+fn apply_ips(mut rom: Vec<u8>, patch: &[u8]) -> Result<Vec<u8>> {
+    let mut reader = PatchReader::new(patch, IPS_MAGIC.len());
+    loop {
+        if patch[reader.pos..].starts_with(IPS_EOF) {
+            reader.pos += IPS_EOF.len();
+            break;
+        }
+        let offset = reader.big_endian(3)?;
+        let size = reader.big_endian(2)?;
+        // A zero size marks a run length encoded record
+        let (size, data) = if size == 0 {
+            let count = reader.big_endian(2)?;
+            (count, None)
+        } else {
+            (size, Some(reader.bytes(size)?))
+        };
+        if rom.len() < offset + size {
+            rom.resize(offset + size, 0);
+        }
+        match data {
+            Some(data) => rom[offset..offset + size].copy_from_slice(data),
+            None => {
+                let value = reader.byte()?;
+                for byte in rom[offset..offset + size].iter_mut() {
+                    *byte = value;
+                }
+            }
+        }
+    }
+    // Optional truncation extension
+    if patch.len() - reader.pos >= 3 {
+        let size = reader.big_endian(3)?;
+        rom.truncate(size);
+    }
+    Ok(rom)
+}
+
+fn apply_ups(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>> {
+    let (source_crc, target_crc) = read_footer(patch)?;
+    let end = patch.len() - FOOTER_SIZE;
+    let mut reader = PatchReader::new(&patch[..end], UPS_MAGIC.len());
+    let source_size = reader.varint()?;
+    let target_size = reader.varint()?;
+    check_target_size(target_size)?;
+    check_source(rom, source_size, source_crc)?;
+
+    let mut target = rom.to_vec();
+    target.resize(target_size, 0);
+    let mut pos = 0usize;
+    while reader.pos < end {
+        pos = pos.checked_add(reader.varint()?).context("UPS record offset is out of range")?;
+        loop {
+            let xor = reader.byte()?;
+            if xor == 0 {
+                pos += 1;
+                break;
+            }
+            if pos < target.len() {
+                target[pos] ^= xor;
+            }
+            pos = pos.checked_add(1).context("UPS record offset is out of range")?;
+        }
+    }
+    check_target(&target, target_crc)?;
+    Ok(target)
+}
+
+// start..start + len, if that fits in a usize
+fn range(start: usize, len: usize) -> Option<std::ops::Range<usize>> {
+    Some(start..start.checked_add(len)?)
+}
+
+fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>> {
+    let (source_crc, target_crc) = read_footer(patch)?;
+    let end = patch.len() - FOOTER_SIZE;
+    let mut reader = PatchReader::new(&patch[..end], BPS_MAGIC.len());
+    let source_size = reader.varint()?;
+    let target_size = reader.varint()?;
+    check_target_size(target_size)?;
+    let metadata_size = reader.varint()?;
+    reader.bytes(metadata_size)?;
+    check_source(rom, source_size, source_crc)?;
+
+    // The size is only trusted once the output matches it
+    let mut target = Vec::new();
+    let mut source_offset = 0isize;
+    let mut target_offset = 0isize;
+    while reader.pos < end {
+        let action = reader.varint()?;
+        let len = (action >> 2) + 1;
+        if target.len().checked_add(len).is_none_or(|new_len| new_len > target_size) {
+            bail!("BPS patch writes past the {} byte target size", target_size);
+        }
+        match action & 0b11 {
+            // SourceRead
+            0 => {
+                let data = range(target.len(), len)
+                    .and_then(|range| rom.get(range))
+                    .context("BPS source read past the end of the ROM")?;
+                target.extend_from_slice(data);
+            }
+            // TargetRead
+            1 => target.extend_from_slice(reader.bytes(len)?),
+            // SourceCopy and TargetCopy, with a signed relative offset
+            command => {
+                let data = reader.varint()?;
+                let magnitude = isize::try_from(data >> 1).context("BPS copy offset is out of range")?;
+                let delta = if data & 1 == 1 { -magnitude } else { magnitude };
+                if command == 2 {
+                    source_offset = source_offset.checked_add(delta).context("BPS source copy offset is out of range")?;
+                    let data = usize::try_from(source_offset)
+                        .ok()
+                        .and_then(|start| range(start, len))
+                        .and_then(|range| rom.get(range))
+                        .context("BPS source copy outside the ROM")?;
+                    target.extend_from_slice(data);
+                    source_offset += data.len() as isize;
+                } else {
+                    target_offset = target_offset.checked_add(delta).context("BPS target copy offset is out of range")?;
+                    let start = usize::try_from(target_offset).context("BPS target copy before the start of the output")?;
+                    // Byte by byte, the copy is allowed to overlap what it is writing
+                    for index in start..start.checked_add(len).context("BPS target copy is too long")? {
+                        let byte = *target.get(index).context("BPS target copy past the end of the output")?;
+                        target.push(byte);
+                    }
+                    target_offset += len as isize;
+                }
+            }
+        }
+    }
+    if target.len() != target_size {
+        bail!("patched ROM is {} bytes but should be {}", target.len(), target_size);
+    }
+    check_target(&target, target_crc)?;
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn varint(out: &mut Vec<u8>, mut value: usize) {
+        loop {
+            let low = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                out.push(0x80 | low);
+                return;
+            }
+            out.push(low);
+            value -= 1;
+        }
+    }
+
+    // Source and target CRCs, then the CRC of the whole patch
+    fn finish(mut patch: Vec<u8>, source: &[u8], target: &[u8]) -> Vec<u8> {
+        patch.extend_from_slice(&crc32(source).to_le_bytes());
+        patch.extend_from_slice(&crc32(target).to_le_bytes());
+        let crc = crc32(&patch);
+        patch.extend_from_slice(&crc.to_le_bytes());
+        patch
+    }
+
+    fn bps(source: &[u8], target: &[u8], actions: &[u8]) -> Vec<u8> {
+        let mut patch = BPS_MAGIC.to_vec();
+        varint(&mut patch, source.len());
+        varint(&mut patch, target.len());
+        varint(&mut patch, 0);
+        patch.extend_from_slice(actions);
+        finish(patch, source, target)
+    }
+
+    #[test]
+    fn crc32_matches_the_standard_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+    }
+
+    #[test]
+    fn ips_applies_records_runs_and_truncation() {
+        let mut patch = IPS_MAGIC.to_vec();
+        patch.extend_from_slice(&[0, 0, 1, 0, 2, 0xAA, 0xBB]);
+        patch.extend_from_slice(&[0, 0, 6, 0, 0, 0, 3, 0xCC]);
+        patch.extend_from_slice(IPS_EOF);
+        patch.extend_from_slice(&[0, 0, 8]);
+        let rom = apply(vec![0; 4], &patch).unwrap();
+        assert_eq!(rom, vec![0, 0xAA, 0xBB, 0, 0, 0, 0xCC, 0xCC]);
+    }
+
+    #[test]
+    fn ups_xors_target_and_checks_source() {
+        let source = b"abcdef".to_vec();
+        let target = b"abXdefg".to_vec();
+        let mut patch = UPS_MAGIC.to_vec();
+        varint(&mut patch, source.len());
+        varint(&mut patch, target.len());
+        varint(&mut patch, 2);
+        patch.extend_from_slice(&[b'c' ^ b'X', 0]);
+        // Counted from just after the previous record's terminator
+        varint(&mut patch, 2);
+        patch.extend_from_slice(&[b'g', 0]);
+        let patch = finish(patch, &source, &target);
+        assert_eq!(apply(source, &patch).unwrap(), target);
+        assert!(apply(b"abcdeF".to_vec(), &patch).is_err());
+    }
+
+    #[test]
+    fn bps_applies_every_action() {
+        let source = b"0123456789".to_vec();
+        let target = b"01xy89ababa".to_vec();
+        let mut actions = Vec::new();
+        // SourceRead "01", TargetRead "xy", SourceCopy "89", TargetRead "ab",
+        // then a TargetCopy that overlaps its own output
+        varint(&mut actions, (2 - 1) << 2);
+        varint(&mut actions, (2 - 1) << 2 | 1);
+        actions.extend_from_slice(b"xy");
+        varint(&mut actions, (2 - 1) << 2 | 2);
+        varint(&mut actions, 8 << 1);
+        varint(&mut actions, (2 - 1) << 2 | 1);
+        actions.extend_from_slice(b"ab");
+        varint(&mut actions, (3 - 1) << 2 | 3);
+        varint(&mut actions, 6 << 1);
+        assert_eq!(apply(source.clone(), &bps(&source, &target, &actions)).unwrap(), target);
+    }
+
+    #[test]
+    fn corrupt_patches_are_rejected() {
+        let source = b"0123".to_vec();
+        let mut patch = bps(&source, &source, &[0x80 | 3 << 2]);
+        assert_eq!(apply(source.clone(), &patch).unwrap(), source);
+        let last = patch.len() - 1;
+        patch[last] ^= 1;
+        assert!(apply(source, &patch).is_err());
+    }
+
+    #[test]
+    fn malformed_offsets_are_errors_not_panics() {
+        let source = b"0123".to_vec();
+        // SourceCopy from before the start of the ROM
+        let mut actions = Vec::new();
+        varint(&mut actions, 2);
+        varint(&mut actions, 5 << 1 | 1);
+        assert!(apply(source.clone(), &bps(&source, &source, &actions)).is_err());
+        // A TargetCopy offset far past anything representable
+        let mut actions = Vec::new();
+        varint(&mut actions, 3);
+        varint(&mut actions, usize::MAX - 1);
+        assert!(apply(source.clone(), &bps(&source, &source, &actions)).is_err());
+        // A varint that never ends inside a usize
+        let mut patch = BPS_MAGIC.to_vec();
+        patch.extend_from_slice(&[0x7F; 12]);
+        assert!(apply(source.clone(), &finish(patch, &source, &source)).is_err());
+        // A record longer than the patch
+        let mut patch = BPS_MAGIC.to_vec();
+        varint(&mut patch, 4);
+        varint(&mut patch, 4);
+        varint(&mut patch, usize::MAX - 0x1000);
+        assert!(apply(source.clone(), &finish(patch, &source, &source)).is_err());
+    }
+
+    #[test]
+    fn oversized_targets_are_rejected_before_allocating() {
+        let source = b"0123".to_vec();
+        let mut patch = UPS_MAGIC.to_vec();
+        varint(&mut patch, source.len());
+        varint(&mut patch, 1 << 40);
+        assert!(apply(source.clone(), &finish(patch, &source, &source)).is_err());
+
+        let mut patch = BPS_MAGIC.to_vec();
+        varint(&mut patch, source.len());
+        varint(&mut patch, MAX_TARGET_SIZE + 1);
+        varint(&mut patch, 0);
+        assert!(apply(source.clone(), &finish(patch, &source, &source)).is_err());
+
+        // A self-overlapping TargetCopy far longer than the stated size
+        let mut actions = Vec::new();
+        varint(&mut actions, 1);
+        actions.push(b'0');
+        varint(&mut actions, ((1 << 40) - 1) << 2 | 3);
+        varint(&mut actions, 0);
+        assert!(apply(source.clone(), &bps(&source, &source, &actions)).is_err());
+    }
+}
@@ -0,0 +1,464 @@
+use anyhow::{anyhow, Result};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const CLOCK_HZ: usize = 16_777_216;
+
+// Pins as seen on the GPIO port
+const PIN_SCK: u8 = 1 << 0;
+const PIN_SIO: u8 = 1 << 1;
+const PIN_CS: u8 = 1 << 2;
+
+// Command byte is 0110 CCC R, received LSB first so the magic ends up in the top nibble
+const COMMAND_MAGIC: u8 = 0b0110;
+
+// Status register bits
+const STATUS_INTFE: u8 = 1 << 1;
+const STATUS_INTME: u8 = 1 << 3;
+const STATUS_INTAE: u8 = 1 << 5;
+const STATUS_24H: u8 = 1 << 6;
+const STATUS_POWER: u8 = 1 << 7;
+const STATUS_WRITABLE: u8 = STATUS_INTFE | STATUS_INTME | STATUS_INTAE | STATUS_24H;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ClockSource {
+    Host,
+    Fixed(u64), // Unix time at power on, advanced only by emulated cycles
+}
+
+impl FromStr for ClockSource {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s == "host" {
+            return Ok(ClockSource::Host);
+        }
+        s.parse::<u64>()
+            .map(ClockSource::Fixed)
+            .map_err(|_| anyhow!("unknown RTC clock '{}' (expected host or a Unix timestamp)", s))
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Command {
+    Reset,
+    Alarm,
+    DateTime,
+    ForceIrq,
+    Status,
+    Time,
+}
+
+impl Command {
+    fn decode(bits: u8) -> Option<Command> {
+        match bits {
+            0 => Some(Command::Reset),
+            1 => Some(Command::Alarm),
+            2 => Some(Command::DateTime),
+            3 => Some(Command::ForceIrq),
+            4 => Some(Command::Status),
+            6 => Some(Command::Time),
+            _ => None,
+        }
+    }
+
+    fn len(self) -> usize {
+        match self {
+            Command::Reset | Command::ForceIrq => 0,
+            Command::Status => 1,
+            Command::Alarm => 2,
+            Command::Time => 3,
+            Command::DateTime => 7,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+struct DateTime {
+    year: u32,
+    month: u32,
+    day: u32,
+    weekday: u32,
+    hour: u32,
+    minute: u32,
+    second: u32,
+}
+
+impl DateTime {
+    // Civil date conversion from Howard Hinnant's days_from_civil/civil_from_days
+    fn from_unix(secs: u64) -> Self {
+        let days = (secs / 86400) as i64;
+        let time = secs % 86400;
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z - era * 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+        DateTime {
+            year: year as u32,
+            month: month as u32,
+            day: day as u32,
+            weekday: ((days + 4) % 7) as u32, // 1970-01-01 was a Thursday
+            hour: (time / 3600) as u32,
+            minute: (time / 60 % 60) as u32,
+            second: (time % 60) as u32,
+        }
+    }
+
+    fn to_unix(self) -> u64 {
+        let year = self.year as i64 - if self.month <= 2 { 1 } else { 0 };
+        let era = year.div_euclid(400);
+        let yoe = year - era * 400;
+        let month = self.month as i64;
+        let doy = (153 * if month > 2 { month - 3 } else { month + 9 } + 2) / 5 + self.day as i64 - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = era * 146097 + doe - 719468;
+        (days * 86400) as u64 + (self.hour * 3600 + self.minute * 60 + self.second) as u64
+    }
+}
+
+fn to_bcd(value: u32) -> u8 {
+    (((value / 10 % 10) << 4) | (value % 10)) as u8
+}
+
+fn from_bcd(value: u8) -> u32 {
+    (value >> 4) as u32 * 10 + (value & 0xF) as u32
+}
+
+// Seiko S-3511 serial real time clock
+pub struct Rtc {
+    clock: ClockSource,
+    offset: i64, // seconds between the clock source and what the game last set
+    elapsed_cycles: usize,
+    status: u8,
+    alarm: (u8, u8),
+    last_minute: Option<u32>,
+    irq: bool,
+
+    pins: u8,
+    sio_out: bool,
+    command: Option<(Command, bool)>,
+    bits: u8,
+    bit_count: usize,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    output_bit: usize,
+}
+
+impl Rtc {
+    pub fn new(clock: ClockSource) -> Self {
+        Rtc {
+            clock,
+            offset: 0,
+            elapsed_cycles: 0,
+            status: STATUS_24H,
+            alarm: (0, 0),
+            last_minute: None,
+            irq: false,
+            pins: 0,
+            sio_out: true,
+            command: None,
+            bits: 0,
+            bit_count: 0,
+            input: Vec::with_capacity(7),
+            output: Vec::with_capacity(7),
+            output_bit: 0,
+        }
+    }
+
+    pub fn sio(&self) -> bool {
+        self.sio_out
+    }
+
+    // Minute and alarm interrupts are checked once per emulated second
+    pub fn step(&mut self, cycles: usize) -> bool {
+        let before = self.elapsed_cycles / CLOCK_HZ;
+        self.elapsed_cycles += cycles;
+        if self.elapsed_cycles / CLOCK_HZ != before {
+            let now = self.now();
+            if self.last_minute != Some(now.minute) {
+                if self.last_minute.is_some() {
+                    if self.status & STATUS_INTME != 0 {
+                        self.irq = true;
+                    }
+                    if self.status & STATUS_INTAE != 0
+                        && self.alarm == (self.encode_hour(now.hour), to_bcd(now.minute))
+                    {
+                        self.irq = true;
+                    }
+                }
+                self.last_minute = Some(now.minute);
+            }
+        }
+        let irq = self.irq;
+        self.irq = false;
+        irq
+    }
+
+    fn unix_time(&self) -> u64 {
+        match self.clock {
+            ClockSource::Host => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            ClockSource::Fixed(epoch) => epoch + (self.elapsed_cycles / CLOCK_HZ) as u64,
+        }
+    }
+
+    fn now(&self) -> DateTime {
+        DateTime::from_unix((self.unix_time() as i64 + self.offset).max(0) as u64)
+    }
+
+    fn set_time(&mut self, time: DateTime) {
+        self.offset = time.to_unix() as i64 - self.unix_time() as i64;
+    }
+
+    fn encode_hour(&self, hour: u32) -> u8 {
+        if self.status & STATUS_24H != 0 {
+            to_bcd(hour)
+        } else {
+            to_bcd(hour % 12) | if hour >= 12 { 0x80 } else { 0 }
+        }
+    }
+
+    fn decode_hour(&self, hour: u8) -> u32 {
+        if self.status & STATUS_24H != 0 {
+            from_bcd(hour & 0x3F)
+        } else {
+            from_bcd(hour & 0x3F) % 12 + if hour & 0x80 != 0 { 12 } else { 0 }
+        }
+    }
+
+    pub fn write_pins(&mut self, pins: u8) {
+        let old = self.pins;
+        self.pins = pins;
+        if pins & PIN_CS == 0 {
+            self.end_transfer();
+            return;
+        }
+        if old & PIN_CS == 0 {
+            self.end_transfer();
+        }
+        // Everything happens on the rising edge of SCK
+        if old & PIN_SCK != 0 || pins & PIN_SCK == 0 {
+            return;
+        }
+        match self.command {
+            Some((_, true)) => {
+                let byte = self.output.get(self.output_bit / 8).copied().unwrap_or(0xFF);
+                self.sio_out = byte >> (self.output_bit % 8) & 1 == 1;
+                self.output_bit += 1;
+            }
+            _ => {
+                self.bits |= ((pins & PIN_SIO != 0) as u8) << self.bit_count;
+                self.bit_count += 1;
+                if self.bit_count == 8 {
+                    let byte = self.bits;
+                    self.bits = 0;
+                    self.bit_count = 0;
+                    self.process_byte(byte);
+                }
+            }
+        }
+    }
+
+    fn end_transfer(&mut self) {
+        self.command = None;
+        self.bits = 0;
+        self.bit_count = 0;
+        self.input.clear();
+        self.output.clear();
+        self.output_bit = 0;
+        self.sio_out = true;
+    }
+
+    fn process_byte(&mut self, byte: u8) {
+        let (command, reading) = match self.command {
+            Some(command) => command,
+            None => {
+                // Some games send the command byte the other way round
+                let byte = if byte >> 4 == COMMAND_MAGIC { byte } else { byte.reverse_bits() };
+                if byte >> 4 != COMMAND_MAGIC {
+                    return;
+                }
+                let reading = byte & 1 == 1;
+                let command = match Command::decode(byte >> 1 & 0b111) {
+                    Some(command) => command,
+                    None => return,
+                };
+                self.start_command(command, reading);
+                return;
+            }
+        };
+        if reading {
+            return;
+        }
+        self.input.push(byte);
+        if self.input.len() < command.len() {
+            return;
+        }
+        let input = self.input.clone();
+        match command {
+            Command::Status => self.status = self.status & !STATUS_WRITABLE | input[0] & STATUS_WRITABLE,
+            Command::Alarm => self.alarm = (input[0], input[1] & 0x7F),
+            Command::DateTime => {
+                let time = DateTime {
+                    year: 2000 + from_bcd(input[0]),
+                    month: from_bcd(input[1] & 0x1F).max(1),
+                    day: from_bcd(input[2] & 0x3F).max(1),
+                    weekday: 0,
+                    hour: self.decode_hour(input[4]),
+                    minute: from_bcd(input[5] & 0x7F),
+                    second: from_bcd(input[6] & 0x7F),
+                };
+                self.set_time(time);
+            }
+            Command::Time => {
+                let mut time = self.now();
+                time.hour = self.decode_hour(input[0]);
+                time.minute = from_bcd(input[1] & 0x7F);
+                time.second = from_bcd(input[2] & 0x7F);
+                self.set_time(time);
+            }
+            Command::Reset | Command::ForceIrq => {}
+        }
+        self.command = None;
+        self.input.clear();
+    }
+
+    fn start_command(&mut self, command: Command, reading: bool) {
+        match command {
+            Command::Reset => {
+                self.status = 0;
+                self.alarm = (0, 0);
+                self.offset = 0;
+                return;
+            }
+            Command::ForceIrq => {
+                self.irq = true;
+                return;
+            }
+            _ => {}
+        }
+        if reading {
+            let now = self.now();
+            self.output.clear();
+            match command {
+                Command::Status => {
+                    self.output.push(self.status);
+                    // The power flag clears once it has been read
+                    self.status &= !STATUS_POWER;
+                }
+                Command::Alarm => {
+                    self.output.push(self.alarm.0);
+                    self.output.push(self.alarm.1);
+                }
+                Command::DateTime => {
+                    self.output.push(to_bcd(now.year % 100));
+                    self.output.push(to_bcd(now.month));
+                    self.output.push(to_bcd(now.day));
+                    self.output.push(to_bcd(now.weekday));
+                }
+                _ => {}
+            }
+            if let Command::DateTime | Command::Time = command {
+                self.output.push(self.encode_hour(now.hour));
+                self.output.push(to_bcd(now.minute));
+                self.output.push(to_bcd(now.second));
+            }
+            self.output_bit = 0;
+        }
+        self.command = Some((command, reading));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2001-02-03 16:05:06 UTC, a Saturday
+    const EPOCH: u64 = 981_216_306;
+
+    fn command(rtc: &mut Rtc, command: u8, reading: bool) {
+        rtc.write_pins(0);
+        rtc.write_pins(PIN_CS);
+        send(rtc, COMMAND_MAGIC << 4 | command << 1 | reading as u8);
+    }
+
+    fn send(rtc: &mut Rtc, byte: u8) {
+        for bit in 0..8 {
+            let sio = if byte >> bit & 1 == 1 { PIN_SIO } else { 0 };
+            rtc.write_pins(PIN_CS | sio);
+            rtc.write_pins(PIN_CS | sio | PIN_SCK);
+        }
+    }
+
+    fn receive(rtc: &mut Rtc, len: usize) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                (0..8).fold(0, |byte, bit| {
+                    rtc.write_pins(PIN_CS);
+                    rtc.write_pins(PIN_CS | PIN_SCK);
+                    byte | (rtc.sio() as u8) << bit
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn bcd_round_trips() {
+        assert_eq!(to_bcd(59), 0x59);
+        assert_eq!(from_bcd(0x47), 47);
+    }
+
+    #[test]
+    fn unix_time_round_trips_through_civil_dates() {
+        let time = DateTime::from_unix(EPOCH);
+        assert_eq!((time.year, time.month, time.day, time.weekday), (2001, 2, 3, 6));
+        assert_eq!((time.hour, time.minute, time.second), (16, 5, 6));
+        assert_eq!(time.to_unix(), EPOCH);
+    }
+
+    #[test]
+    fn reads_date_and_time_from_fixed_epoch() {
+        let mut rtc = Rtc::new(ClockSource::Fixed(EPOCH));
+        command(&mut rtc, 2, true);
+        assert_eq!(receive(&mut rtc, 7), vec![0x01, 0x02, 0x03, 0x06, 0x16, 0x05, 0x06]);
+    }
+
+    #[test]
+    fn fixed_clock_only_moves_with_emulated_cycles() {
+        let mut rtc = Rtc::new(ClockSource::Fixed(EPOCH));
+        rtc.step(61 * CLOCK_HZ);
+        command(&mut rtc, 6, true);
+        assert_eq!(receive(&mut rtc, 3), vec![0x16, 0x06, 0x07]);
+    }
+
+    #[test]
+    fn written_time_is_read_back_in_12_hour_mode() {
+        let mut rtc = Rtc::new(ClockSource::Fixed(EPOCH));
+        command(&mut rtc, 6, false);
+        for &byte in [0x23, 0x59, 0x30].iter() {
+            send(&mut rtc, byte);
+        }
+        command(&mut rtc, 4, false);
+        send(&mut rtc, 0);
+        command(&mut rtc, 6, true);
+        // 11 PM, with the PM flag in the top bit
+        assert_eq!(receive(&mut rtc, 3), vec![0x91, 0x59, 0x30]);
+    }
+
+    #[test]
+    fn minute_interrupt_fires_when_enabled() {
+        let mut rtc = Rtc::new(ClockSource::Fixed(EPOCH));
+        command(&mut rtc, 4, false);
+        send(&mut rtc, STATUS_24H | STATUS_INTME);
+        assert!(!rtc.step(CLOCK_HZ));
+        assert!(!rtc.step(50 * CLOCK_HZ));
+        assert!(rtc.step(5 * CLOCK_HZ));
+    }
+}
@@ -0,0 +1,153 @@
+use anyhow::{bail, Context, Result};
+
+use crate::cheat::op::{Code, Condition, Op, Skip, Width, IO_START, ROM_START};
+
+const REG_KEYINPUT: u32 = IO_START + 0x130;
+// The only button code form: run the next line while any of the keys are held
+const BUTTON_CODE: u32 = 0x0000020;
+
+fn condition(address: u32, condition: Condition, value: u32) -> Op {
+    Op::If {
+        address,
+        width: Width::Half,
+        condition,
+        value,
+        skip: Skip::Next(1),
+    }
+}
+
+// Lines are "TAAAAAAA VVVV": a type nibble, a 28 bit address and a 16 bit value
+pub fn parse(lines: &[(u32, u32)]) -> Result<Code> {
+    let total = lines.len();
+    let mut lines = lines.iter().cloned();
+    let mut code = Code::default();
+    while let Some((op1, op2)) = lines.next() {
+        let line = total - lines.len() - 1;
+        let address = op1 & 0x0FFFFFFF;
+        let value = op2 & 0xFFFF;
+        let op = match op1 >> 28 {
+            // Game ID, only there for the real device's benefit
+            0x0 => continue,
+            0x1 => {
+                code.hook = Some(ROM_START | address & 0x01FFFFFF);
+                continue;
+            }
+            0x2 => Op::Or {
+                address,
+                width: Width::Half,
+                value,
+            },
+            0x3 => Op::Write {
+                address,
+                width: Width::Byte,
+                value: value & 0xFF,
+            },
+            // Slide: count and value step on the next line, then the address step in bytes
+            0x4 => {
+                let (steps, address_step) = lines.next().context("CodeBreaker slide code is missing its second line")?;
+                Op::Slide {
+                    address,
+                    width: Width::Half,
+                    value,
+                    count: steps >> 16,
+                    address_step: address_step & 0xFFFF,
+                    value_step: steps & 0xFFFF,
+                }
+            }
+            // value bytes follow, six to a line
+            0x5 => {
+                let count = value as usize;
+                let mut data = Vec::with_capacity(count);
+                while data.len() < count {
+                    let (high, low) = lines.next().context("CodeBreaker byte list ends early")?;
+                    data.extend_from_slice(&high.to_be_bytes());
+                    data.extend_from_slice(&(low as u16).to_be_bytes());
+                }
+                data.truncate(count);
+                Op::Bytes { address, data }
+            }
+            0x6 => Op::And {
+                address,
+                width: Width::Half,
+                value,
+            },
+            0x7 => condition(address, Condition::Equal, value),
+            0x8 => Op::Write {
+                address,
+                width: Width::Half,
+                value,
+            },
+            0x9 => bail!("encrypted CodeBreaker codes are not supported"),
+            0xA => condition(address, Condition::NotEqual, value),
+            0xB => condition(address, Condition::Greater, value),
+            0xC => condition(address, Condition::Less, value),
+            0xD if address == BUTTON_CODE => condition(REG_KEYINPUT, Condition::AnyClear, value & 0x3FF),
+            0xD => bail!("CodeBreaker button code {:08X} {:04X} is not supported", op1, value),
+            0xE => Op::Add {
+                address,
+                width: Width::Half,
+                value,
+            },
+            _ => condition(address, Condition::And, value),
+        };
+        code.push(line, op);
+    }
+    Ok(code.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conditions_skip_whole_multi_line_codes() {
+        let lines = [
+            (0x72000000, 0x0001),
+            // Three bytes on the line after
+            (0x52000010, 0x0003),
+            (0x11223344, 0x5566),
+            (0x82000020, 0x0002),
+        ];
+        let code = parse(&lines).unwrap();
+        assert_eq!(code.ops.len(), 3);
+        assert_eq!(
+            code.ops[1],
+            Op::Bytes {
+                address: 0x2000010,
+                data: vec![0x11, 0x22, 0x33]
+            }
+        );
+        match code.ops[0] {
+            Op::If { skip, .. } => assert_eq!(skip, Skip::Next(1)),
+            ref op => panic!("expected a condition, got {:?}", op),
+        }
+    }
+
+    #[test]
+    fn button_codes_test_keyinput() {
+        // Run the next line while Start is held
+        let code = parse(&[(0xD0000020, 0x0008), (0x32000000, 0x0063)]).unwrap();
+        assert_eq!(
+            code.ops[0],
+            Op::If {
+                address: 0x4000130,
+                width: Width::Half,
+                condition: Condition::AnyClear,
+                value: 0x0008,
+                skip: Skip::Next(1)
+            }
+        );
+        assert!(parse(&[(0xD0000010, 0x0008)]).is_err());
+    }
+
+    #[test]
+    fn a_skipped_master_code_line_skips_no_ops() {
+        let lines = [(0x72000000, 0x0001), (0x18000100, 0x0000), (0x82000020, 0x0002)];
+        let code = parse(&lines).unwrap();
+        assert_eq!(code.hook, Some(0x8000100));
+        match code.ops[0] {
+            Op::If { skip, .. } => assert_eq!(skip, Skip::Next(0)),
+            ref op => panic!("expected a condition, got {:?}", op),
+        }
+    }
+}
@@ -0,0 +1,344 @@
+use anyhow::{bail, Context, Result};
+
+use crate::cheat::op::{Code, Condition, Op, Skip, Width, IO_START, ROM_START};
+
+// GameShark / Action Replay v1 and v2
+pub const V1_SEEDS: [u32; 4] = [0x09F4FBBD, 0x9681884A, 0x352027E9, 0xF3DEE5A7];
+// Action Replay v3, also sold as GameShark SP
+pub const V3_SEEDS: [u32; 4] = [0x7AA9648F, 0x7FAE6994, 0xC0EFAAD5, 0x42712C57];
+
+const TEA_DELTA: u32 = 0x9E3779B9;
+const TEA_ROUNDS: u32 = 32;
+// Changes the seeds for the codes after it
+const RESEED: u32 = 0xDEADFACE;
+
+// Both generations encrypt each address/value pair with 32 rounds of TEA
+pub fn decrypt(mut address: u32, mut value: u32, seeds: &[u32; 4]) -> (u32, u32) {
+    let mut sum = TEA_DELTA.wrapping_mul(TEA_ROUNDS);
+    for _ in 0..TEA_ROUNDS {
+        value = value.wrapping_sub(
+            (address << 4).wrapping_add(seeds[2]) ^ address.wrapping_add(sum) ^ (address >> 5).wrapping_add(seeds[3]),
+        );
+        address = address.wrapping_sub(
+            (value << 4).wrapping_add(seeds[0]) ^ value.wrapping_add(sum) ^ (value >> 5).wrapping_add(seeds[1]),
+        );
+        sum = sum.wrapping_sub(TEA_DELTA);
+    }
+    (address, value)
+}
+
+fn decrypt_all(lines: &[(u32, u32)], seeds: &[u32; 4], encrypted: bool) -> Result<Vec<(u32, u32)>> {
+    let lines: Vec<(u32, u32)> = lines
+        .iter()
+        .map(|&(address, value)| if encrypted { decrypt(address, value, seeds) } else { (address, value) })
+        .collect();
+    if lines.iter().any(|&(address, _)| address == RESEED) {
+        bail!("reseeding (DEADFACE) codes are not supported");
+    }
+    Ok(lines)
+}
+
+pub fn parse_v1(lines: &[(u32, u32)], encrypted: bool) -> Result<Code> {
+    let lines = decrypt_all(lines, &V1_SEEDS, encrypted)?;
+    let total = lines.len();
+    let mut lines = lines.into_iter();
+    let mut code = Code::default();
+    while let Some((op1, op2)) = lines.next() {
+        let line = total - lines.len() - 1;
+        let address = op1 & 0x0FFFFFFF;
+        let op = match op1 >> 28 {
+            0x0 => Op::Write {
+                address,
+                width: Width::Byte,
+                value: op2 & 0xFF,
+            },
+            0x1 => Op::Write {
+                address,
+                width: Width::Half,
+                value: op2 & 0xFFFF,
+            },
+            0x2 => Op::Write {
+                address,
+                width: Width::Word,
+                value: op2,
+            },
+            // One value to the count addresses on the lines that follow, two per line
+            0x3 => {
+                let count = (op1 & 0xFFFF) as usize;
+                let mut addresses = Vec::with_capacity(count);
+                while addresses.len() < count {
+                    let (first, second) = lines.next().context("GameShark address list ends early")?;
+                    addresses.push(first);
+                    if addresses.len() < count {
+                        addresses.push(second);
+                    }
+                }
+                Op::WriteList {
+                    addresses,
+                    width: Width::Word,
+                    value: op2,
+                }
+            }
+            0x6 => Op::Patch {
+                address: ROM_START + ((op1 & 0xFFFFFF) << 1),
+                value: op2 as u16,
+            },
+            0xD => Op::If {
+                address,
+                width: Width::Half,
+                condition: Condition::Equal,
+                value: op2 & 0xFFFF,
+                skip: Skip::Next(1),
+            },
+            // The address is in the value half, the value and line count in the address half
+            0xE => Op::If {
+                address: op2 & 0x0FFFFFFF,
+                width: Width::Half,
+                condition: Condition::Equal,
+                value: op1 & 0xFFFF,
+                skip: Skip::Next((op1 >> 16 & 0xFF) as usize),
+            },
+            0xF => {
+                code.hook = Some(ROM_START | op1 & 0x01FFFFFF);
+                continue;
+            }
+            kind => bail!("GameShark code type {:X} ({:08X} {:08X}) is not supported", kind, op1, op2),
+        };
+        code.push(line, op);
+    }
+    Ok(code.finish())
+}
+
+// The region number sits in bits 20-23, the offset in the 20 bits below
+fn v3_address(op: u32) -> u32 {
+    (op & 0xF00000) << 4 | op & 0xFFFFF
+}
+
+fn v3_width(bits: u32) -> Result<Width> {
+    match bits & 0b11 {
+        0 => Ok(Width::Byte),
+        1 => Ok(Width::Half),
+        2 => Ok(Width::Word),
+        _ => bail!("Action Replay code has an invalid width"),
+    }
+}
+
+pub fn parse_v3(lines: &[(u32, u32)], encrypted: bool) -> Result<Code> {
+    let lines = decrypt_all(lines, &V3_SEEDS, encrypted)?;
+    let total = lines.len();
+    let mut lines = lines.into_iter();
+    let mut code = Code::default();
+    while let Some((op1, op2)) = lines.next() {
+        let line = total - lines.len() - 1;
+        // A zero address introduces a special code, spelled out in the value
+        if op1 == 0 {
+            let op = match op2 >> 24 & 0xFE {
+                // End of list, and slowdown, which has nothing to slow down here
+                0x00 | 0x08 => continue,
+                0x18 | 0x1A | 0x1C | 0x1E => {
+                    let (value, _) = lines.next().context("Action Replay ROM patch is missing its value")?;
+                    Op::Patch {
+                        address: ROM_START + ((op2 & 0xFFFFFF) << 1),
+                        value: value as u16,
+                    }
+                }
+                0x40 => Op::EndIf,
+                0x60 => Op::Else,
+                // Slide: value on the next line, then the address step in units and the count
+                0x80 | 0x82 | 0x84 => {
+                    let width = v3_width(op2 >> 25)?;
+                    let (value, steps) = lines.next().context("Action Replay slide code is missing its second line")?;
+                    Op::Slide {
+                        address: v3_address(op2),
+                        width,
+                        value: value & width.mask(),
+                        count: steps & 0xFFFF,
+                        address_step: (steps >> 16) * width.bytes(),
+                        value_step: 0,
+                    }
+                }
+                kind => bail!("Action Replay special code {:02X} is not supported", kind),
+            };
+            code.push(line, op);
+            continue;
+        }
+
+        let address = v3_address(op1);
+        let condition = match op1 >> 27 & 0b111 {
+            0 => None,
+            1 => Some(Condition::Equal),
+            2 => Some(Condition::NotEqual),
+            3 => Some(Condition::Less),
+            4 => Some(Condition::Greater),
+            5 => Some(Condition::LessUnsigned),
+            6 => Some(Condition::GreaterUnsigned),
+            _ => Some(Condition::And),
+        };
+        let op = if let Some(condition) = condition {
+            let skip = match op1 >> 30 {
+                0 => Skip::Next(1),
+                1 => Skip::Next(2),
+                2 => Skip::Block,
+                _ => Skip::Rest,
+            };
+            // The fourth width is a condition that always fails
+            let (width, condition) = match v3_width(op1 >> 25) {
+                Ok(width) => (width, condition),
+                Err(_) => (Width::Byte, Condition::Never),
+            };
+            Op::If {
+                address,
+                width,
+                condition,
+                value: op2 & width.mask(),
+                skip,
+            }
+        } else {
+            match op1 >> 24 {
+                0xC4 => {
+                    code.hook = Some(ROM_START | op1 & 0x01FFFFFE);
+                    continue;
+                }
+                0xC6 => Op::Write {
+                    address: IO_START | op1 & 0xFFFFFF,
+                    width: Width::Half,
+                    value: op2 & 0xFFFF,
+                },
+                0xC7 => Op::Write {
+                    address: IO_START | op1 & 0xFFFFFF,
+                    width: Width::Word,
+                    value: op2,
+                },
+                _ => {
+                    let width = v3_width(op1 >> 25)?;
+                    match op1 >> 30 {
+                        // Byte and halfword writes carry a repeat count above the value
+                        0 => match width {
+                            Width::Word => Op::Write {
+                                address,
+                                width,
+                                value: op2,
+                            },
+                            _ => Op::Slide {
+                                address,
+                                width,
+                                value: op2 & width.mask(),
+                                count: (op2 >> (8 * width.bytes())) + 1,
+                                address_step: width.bytes(),
+                                value_step: 0,
+                            },
+                        },
+                        // Likewise an offset from the pointer
+                        1 => Op::Indirect {
+                            address,
+                            offset: if width == Width::Word { 0 } else { op2 >> (8 * width.bytes()) },
+                            width,
+                            value: op2 & width.mask(),
+                        },
+                        2 => Op::Add {
+                            address,
+                            width,
+                            value: op2 & width.mask(),
+                        },
+                        _ => bail!("Action Replay code {:08X} {:08X} is not supported", op1, op2),
+                    }
+                }
+            }
+        };
+        code.push(line, op);
+    }
+    Ok(code.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encrypt(mut address: u32, mut value: u32, seeds: &[u32; 4]) -> (u32, u32) {
+        let mut sum = 0u32;
+        for _ in 0..TEA_ROUNDS {
+            sum = sum.wrapping_add(TEA_DELTA);
+            address = address.wrapping_add(
+                (value << 4).wrapping_add(seeds[0]) ^ value.wrapping_add(sum) ^ (value >> 5).wrapping_add(seeds[1]),
+            );
+            value = value.wrapping_add(
+                (address << 4).wrapping_add(seeds[2]) ^ address.wrapping_add(sum) ^ (address >> 5).wrapping_add(seeds[3]),
+            );
+        }
+        (address, value)
+    }
+
+    fn skips(code: &Code) -> Vec<usize> {
+        code.ops
+            .iter()
+            .filter_map(|op| match op {
+                Op::If {
+                    skip: Skip::Next(count),
+                    ..
+                } => Some(*count),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn decrypt_matches_the_tea_reference() {
+        // The published TEA vector: zero key and plaintext
+        assert_eq!(decrypt(0x41EA3A0A, 0x94BAA940, &[0; 4]), (0, 0));
+    }
+
+    #[test]
+    fn encrypted_codes_parse_like_raw_ones() {
+        let raw = [(0x02001234, 0x0000_00FF)];
+        let encrypted: Vec<_> = raw.iter().map(|&(a, v)| encrypt(a, v, &V1_SEEDS)).collect();
+        assert_ne!(encrypted[0], raw[0]);
+        assert_eq!(parse_v1(&encrypted, true).unwrap().ops, parse_v1(&raw, false).unwrap().ops);
+
+        let raw = [(0x00200004, 0x56)];
+        let encrypted: Vec<_> = raw.iter().map(|&(a, v)| encrypt(a, v, &V3_SEEDS)).collect();
+        assert_eq!(parse_v3(&encrypted, true).unwrap().ops, parse_v3(&raw, false).unwrap().ops);
+    }
+
+    #[test]
+    fn v1_conditions_skip_lines_not_ops() {
+        let lines = [
+            // If 0x2000000 == 0x1234, skip two lines
+            (0xE0021234, 0x02000000),
+            // An address list spanning both of them
+            (0x30000002, 0xAAAA),
+            (0x02000010, 0x02000020),
+            (0x02000030, 0x55),
+            (0xF8000100, 0),
+        ];
+        let code = parse_v1(&lines, false).unwrap();
+        assert_eq!(code.ops.len(), 3);
+        assert_eq!(skips(&code), vec![1]);
+        assert_eq!(code.hook, Some(0x8000100));
+    }
+
+    #[test]
+    fn v3_conditions_skip_lines_not_ops() {
+        let lines = [
+            // If the halfword at 0x2000000 == 0x1234, skip two lines
+            (0x4A200000, 0x1234),
+            // A ROM patch, which takes both
+            (0, 0x18000010),
+            (0xBEEF, 0),
+            (0x00200004, 0x56),
+        ];
+        let code = parse_v3(&lines, false).unwrap();
+        assert_eq!(
+            code.ops[1],
+            Op::Patch {
+                address: 0x8000020,
+                value: 0xBEEF
+            }
+        );
+        assert_eq!(skips(&code), vec![1]);
+    }
+
+    #[test]
+    fn reseeding_is_reported() {
+        assert!(parse_v1(&[(RESEED, 0x1000)], false).is_err());
+    }
+}
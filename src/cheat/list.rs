@@ -0,0 +1,243 @@
+use anyhow::{anyhow, bail, Context, Result};
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::arm::mem::Mem;
+use crate::cheat::codebreaker;
+use crate::cheat::gameshark;
+use crate::cheat::op::{self, Code, Op};
+
+const CHEATS_DIR: &str = "cheats";
+
+// Name, format, enabled, codes and the line the cheat started on
+type PendingCheat = (String, Option<CodeFormat>, bool, Vec<(u32, u32)>, usize);
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum CodeFormat {
+    GameSharkV1,
+    GameSharkV1Raw,
+    ActionReplayV3,
+    ActionReplayV3Raw,
+    CodeBreaker,
+}
+
+impl FromStr for CodeFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "gsv1" | "arv1" => Ok(CodeFormat::GameSharkV1),
+            "gsv1-raw" | "arv1-raw" => Ok(CodeFormat::GameSharkV1Raw),
+            "arv3" | "gsv3" => Ok(CodeFormat::ActionReplayV3),
+            "arv3-raw" | "gsv3-raw" => Ok(CodeFormat::ActionReplayV3Raw),
+            "cb" | "codebreaker" => Ok(CodeFormat::CodeBreaker),
+            _ => Err(anyhow!("unknown cheat format '{}' (expected gsv1, gsv1-raw, arv3, arv3-raw or cb)", s)),
+        }
+    }
+}
+
+impl CodeFormat {
+    // Digits in the address and value halves of a line
+    fn digits(self) -> (usize, usize) {
+        match self {
+            CodeFormat::CodeBreaker => (8, 4),
+            _ => (8, 8),
+        }
+    }
+
+    fn parse_line(self, line: &str) -> Result<(u32, u32)> {
+        let digits: String = line.chars().filter(|c| !c.is_whitespace()).collect();
+        let (address_digits, value_digits) = self.digits();
+        if digits.len() != address_digits + value_digits || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+            bail!("expected {} hex digits then {} more, got '{}'", address_digits, value_digits, line);
+        }
+        let address = u32::from_str_radix(&digits[..address_digits], 16)?;
+        let value = u32::from_str_radix(&digits[address_digits..], 16)?;
+        Ok((address, value))
+    }
+
+    pub fn parse(self, lines: &[(u32, u32)]) -> Result<Code> {
+        match self {
+            CodeFormat::GameSharkV1 => gameshark::parse_v1(lines, true),
+            CodeFormat::GameSharkV1Raw => gameshark::parse_v1(lines, false),
+            CodeFormat::ActionReplayV3 => gameshark::parse_v3(lines, true),
+            CodeFormat::ActionReplayV3Raw => gameshark::parse_v3(lines, false),
+            CodeFormat::CodeBreaker => codebreaker::parse(lines),
+        }
+    }
+}
+
+pub struct Cheat {
+    pub name: String,
+    enabled: bool,
+    ops: Vec<Op>,
+    hook: Option<u32>,
+    // ROM halfwords from before the cheat patched them
+    saved: Vec<(u32, u16)>,
+}
+
+#[derive(Default)]
+pub struct CheatList {
+    cheats: Vec<Cheat>,
+    // Cheats left out of a loaded file, and why
+    skipped: Vec<String>,
+}
+
+impl CheatList {
+    pub fn new() -> Self {
+        CheatList::default()
+    }
+
+    // cheats/<game code>.cht, if there is one
+    pub fn for_game(game_code: &str) -> Result<Self> {
+        let path = Path::new(CHEATS_DIR).join(format!("{}.cht", game_code));
+        if game_code.is_empty() || !path.exists() {
+            return Ok(CheatList::new());
+        }
+        CheatList::load(&path)
+    }
+
+    // A cheat starts with "[name]", then "format = <format>" and optionally
+    // "enabled = false", then one code per line. Lines starting with # are comments.
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path).with_context(|| format!("could not read {}", path.display()))?;
+        let mut list = CheatList::new();
+        let mut current: Option<PendingCheat> = None;
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            let location = format!("{}:{}", path.display(), number + 1);
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if line.starts_with('[') && line.ends_with(']') {
+                if let Some(cheat) = current.take() {
+                    list.finish(path, cheat)?;
+                }
+                current = Some((line[1..line.len() - 1].trim().to_string(), None, true, Vec::new(), number + 1));
+                continue;
+            }
+            let (_, format, enabled, codes, _) = current
+                .as_mut()
+                .with_context(|| format!("{}: expected [cheat name] before any codes", location))?;
+            if let Some(index) = line.find('=') {
+                let (key, value) = (line[..index].trim(), line[index + 1..].trim());
+                match key {
+                    "format" => *format = Some(value.parse().context(location)?),
+                    "enabled" => {
+                        *enabled = value
+                            .parse()
+                            .with_context(|| format!("{}: enabled must be true or false", location))?
+                    }
+                    _ => bail!("{}: unknown cheat setting '{}'", location, key),
+                }
+                continue;
+            }
+            let format = format.with_context(|| format!("{}: the cheat needs a format before its codes", location))?;
+            codes.push(format.parse_line(line).context(location)?);
+        }
+        if let Some(cheat) = current.take() {
+            list.finish(path, cheat)?;
+        }
+        Ok(list)
+    }
+
+    fn finish(&mut self, path: &Path, (name, format, enabled, codes, line): PendingCheat) -> Result<()> {
+        let location = format!("{}:{}", path.display(), line);
+        let format = format.with_context(|| format!("{}: cheat '{}' has no format", location, name))?;
+        // Codes this engine can't run only cost their own cheat, not the whole file
+        if let Err(e) = self.add(&name, format, &codes) {
+            self.skipped.push(format!("{}: skipped cheat '{}': {:#}", location, name, e));
+            return Ok(());
+        }
+        self.cheats.last_mut().unwrap().enabled = enabled;
+        Ok(())
+    }
+
+    pub fn add(&mut self, name: &str, format: CodeFormat, lines: &[(u32, u32)]) -> Result<()> {
+        let code = format.parse(lines)?;
+        self.cheats.push(Cheat {
+            name: name.to_string(),
+            enabled: true,
+            ops: code.ops,
+            hook: code.hook,
+            saved: Vec::new(),
+        });
+        Ok(())
+    }
+
+    pub fn cheats(&self) -> &[Cheat] {
+        &self.cheats
+    }
+
+    pub fn skipped(&self) -> &[String] {
+        &self.skipped
+    }
+
+    pub fn is_enabled(&self, index: usize) -> bool {
+        self.cheats.get(index).is_some_and(|cheat| cheat.enabled)
+    }
+
+    // Turning a cheat off undoes its ROM patches; RAM writes just stop
+    pub fn set_enabled(&mut self, index: usize, enabled: bool, mem: &mut Mem) {
+        if let Some(cheat) = self.cheats.get_mut(index) {
+            cheat.enabled = enabled;
+            if !enabled {
+                op::restore(mem, &mut cheat.saved);
+            }
+        }
+    }
+
+    // Returns the new state, or None if there is no such cheat
+    pub fn toggle(&mut self, index: usize, mem: &mut Mem) -> Option<bool> {
+        let enabled = !self.cheats.get(index)?.enabled;
+        self.set_enabled(index, enabled, mem);
+        Some(enabled)
+    }
+
+    // With a master code enabled, cheats run when the game reaches its hook instead of once a frame
+    pub fn hook(&self) -> Option<u32> {
+        self.cheats.iter().filter(|cheat| cheat.enabled).find_map(|cheat| cheat.hook)
+    }
+
+    pub fn apply(&mut self, mem: &mut Mem) {
+        for cheat in self.cheats.iter_mut().filter(|cheat| cheat.enabled) {
+            op::run(&cheat.ops, mem, &mut cheat.saved);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn unusable_cheats_are_skipped_without_losing_the_file() {
+        let path = env::temp_dir().join(format!("gbaemu-cheats-{}.cht", std::process::id()));
+        let contents = "\
+# Encrypted lists open with a seed code
+[Encrypted]
+format = cb
+9000ABCD 1234
+[Max money]
+format = cb
+enabled = false
+82025BC4 270F
+[Hold Start for 99 lives]
+format = cb
+D0000020 0008
+32001234 0063
+";
+        fs::write(&path, contents).unwrap();
+        let list = CheatList::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let names: Vec<&str> = list.cheats().iter().map(|cheat| cheat.name.as_str()).collect();
+        assert_eq!(names, vec!["Max money", "Hold Start for 99 lives"]);
+        assert!(!list.is_enabled(0));
+        assert!(list.is_enabled(1));
+        assert_eq!(list.skipped().len(), 1);
+        assert!(list.skipped()[0].contains(":2: skipped cheat 'Encrypted'"));
+    }
+}
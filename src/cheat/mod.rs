@@ -0,0 +1,5 @@
+pub mod codebreaker;
+pub mod gameshark;
+pub mod list;
+pub mod op;
+pub mod search;
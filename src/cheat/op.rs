@@ -0,0 +1,259 @@
+use crate::arm::common::{HalfWord, Word};
+use crate::arm::mem::Mem;
+
+pub const ROM_START: u32 = 0x8000000;
+pub const IO_START: u32 = 0x4000000;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Width {
+    Byte,
+    Half,
+    Word,
+}
+
+impl Width {
+    pub fn bytes(self) -> u32 {
+        match self {
+            Width::Byte => 1,
+            Width::Half => 2,
+            Width::Word => 4,
+        }
+    }
+
+    pub fn mask(self) -> u32 {
+        match self {
+            Width::Byte => 0xFF,
+            Width::Half => 0xFFFF,
+            Width::Word => 0xFFFF_FFFF,
+        }
+    }
+
+    pub fn read(self, mem: &Mem, address: u32) -> u32 {
+        let address = address as usize;
+        match self {
+            Width::Byte => mem.get_byte(address) as u32,
+            Width::Half => mem.get_halfword(address).little_endian() as u32,
+            Width::Word => mem.get_word(address).little_endian(),
+        }
+    }
+
+    fn write(self, mem: &mut Mem, address: u32, value: u32) {
+        let address = address as usize;
+        match self {
+            Width::Byte => mem.set_byte(address, value as u8),
+            Width::Half => mem.set_halfword(address, HalfWord::from_u16_le(value as u16)),
+            Width::Word => mem.set_word(address, Word::from_u32_le(value)),
+        }
+    }
+
+    pub fn sign_extend(self, value: u32) -> i32 {
+        let shift = 32 - 8 * self.bytes();
+        ((value << shift) as i32) >> shift
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Condition {
+    Equal,
+    NotEqual,
+    Less,
+    Greater,
+    LessUnsigned,
+    GreaterUnsigned,
+    // Any of the value's bits set
+    And,
+    // Any of the value's bits clear, which for KEYINPUT means any of those keys held
+    AnyClear,
+    Never,
+}
+
+impl Condition {
+    fn test(self, width: Width, current: u32, value: u32) -> bool {
+        match self {
+            Condition::Equal => current == value,
+            Condition::NotEqual => current != value,
+            Condition::Less => width.sign_extend(current) < width.sign_extend(value),
+            Condition::Greater => width.sign_extend(current) > width.sign_extend(value),
+            Condition::LessUnsigned => current < value,
+            Condition::GreaterUnsigned => current > value,
+            Condition::And => current & value != 0,
+            Condition::AnyClear => current & value != value,
+            Condition::Never => false,
+        }
+    }
+}
+
+// What a failed condition skips over
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Skip {
+    // Parsers count code lines, like the devices do, and Code::finish turns that into ops
+    Next(usize),
+    // Up to the matching Else or EndIf
+    Block,
+    // Everything left in the cheat
+    Rest,
+}
+
+// Every code format boils down to these
+#[derive(Clone, PartialEq, Debug)]
+pub enum Op {
+    Write { address: u32, width: Width, value: u32 },
+    // count writes, moving the address and value on after each one
+    Slide { address: u32, width: Width, value: u32, count: u32, address_step: u32, value_step: u32 },
+    WriteList { addresses: Vec<u32>, width: Width, value: u32 },
+    Bytes { address: u32, data: Vec<u8> },
+    Add { address: u32, width: Width, value: u32 },
+    Or { address: u32, width: Width, value: u32 },
+    And { address: u32, width: Width, value: u32 },
+    // Writes through the pointer stored at address
+    Indirect { address: u32, offset: u32, width: Width, value: u32 },
+    // A ROM halfword, put back when the cheat is turned off
+    Patch { address: u32, value: u16 },
+    If { address: u32, width: Width, condition: Condition, value: u32, skip: Skip },
+    Else,
+    EndIf,
+}
+
+#[derive(Default)]
+pub struct Code {
+    pub ops: Vec<Op>,
+    // Master code: where the game should be when the cheats run
+    pub hook: Option<u32>,
+    // The code line each op starts on
+    lines: Vec<usize>,
+}
+
+impl Code {
+    pub fn push(&mut self, line: usize, op: Op) {
+        self.ops.push(op);
+        self.lines.push(line);
+    }
+
+    // A line can make no op (a master code) or share one with the lines after it (a slide),
+    // so skipping n lines only skips the ops that start on them
+    pub fn finish(mut self) -> Self {
+        for i in 0..self.ops.len() {
+            if let Op::If {
+                skip: Skip::Next(ref mut count),
+                ..
+            } = self.ops[i]
+            {
+                let end = self.lines[i] + 1 + *count;
+                *count = self.lines[i + 1..].iter().take_while(|&&line| line < end).count();
+            }
+        }
+        self
+    }
+}
+
+// Index of the Else or EndIf that closes the block opened just before start
+fn block_end(ops: &[Op], start: usize, stop_at_else: bool) -> usize {
+    let mut depth = 0;
+    for (i, op) in ops.iter().enumerate().skip(start + 1) {
+        match op {
+            Op::If { skip: Skip::Block, .. } => depth += 1,
+            Op::Else if depth == 0 && stop_at_else => return i,
+            Op::EndIf if depth == 0 => return i,
+            Op::EndIf => depth -= 1,
+            _ => {}
+        }
+    }
+    ops.len()
+}
+
+// saved collects the original ROM halfwords under any patches
+pub fn run(ops: &[Op], mem: &mut Mem, saved: &mut Vec<(u32, u16)>) {
+    let mut i = 0;
+    while i < ops.len() {
+        match ops[i] {
+            Op::Write { address, width, value } => width.write(mem, address, value),
+            Op::Slide {
+                address,
+                width,
+                value,
+                count,
+                address_step,
+                value_step,
+            } => {
+                for n in 0..count {
+                    let address = address.wrapping_add(n.wrapping_mul(address_step));
+                    width.write(mem, address, value.wrapping_add(n.wrapping_mul(value_step)));
+                }
+            }
+            Op::WriteList {
+                ref addresses,
+                width,
+                value,
+            } => {
+                for &address in addresses.iter() {
+                    width.write(mem, address, value);
+                }
+            }
+            Op::Bytes { address, ref data } => {
+                for (n, &byte) in data.iter().enumerate() {
+                    mem.set_byte(address as usize + n, byte);
+                }
+            }
+            Op::Add { address, width, value } => {
+                width.write(mem, address, width.read(mem, address).wrapping_add(value))
+            }
+            Op::Or { address, width, value } => width.write(mem, address, width.read(mem, address) | value),
+            Op::And { address, width, value } => width.write(mem, address, width.read(mem, address) & value),
+            Op::Indirect {
+                address,
+                offset,
+                width,
+                value,
+            } => {
+                let pointer = Width::Word.read(mem, address);
+                width.write(mem, pointer.wrapping_add(offset), value);
+            }
+            Op::Patch { address, value } => {
+                if !saved.iter().any(|&(patched, _)| patched == address) {
+                    saved.push((address, Width::Half.read(mem, address) as u16));
+                }
+                Width::Half.write(mem, address, value as u32);
+            }
+            Op::If {
+                address,
+                width,
+                condition,
+                value,
+                skip,
+            } => {
+                if !condition.test(width, width.read(mem, address), value) {
+                    match skip {
+                        Skip::Next(count) => i += count,
+                        Skip::Block => i = block_end(ops, i, true),
+                        Skip::Rest => return,
+                    }
+                }
+            }
+            // Reaching an Else means the If side ran
+            Op::Else => i = block_end(ops, i, false),
+            Op::EndIf => {}
+        }
+        i += 1;
+    }
+}
+
+pub fn restore(mem: &mut Mem, saved: &mut Vec<(u32, u16)>) {
+    for (address, value) in saved.drain(..) {
+        Width::Half.write(mem, address, value as u32);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_conditions_follow_active_low_keyinput() {
+        // KEYINPUT with Start (bit 3) held
+        let keys = 0x03F7;
+        assert!(Condition::AnyClear.test(Width::Half, keys, 0x0008));
+        assert!(Condition::AnyClear.test(Width::Half, keys, 0x0009));
+        assert!(!Condition::AnyClear.test(Width::Half, keys, 0x0001));
+        assert!(!Condition::And.test(Width::Half, keys, 0x0008));
+    }
+}
@@ -0,0 +1,258 @@
+use anyhow::{anyhow, bail, Context, Result};
+use std::io::{self, BufRead};
+use std::str::FromStr;
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+
+use crate::arm::mem::Mem;
+use crate::cheat::op::Width;
+
+// EWRAM and IWRAM, the only places game variables live
+const REGIONS: [(u32, u32); 2] = [(0x2000000, 0x40000), (0x3000000, 0x8000)];
+
+// How many addresses "list" shows unless told otherwise
+const DEFAULT_LIST_LIMIT: usize = 20;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Comparison {
+    // Against the value at the last search
+    Equal,
+    Changed,
+    Increased,
+    Decreased,
+    // Against a fixed value
+    Value(i64),
+}
+
+impl FromStr for Comparison {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "eq" | "equal" | "same" => Ok(Comparison::Equal),
+            "ne" | "changed" => Ok(Comparison::Changed),
+            "inc" | "increased" => Ok(Comparison::Increased),
+            "dec" | "decreased" => Ok(Comparison::Decreased),
+            _ => parse_number(s).map(Comparison::Value),
+        }
+    }
+}
+
+fn parse_number(s: &str) -> Result<i64> {
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, s),
+    };
+    let value = match digits.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16),
+        None => digits.parse(),
+    }
+    .map_err(|_| anyhow!("'{}' is not a comparison or a number", s))?;
+    Ok(if negative { -value } else { value })
+}
+
+pub struct Candidate {
+    pub address: u32,
+    // As of the last search
+    pub value: i64,
+}
+
+pub struct RamSearch {
+    width: Width,
+    signed: bool,
+    candidates: Vec<Candidate>,
+}
+
+impl RamSearch {
+    // Snapshots every aligned value in EWRAM and IWRAM
+    pub fn new(mem: &Mem, width: Width, signed: bool) -> Self {
+        let mut search = RamSearch {
+            width,
+            signed,
+            candidates: Vec::new(),
+        };
+        for &(start, size) in REGIONS.iter() {
+            for address in (start..start + size).step_by(width.bytes() as usize) {
+                let value = search.read(mem, address);
+                search.candidates.push(Candidate { address, value });
+            }
+        }
+        search
+    }
+
+    fn read(&self, mem: &Mem, address: u32) -> i64 {
+        let raw = self.width.read(mem, address);
+        if self.signed {
+            self.width.sign_extend(raw) as i64
+        } else {
+            raw as i64
+        }
+    }
+
+    // Drops every address that fails the comparison and returns how many are left
+    pub fn narrow(&mut self, mem: &Mem, comparison: Comparison) -> usize {
+        let candidates = std::mem::take(&mut self.candidates);
+        let kept: Vec<Candidate> = candidates
+            .into_iter()
+            .filter_map(|candidate| {
+                let current = self.read(mem, candidate.address);
+                let keep = match comparison {
+                    Comparison::Equal => current == candidate.value,
+                    Comparison::Changed => current != candidate.value,
+                    Comparison::Increased => current > candidate.value,
+                    Comparison::Decreased => current < candidate.value,
+                    Comparison::Value(value) => current == value,
+                };
+                // The next comparison is against what is there now
+                if keep {
+                    Some(Candidate {
+                        address: candidate.address,
+                        value: current,
+                    })
+                } else {
+                    None
+                }
+            })
+            .collect();
+        self.candidates = kept;
+        self.candidates.len()
+    }
+
+    pub fn candidates(&self) -> &[Candidate] {
+        &self.candidates
+    }
+
+    pub fn len(&self) -> usize {
+        self.candidates.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.candidates.is_empty()
+    }
+}
+
+fn parse_width(s: &str) -> Result<Width> {
+    match s {
+        "8" => Ok(Width::Byte),
+        "16" => Ok(Width::Half),
+        "32" => Ok(Width::Word),
+        _ => bail!("width must be 8, 16 or 32, not '{}'", s),
+    }
+}
+
+// Text commands for driving a search from a console:
+//   new <8|16|32> [signed]    start over from a fresh snapshot
+//   eq | changed | inc | dec  compare with the last search
+//   <number>                  keep addresses holding that value (0x for hex)
+//   list [count]              show what is left
+pub fn command(search: &mut Option<RamSearch>, mem: &Mem, line: &str) -> Result<String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match words.as_slice() {
+        [] => Ok(String::new()),
+        ["new", width, rest @ ..] => {
+            let signed = match rest {
+                [] | ["unsigned"] => false,
+                ["signed"] => true,
+                _ => bail!("usage: new <8|16|32> [signed]"),
+            };
+            let started = RamSearch::new(mem, parse_width(width)?, signed);
+            let message = format!("{} candidates", started.len());
+            *search = Some(started);
+            Ok(message)
+        }
+        ["list", rest @ ..] => {
+            let search = search.as_ref().context("no search running, start one with 'new'")?;
+            let limit = match rest {
+                [] => DEFAULT_LIST_LIMIT,
+                [count] => count.parse().with_context(|| format!("'{}' is not a count", count))?,
+                _ => bail!("usage: list [count]"),
+            };
+            let mut output = format!("{} candidates", search.len());
+            for candidate in search.candidates().iter().take(limit) {
+                output.push_str(&format!("\n{:08X}: {}", candidate.address, candidate.value));
+            }
+            Ok(output)
+        }
+        [comparison] => {
+            let comparison: Comparison = comparison.parse()?;
+            let search = search.as_mut().context("no search running, start one with 'new'")?;
+            Ok(format!("{} candidates", search.narrow(mem, comparison)))
+        }
+        _ => bail!("unknown search command '{}'", line),
+    }
+}
+
+// Lines typed on stdin, for running search commands while the game plays
+pub fn spawn_console() -> Receiver<String> {
+    let (sender, receiver) = channel();
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EWRAM: u32 = 0x2000000;
+    const IWRAM: u32 = 0x3000000;
+
+    fn ram() -> Mem {
+        // Up to the end of IO, which holds KEYINPUT
+        let mut mem = Mem::new(0x4000400);
+        mem.load(0, io::repeat(0)).unwrap();
+        mem
+    }
+
+    fn addresses(search: &RamSearch) -> Vec<u32> {
+        search.candidates().iter().map(|candidate| candidate.address).collect()
+    }
+
+    #[test]
+    fn narrowing_tracks_values_across_both_regions() {
+        let mut mem = ram();
+        mem.set_byte(EWRAM as usize + 0x10, 5);
+        mem.set_byte(IWRAM as usize + 0x7FFF, 5);
+        let mut search = RamSearch::new(&mem, Width::Byte, false);
+        assert_eq!(search.len(), 0x48000);
+
+        assert_eq!(search.narrow(&mem, Comparison::Value(5)), 2);
+        assert_eq!(addresses(&search), vec![EWRAM + 0x10, IWRAM + 0x7FFF]);
+        assert_eq!(search.narrow(&mem, Comparison::Equal), 2);
+
+        mem.set_byte(EWRAM as usize + 0x10, 6);
+        mem.set_byte(IWRAM as usize + 0x7FFF, 4);
+        assert_eq!(search.narrow(&mem, Comparison::Increased), 1);
+        assert_eq!(addresses(&search), vec![EWRAM + 0x10]);
+        // Later comparisons are against the value now, not the first snapshot
+        assert_eq!(search.narrow(&mem, Comparison::Changed), 0);
+        assert!(search.is_empty());
+    }
+
+    #[test]
+    fn changes_are_seen_in_either_direction() {
+        let mut mem = ram();
+        let mut changed = RamSearch::new(&mem, Width::Half, false);
+        let mut decreased = RamSearch::new(&mem, Width::Half, true);
+        assert_eq!(changed.len(), 0x24000);
+
+        mem.set_byte(EWRAM as usize + 0x3FFFF, 0x80);
+        mem.set_byte(IWRAM as usize + 0x100, 1);
+        assert_eq!(changed.narrow(&mem, Comparison::Changed), 2);
+        assert_eq!(addresses(&changed), vec![EWRAM + 0x3FFFE, IWRAM + 0x100]);
+
+        // 0x8000 is negative once sign extended
+        assert_eq!(decreased.narrow(&mem, Comparison::Decreased), 1);
+        assert_eq!(decreased.candidates()[0].value, -0x8000);
+        assert_eq!(decreased.narrow(&mem, Comparison::Value(-0x8000)), 1);
+    }
+}
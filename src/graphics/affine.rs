@@ -0,0 +1,99 @@
+use crate::arm::mem::Mem;
+
+// Rotation/scaling registers, first for BG2 then for BG3. PA-PD are signed 8.8 fixed point.
+pub const BG_X_SCALE_ADDR: [usize; 2] = [0x4000020, 0x4000030];
+pub const BG_X_SHEAR_ADDR: [usize; 2] = [0x4000022, 0x4000032];
+pub const BG_Y_SHEAR_ADDR: [usize; 2] = [0x4000024, 0x4000034];
+pub const BG_Y_SCALE_ADDR: [usize; 2] = [0x4000026, 0x4000036];
+// Reference points, signed 20.8 fixed point in 28 bits
+pub const BG_AFFINE_HORIZONTAL_OFFSET: [usize; 2] = [0x4000028, 0x4000038];
+pub const BG_AFFINE_VERTICAL_OFFSET: [usize; 2] = [0x400002C, 0x400003C];
+pub const REFERENCE_START: usize = 0x4000028;
+pub const REFERENCE_END: usize = 0x400003F;
+
+fn read_param(mem: &Mem, address: usize) -> i32 {
+    mem.get_halfword(address).little_endian() as i16 as i32
+}
+
+fn read_reference(mem: &Mem, address: usize) -> i32 {
+    ((mem.get_word(address).little_endian() << 4) as i32) >> 4
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Params {
+    // Texture step for each pixel along the line
+    pub pa: i32,
+    pub pc: i32,
+    // and for each line down the screen
+    pub pb: i32,
+    pub pd: i32,
+}
+
+impl Params {
+    // affine is 0 for BG2 and 1 for BG3
+    pub fn read(mem: &Mem, affine: usize) -> Self {
+        Params {
+            pa: read_param(mem, BG_X_SCALE_ADDR[affine]),
+            pb: read_param(mem, BG_X_SHEAR_ADDR[affine]),
+            pc: read_param(mem, BG_Y_SHEAR_ADDR[affine]),
+            pd: read_param(mem, BG_Y_SCALE_ADDR[affine]),
+        }
+    }
+}
+
+// Which of BG2X, BG2Y, BG3X and BG3Y a register halfword belongs to
+pub fn reference_index(byte_index: usize) -> Option<usize> {
+    (0..2).find_map(|affine| {
+        if byte_index & !2 == BG_AFFINE_HORIZONTAL_OFFSET[affine] {
+            Some(2 * affine)
+        } else if byte_index & !2 == BG_AFFINE_VERTICAL_OFFSET[affine] {
+            Some(2 * affine + 1)
+        } else {
+            None
+        }
+    })
+}
+
+// The hardware draws from its own copy of the reference points. It copies the registers at
+// VBlank and whenever the game writes them, and moves the copy on by PB and PD after every line.
+#[derive(Default)]
+pub struct ReferencePoints {
+    points: [i32; 4],
+}
+
+impl ReferencePoints {
+    pub fn new() -> Self {
+        ReferencePoints { points: [0; 4] }
+    }
+
+    fn load(&mut self, mem: &Mem, index: usize) {
+        let registers = if index.is_multiple_of(2) { BG_AFFINE_HORIZONTAL_OFFSET } else { BG_AFFINE_VERTICAL_OFFSET };
+        self.points[index] = read_reference(mem, registers[index / 2]);
+    }
+
+    pub fn latch(&mut self, mem: &Mem) {
+        for index in 0..4 {
+            self.load(mem, index);
+        }
+    }
+
+    // written has a bit set for each reference_index the game wrote
+    pub fn reload(&mut self, mem: &Mem, written: u8) {
+        for index in (0..4).filter(|index| written >> index & 1 == 1) {
+            self.load(mem, index);
+        }
+    }
+
+    pub fn advance(&mut self, mem: &Mem) {
+        for affine in 0..2 {
+            let params = Params::read(mem, affine);
+            self.points[2 * affine] = self.points[2 * affine].wrapping_add(params.pb);
+            self.points[2 * affine + 1] = self.points[2 * affine + 1].wrapping_add(params.pd);
+        }
+    }
+
+    // Texture coordinates of the line's first pixel, still in 20.8
+    pub fn origin(&self, affine: usize) -> (i32, i32) {
+        (self.points[2 * affine], self.points[2 * affine + 1])
+    }
+}
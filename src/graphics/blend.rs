@@ -0,0 +1,177 @@
+use crate::arm::mem::Mem;
+
+const REG_BLDCNT: usize = 0x4000050;
+const REG_BLDALPHA: usize = 0x4000052;
+const REG_BLDY: usize = 0x4000054;
+
+// Target bits in BLDCNT are BG0-BG3, then OBJ, then the backdrop
+pub const BACKDROP_LAYER: usize = 5;
+const SECOND_TARGET_SHIFT: u16 = 8;
+const EFFECT_SHIFT: u16 = 6;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Effect {
+    None,
+    Alpha,
+    Brighten,
+    Darken,
+}
+
+// An opaque pixel from one of the layers, or the backdrop
+#[derive(Copy, Clone, Debug)]
+pub struct Pixel {
+    pub color: u16,
+    pub layer: usize,
+    // From an OBJ with the semi-transparent mode, which blends whatever BLDCNT says
+    pub semi_transparent: bool,
+}
+
+impl Pixel {
+    pub fn backdrop(mem: &Mem) -> Self {
+        Pixel {
+            color: mem.get_halfword(0x5000000).little_endian() & 0x7FFF,
+            layer: BACKDROP_LAYER,
+            semi_transparent: false,
+        }
+    }
+}
+
+fn channels(color: u16) -> [u16; 3] {
+    [color & 0x1F, color >> 5 & 0x1F, color >> 10 & 0x1F]
+}
+
+fn from_channels(channels: [u16; 3]) -> u16 {
+    channels[0] | channels[1] << 5 | channels[2] << 10
+}
+
+// BLDCNT, BLDALPHA and BLDY for one line
+pub struct ColorEffects {
+    effect: Effect,
+    first_targets: u16,
+    second_targets: u16,
+    // Coefficients out of 16
+    eva: u16,
+    evb: u16,
+    evy: u16,
+}
+
+impl ColorEffects {
+    pub fn new(mem: &Mem) -> Self {
+        let control = mem.get_halfword(REG_BLDCNT).little_endian();
+        let alpha = mem.get_halfword(REG_BLDALPHA).little_endian();
+        let brightness = mem.get_halfword(REG_BLDY).little_endian();
+        ColorEffects {
+            effect: match control >> EFFECT_SHIFT & 0b11 {
+                0 => Effect::None,
+                1 => Effect::Alpha,
+                2 => Effect::Brighten,
+                _ => Effect::Darken,
+            },
+            first_targets: control & 0x3F,
+            second_targets: control >> SECOND_TARGET_SHIFT & 0x3F,
+            eva: (alpha & 0x1F).min(16),
+            evb: (alpha >> 8 & 0x1F).min(16),
+            evy: (brightness & 0x1F).min(16),
+        }
+    }
+
+    fn alpha(&self, top: u16, below: u16) -> u16 {
+        let (top, below) = (channels(top), channels(below));
+        let mut blended = [0; 3];
+        for i in 0..3 {
+            blended[i] = ((top[i] * self.eva + below[i] * self.evb) >> 4).min(31);
+        }
+        from_channels(blended)
+    }
+
+    fn brightness(&self, color: u16, brighten: bool) -> u16 {
+        let mut channels = channels(color);
+        for channel in channels.iter_mut() {
+            if brighten {
+                *channel += ((31 - *channel) * self.evy) >> 4;
+            } else {
+                *channel -= (*channel * self.evy) >> 4;
+            }
+        }
+        from_channels(channels)
+    }
+
+    // The color that shows, given the front two pixels. enabled is the window's say on effects.
+    pub fn apply(&self, top: Pixel, below: Option<Pixel>, enabled: bool) -> u16 {
+        if !enabled {
+            return top.color;
+        }
+        let below = below.filter(|below| self.second_targets >> below.layer & 1 == 1);
+        if top.semi_transparent {
+            if let Some(below) = below {
+                return self.alpha(top.color, below.color);
+            }
+        }
+        if self.first_targets >> top.layer & 1 == 0 {
+            return top.color;
+        }
+        match (self.effect, below) {
+            (Effect::Alpha, Some(below)) => self.alpha(top.color, below.color),
+            (Effect::Brighten, _) => self.brightness(top.color, true),
+            (Effect::Darken, _) => self.brightness(top.color, false),
+            _ => top.color,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arm::common::HalfWord;
+
+    const WHITE: u16 = 0x7FFF;
+    const GREY: u16 = 16 | 16 << 5 | 16 << 10;
+
+    fn effects(control: u16, alpha: u16, brightness: u16) -> ColorEffects {
+        let mut mem = Mem::new(0x4000400);
+        mem.set_halfword(REG_BLDCNT, HalfWord::from_u16_le(control));
+        mem.set_halfword(REG_BLDALPHA, HalfWord::from_u16_le(alpha));
+        mem.set_halfword(REG_BLDY, HalfWord::from_u16_le(brightness));
+        ColorEffects::new(&mem)
+    }
+
+    fn pixel(color: u16, layer: usize) -> Pixel {
+        Pixel {
+            color,
+            layer,
+            semi_transparent: false,
+        }
+    }
+
+    #[test]
+    fn coefficients_stop_at_sixteen() {
+        // BG0 over BG1 with EVA and EVB both 31, which the hardware reads as 16
+        let blend = effects(1 << EFFECT_SHIFT | 0b10 << SECOND_TARGET_SHIFT | 0b01, 31 << 8 | 31, 0);
+        assert_eq!((blend.eva, blend.evb), (16, 16));
+        // Adding the two still can't overflow a channel
+        assert_eq!(blend.apply(pixel(WHITE, 0), Some(pixel(GREY, 1)), true), WHITE);
+        assert_eq!(blend.apply(pixel(0x0010, 0), Some(pixel(0x0008, 1)), true), 0x0018);
+
+        let blend = effects(2 << EFFECT_SHIFT | 0b01, 0, 31);
+        assert_eq!(blend.evy, 16);
+        assert_eq!(blend.apply(pixel(GREY, 0), None, true), WHITE);
+        let blend = effects(3 << EFFECT_SHIFT | 0b01, 0, 20);
+        assert_eq!(blend.apply(pixel(GREY, 0), None, true), 0);
+    }
+
+    #[test]
+    fn partial_coefficients_round_down() {
+        let blend = effects(1 << EFFECT_SHIFT | 0b10 << SECOND_TARGET_SHIFT | 0b01, 4 << 8 | 12, 0);
+        // (31 * 12 + 16 * 4) / 16 = 27.25 in every channel
+        assert_eq!(blend.apply(pixel(WHITE, 0), Some(pixel(GREY, 1)), true), 27 | 27 << 5 | 27 << 10);
+
+        // Brighten by 8/16: 16 + 15 * 8 / 16 = 23.5, darken: 16 - 16 * 8 / 16 = 8
+        let blend = effects(2 << EFFECT_SHIFT | 0b01, 0, 8);
+        assert_eq!(blend.apply(pixel(GREY, 0), None, true), 23 | 23 << 5 | 23 << 10);
+        let blend = effects(3 << EFFECT_SHIFT | 0b01, 0, 8);
+        assert_eq!(blend.apply(pixel(GREY, 0), None, true), 8 | 8 << 5 | 8 << 10);
+        // Not a first target, or the window says no
+        assert_eq!(blend.apply(pixel(GREY, 1), None, true), GREY);
+        assert_eq!(blend.apply(pixel(GREY, 0), None, false), GREY);
+    }
+}
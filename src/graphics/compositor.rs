@@ -0,0 +1,102 @@
+use crate::graphics::blend::{ColorEffects, Pixel};
+use crate::graphics::gpu::SCREEN_WIDTH;
+use crate::graphics::window::{Windows, EFFECTS_BIT, OBJ_LAYER};
+
+// One BG's pixels on a line, None where it is transparent
+pub type Layer = [Option<u16>; SCREEN_WIDTH];
+
+pub struct BgLayer {
+    pub bg: usize,
+    pub priority: u16,
+    pub pixels: Layer,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct ObjPixel {
+    pub color: u16,
+    pub priority: u16,
+    pub semi_transparent: bool,
+}
+
+impl ObjPixel {
+    fn pixel(self) -> Pixel {
+        Pixel {
+            color: self.color,
+            layer: OBJ_LAYER,
+            semi_transparent: self.semi_transparent,
+        }
+    }
+}
+
+// The front sprite pixel at each x
+pub type ObjLine = [Option<ObjPixel>; SCREEN_WIDTH];
+
+// Sprites go in in OAM order, so on equal priority the lower OAM number stays in front
+pub fn put_obj_pixel(line: &mut ObjLine, x: usize, pixel: ObjPixel) {
+    if line[x].is_none_or(|front| pixel.priority < front.priority) {
+        line[x] = Some(pixel);
+    }
+}
+
+pub struct Compositor {
+    // Sorted front to back: by priority, then by BG number
+    bgs: Vec<BgLayer>,
+}
+
+impl Compositor {
+    pub fn new(mut bgs: Vec<BgLayer>) -> Self {
+        bgs.sort_by_key(|layer| (layer.priority, layer.bg));
+        Compositor { bgs }
+    }
+
+    // The front two pixels the windows let show at x. A sprite goes in front of any BG
+    // with the same or a lower priority, and the backdrop is behind everything.
+    fn candidates(&self, x: usize, objs: &ObjLine, enables: u8, backdrop: Pixel) -> (Pixel, Option<Pixel>) {
+        let mut found = [backdrop; 2];
+        let mut count = 0;
+        let mut push = |pixel: Pixel| {
+            if count < 2 {
+                found[count] = pixel;
+                count += 1;
+            }
+        };
+        let mut obj = objs[x].filter(|_| enables >> OBJ_LAYER & 1 == 1);
+        let bgs = self
+            .bgs
+            .iter()
+            .filter(|layer| enables >> layer.bg & 1 == 1)
+            .filter_map(|layer| layer.pixels[x].map(|color| (layer, color)));
+        for (layer, color) in bgs {
+            if let Some(front) = obj.filter(|front| front.priority <= layer.priority) {
+                push(front.pixel());
+                obj = None;
+            }
+            push(Pixel {
+                color,
+                layer: layer.bg,
+                semi_transparent: false,
+            });
+        }
+        if let Some(front) = obj {
+            push(front.pixel());
+        }
+        push(backdrop);
+        (found[0], if count == 2 { Some(found[1]) } else { None })
+    }
+
+    pub fn compose(
+        &self,
+        line: &mut [u16; SCREEN_WIDTH],
+        objs: &ObjLine,
+        obj_window: &[bool; SCREEN_WIDTH],
+        windows: &Windows,
+        effects: &ColorEffects,
+        backdrop: Pixel,
+    ) {
+        for x in 0..SCREEN_WIDTH {
+            let enables = windows.enables(x, obj_window[x]);
+            let (top, below) = self.candidates(x, objs, enables, backdrop);
+            line[x] = effects.apply(top, below, enables >> EFFECTS_BIT & 1 == 1);
+        }
+    }
+}
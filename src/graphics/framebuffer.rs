@@ -0,0 +1,58 @@
+use crate::graphics::gpu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+// 5 bit channels out to 8 bits, copying the top bits into the bottom so white stays white
+pub fn rgb555_to_rgb888(color: u16) -> [u8; 3] {
+    let expand = |channel: u16| ((channel << 3) | (channel >> 2)) as u8;
+    [expand(color & 0x1F), expand(color >> 5 & 0x1F), expand(color >> 10 & 0x1F)]
+}
+
+// One 240x160 frame in the GBA's own RGB555
+#[derive(Clone)]
+pub struct Framebuffer {
+    lines: Vec<[u16; SCREEN_WIDTH]>,
+}
+
+impl Framebuffer {
+    pub fn new() -> Self {
+        Framebuffer {
+            lines: vec![[0; SCREEN_WIDTH]; SCREEN_HEIGHT],
+        }
+    }
+
+    pub fn line(&self, y: usize) -> &[u16; SCREEN_WIDTH] {
+        &self.lines[y]
+    }
+
+    pub fn line_mut(&mut self, y: usize) -> &mut [u16; SCREEN_WIDTH] {
+        &mut self.lines[y]
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> u16 {
+        self.lines[y][x] & 0x7FFF
+    }
+
+    // Bytes R, G, B, top row first
+    pub fn to_rgb888(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(SCREEN_WIDTH * SCREEN_HEIGHT * 3);
+        for &color in self.lines.iter().flatten() {
+            bytes.extend_from_slice(&rgb555_to_rgb888(color));
+        }
+        bytes
+    }
+
+    // Bytes R, G, B, A with A always opaque
+    pub fn to_rgba8888(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(SCREEN_WIDTH * SCREEN_HEIGHT * 4);
+        for &color in self.lines.iter().flatten() {
+            bytes.extend_from_slice(&rgb555_to_rgb888(color));
+            bytes.push(0xFF);
+        }
+        bytes
+    }
+}
+
+impl Default for Framebuffer {
+    fn default() -> Self {
+        Framebuffer::new()
+    }
+}
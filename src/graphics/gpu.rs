@@ -5,21 +5,24 @@
 #![allow(unused_parens)]
 #![allow(unused_variables)]
 use std::{fs::File, io::Write, thread, u16, u8, usize};
-use gdk_pixbuf::{Pixbuf, Colorspace};
-use image::{RgbImage, Rgb};
-use sdl2::{image::LoadTexture, render::Canvas, surface::Surface, video::Window};
 use std::time::{Duration, Instant};
 use std::fmt;
 
-use crate::arm::{cpu::{Cpu}, mem::{Mem, Interrupt}};
+use crate::arm::{cpu::{Cpu}, dma::DmaTiming, mem::{Mem, Interrupt}};
 use crate::arm::common::{HalfWord};
+use crate::graphics::affine::{Params, ReferencePoints};
+use crate::graphics::blend::{ColorEffects, Pixel};
+use crate::graphics::compositor::{BgLayer, Compositor, Layer, ObjLine};
+use crate::graphics::framebuffer::Framebuffer;
+use crate::graphics::mosaic::{block_start, Mosaic};
+use crate::graphics::sprite::{self, ObjSettings};
+use crate::graphics::window::Windows;
+use crate::graphics::timing::{DisplayTiming, Edge, REG_DISPSTAT, VDRAW_LINES};
 
 const PRAM_START: usize = 0x05000000;
 const PRAM_END: usize = 0x050003FF;
 const VRAM_START: usize = 0x06000000;
 const VRAM_END: usize = 0x06017FFF;
-const OAM_START: usize = 0x07000000;
-const OAM_END: usize = 0x070003FF;
 
 //Control Register Information
 const REG_DISPCNT_ADDR: usize = 0x04000000;
@@ -38,18 +41,12 @@ const OBJDisplay_BIT: u8 = 12;
 const Window0Display_BIT: u8 = 13;
 const Window1Display_BIT: u8 = 14;
 const OBJWindowDisplay_BIT: u8 = 15;
-//Display Status Register Information
-const REG_DISPSTAT_ADDR: usize = 0x04000004;
-const VBlank_BIT: u8 = 0;
-const HBlank_BIT: u8 = 1;
-const VCountTrigger_BIT: u8 = 2;
+//Display Status Register Information, the flags are in timing.rs
 const VBlankInterruptRequest_BIT: u8 = 3;
 const HBlankInterruptRequest_BIT: u8 = 4;
 const VCountInterruptRequest_BIT: u8 = 5;
 const VCountTriggerValue_START_BIT: u8 = 8;
 const VCountTriggerValue_END_BIT: u8 = 15;
-//VCounter Register Information
-const REG_VCOUNT_ADDR: usize = 0x04000006;
 //BG Control Register Information
 const BG_CNTRL_ADDR: [usize; 4] = [0x04000008, 0x0400000A, 0x0400000C, 0x0400000E];
 const BG_PRIORITY_START_BIT: u8 = 0;
@@ -58,6 +55,7 @@ const CHARACTER_BASE_START_BIT: u8 = 2;
 const CHARACTER_BASE_END_BIT: u8 = 3;
 const MOSAIC_BIT: u8 = 6;
 const PALETTES_BIT: u8 = 7;
+const WRAPAROUND_BIT: u8 = 13;
 const SCREEN_BASE_START_BIT: u8 = 8;
 const SCREEN_BASE_END_BIT: u8 = 12;
 const SCREEN_SIZE_START_BIT: u8 = 14;
@@ -65,44 +63,16 @@ const SCREEN_SIZE_END_BIT: u8 = 15;
 //I/O BG Offset Registers
 const BG_HORIZONTAL_OFFSET_ADDR: [usize; 4] = [0x4000010, 0x4000014, 0x4000018, 0x400001C];
 const BG_VERTICAL_OFFSET_ADDR: [usize; 4] = [0x4000012, 0x4000016, 0x400001A, 0x400001E];
-//Timing constants
-const CYCLE_TIME: usize = 16666666;
-const V_BLANK_TIME: usize = 11695906;
-const SCANLINE_TIME: usize = 73099;
-const H_BLANK_TIME: usize = 56960;
-const FRAME_CYCLES: usize = 280_896;
-const SCANLINE_CYCLES: usize = 1232;
-const H_BLANK_CYCLES: usize = 1004;
-const V_BLANK_CYCLES: usize = 197_120;
 //Tile Data Information
 const TILE_DATA_ADDR:usize = 0x06000000;
-//Sprite Tile Data/PRAM information
-const SPRITE_TILE_DATA_ADDR:usize = 0x06010000;
-const SPRITE_PRAM_ADDR:usize = 0x05000200;
+//Sprites are drawn in sprite.rs
 //Bitmap addresses
 const BITMAP_DATA_ADDR : usize = 0x06000000;
 const MODE3_DATA_ENDADDR : usize = 0x06013FFF;
 const MODE45_DATA_ENDADDR : usize = 0x06009FFF;
-//OBJ Tile Addresses 
-const OBJ_DATA_ADDR : usize = 0x06014000;
-const OBJ_DATA_ENDADDR : usize = 0x06017FFF;
-//BG Rotation/Scaling Register Addresses
-const BG_X_SCALE_ADDR: [usize; 2] = [0x4000020, 0x4000030];
-const BG_Y_SCALE_ADDR: [usize; 2] = [0x4000026, 0x4000036];
-const BG_X_SHEAR_ADDR: [usize; 2] = [0x4000022, 0x4000032];
-const BG_Y_SHEAR_ADDR: [usize; 2] = [0x4000024, 0x4000034];
-const FRACTIONAL_PART_START_BIT: usize = 0;
-const FRACTIONAL_PART_END_BIT: usize = 7;
-const INTEGER_PART_START_BIT: usize = 8;
-const INTEGER_PART_END_BIT: usize = 14;
-const SIGN_BIT: usize = 15;
-const BG_AFFINE_HORIZONTAL_OFFSET: [usize; 2] = [0x4000028, 0x4000038];
-const BG_AFFINE_VERTICAL_OFFSET: [usize; 2] = [0x400002C, 0x400003C];
-const FRACTIONAL_PART_OFFSET_START_BIT: usize = 0;
-const FRACTIONAL_PART_OFFSET_END_BIT: usize = 7;
-const INTEGER_PART_OFFSET_START_BIT: usize = 8;
-const INTEGER_PART_OFFSET_END_BIT: usize = 26;
-const SIGN_OFFSET_BIT: usize = 27;
+//Modes 4 and 5 draw the second frame from here when DISPCNT selects it
+const BITMAP_PAGE_SIZE : usize = 0xA000;
+//BG Rotation/Scaling registers are in affine.rs
 
 
 pub struct Register {
@@ -147,378 +117,214 @@ impl Register{
 }
 
 
-pub fn draw(mem: &mut Mem, cycle: usize, canvas: &mut Canvas<Window>) {
-    //initializing video registers
-    let mut control = Register {
-        value: mem.get_halfword(REG_DISPCNT_ADDR).little_endian(),
-        address: REG_DISPCNT_ADDR,
-    };
-    let mut status = Register {
-        value: mem.get_halfword(REG_DISPSTAT_ADDR).little_endian(),
-        address: REG_DISPSTAT_ADDR,
-    };
-    let mut vCounter = Register {
-        value: mem.get_halfword(REG_VCOUNT_ADDR).little_endian(),
-        address: REG_VCOUNT_ADDR,
-    };
-    //setting up timing
-    let oldvCounter = mem.get_byte(0x4000006);
-    let cycle_within_frame = cycle % FRAME_CYCLES;
-    let currentLine = ((cycle_within_frame as f64 / FRAME_CYCLES as f64) * 228f64 ) as u16;
-    vCounter.setValue(currentLine, mem);
-    //if currentLine != oldvCounter as u16 { println!("VCOUNTER IS {}", mem.get_byte(0x4000006)) };
-    if(vCounter.getValue(mem) == status.getBits(VCountTriggerValue_START_BIT as u16, 8, mem)){
-        if(status.getBit(VCountInterruptRequest_BIT as u16, mem) == 1){
-            mem.request_irq(Interrupt::VCounter);
-        }
-        status.setBit(1, VCountTrigger_BIT, mem);
-    }
-    if(cycle % SCANLINE_CYCLES > H_BLANK_CYCLES){
-        if(status.getBit(HBlankInterruptRequest_BIT as u16, mem) == 1 && status.getBit(HBlank_BIT as u16, mem) == 0){
-            mem.request_irq(Interrupt::HBlank);
+pub const SCREEN_WIDTH: usize = 240;
+pub const SCREEN_HEIGHT: usize = 160;
+// What the screen shows while DISPCNT forces a blank
+const BLANK_COLOR: u16 = 0x7FFF;
+
+pub struct Gpu {
+    timing: DisplayTiming,
+    references: ReferencePoints,
+    // Each line drawn as its HDraw period ends
+    frame: Framebuffer,
+}
+
+impl Gpu {
+    pub fn new() -> Self {
+        Gpu {
+            timing: DisplayTiming::new(),
+            references: ReferencePoints::new(),
+            frame: Framebuffer::new(),
         }
-        status.setBit(1, HBlank_BIT, mem);
     }
-    else {
-        status.setBit(0, HBlank_BIT, mem);
-    }
-    if(cycle_within_frame > V_BLANK_CYCLES){
-        if(status.getBit(VBlankInterruptRequest_BIT as u16, mem) == 1 && status.getBit(VBlank_BIT as u16, mem) == 0){
-            mem.request_irq(Interrupt::VBlank);
+
+    // True when a frame was finished, which is then in frame() until line 0 of the next one is drawn
+    pub fn step(&mut self, mem: &mut Mem, mut cycles: usize) -> bool {
+        let mut finished = false;
+        while let Some(edge) = self.timing.advance(&mut cycles) {
+            let mut status = Register {
+                value: mem.get_halfword(REG_DISPSTAT).little_endian(),
+                address: REG_DISPSTAT,
+            };
+            let matchLine = status.getBits(VCountTriggerValue_START_BIT as u16, 8, mem);
+            mem.set_display_status(self.timing.flags(matchLine), self.timing.line());
+            match edge {
+                Edge::HBlank(line) => {
+                    if(line < VDRAW_LINES){
+                        // The line is drawn from the registers as they are now, before HBlank DMA changes them
+                        let written = mem.take_affine_writes();
+                        self.references.reload(mem, written);
+                        self.renderLine(line as usize, mem);
+                        self.references.advance(mem);
+                        mem.trigger_dma(DmaTiming::HBlank);
+                    }
+                    if(status.getBit(HBlankInterruptRequest_BIT as u16, mem) == 1){
+                        mem.request_irq(Interrupt::HBlank);
+                    }
+                }
+                Edge::Line(line) => {
+                    if(line == VDRAW_LINES){
+                        finished = true;
+                        mem.take_affine_writes();
+                        self.references.latch(mem);
+                        mem.trigger_dma(DmaTiming::VBlank);
+                        if(status.getBit(VBlankInterruptRequest_BIT as u16, mem) == 1){
+                            mem.request_irq(Interrupt::VBlank);
+                        }
+                    }
+                    if(line == matchLine && status.getBit(VCountInterruptRequest_BIT as u16, mem) == 1){
+                        mem.request_irq(Interrupt::VCounter);
+                    }
+                }
+            }
         }
-        status.setBit(1, VBlank_BIT, mem);
+        finished
     }
-    else {
-        status.setBit(0, VBlank_BIT, mem);
-    }
-    if !((V_BLANK_CYCLES - 100)..=V_BLANK_CYCLES).contains(&cycle_within_frame) {
-        return;
+
+    pub fn frame(&self) -> &Framebuffer {
+        &self.frame
     }
-    
-    let mut screen = Pixbuf::new(Colorspace::Rgb, false, 8, 960, 640).unwrap();
-    screen.fill(0xFFFFFF00);
-    //let mut screen: [u8; 1843200] = [0; 1843200];
-    //let mut screen: [u8; 115200] = [0; 115200];
-
-
-    //display mode 1
-    if(true){
-        let mut prioritySprites: Vec<Vec<usize>> = Vec::new();
-        for i in 0..4{
-            prioritySprites.push(Vec::new());
-        }
-        for x in 0..128{
-            let attr0 = mem.get_halfword(OAM_START + 8 * x +  0 * 2).little_endian();
-            let attr2 = mem.get_halfword(OAM_START + 8 * x +  2 * 2).little_endian();
-            
-            if((attr0 >> 8) & 0b11 != 2){
-                let priority = (attr2 >> 10) & 0b11;
-                prioritySprites[priority as usize].push(x);
+
+    fn renderLine(&mut self, y: usize, mem: &Mem) {
+        let mut control = Register {
+            value: mem.get_halfword(REG_DISPCNT_ADDR).little_endian(),
+            address: REG_DISPCNT_ADDR,
+        };
+        if(control.getBit(ForceBlank_BIT as u16, mem) == 1){
+            *self.frame.line_mut(y) = [BLANK_COLOR; SCREEN_WIDTH];
+            return;
+        }
+        let windows = Windows::new(mem, y);
+        let effects = ColorEffects::new(mem);
+        let mosaic = Mosaic::new(mem);
+        let videoMode = control.getBits(VideoMode_START_BIT as u16, 3, mem);
+
+        let mut bgLayers: Vec<BgLayer> = Vec::new();
+        for bgNum in 0..4{
+            if(control.getBit((BG0Display_BIT + bgNum as u8) as u16, mem) == 0){
+                continue;
             }
-        }
-        let mut priorities:[usize; 4] = [5,5,5,5];
-        for x in 0..4{
             let mut bgControl = Register {
-                value: mem.get_halfword(BG_CNTRL_ADDR[x]).little_endian(),
-                address: BG_CNTRL_ADDR[x]
+                value: mem.get_halfword(BG_CNTRL_ADDR[bgNum]).little_endian(),
+                address: BG_CNTRL_ADDR[bgNum]
             };
-            let layer: u16 = bgControl.getBits(BG_PRIORITY_START_BIT as u16, 2, &mem);
-            if(control.getBit((BG0Display_BIT + x as u8) as u16, &mem) == 1){
-                priorities[layer as usize] = x;
+            let mut layer: Layer = [None; SCREEN_WIDTH];
+            match (videoMode, bgNum) {
+                (0, _) | (1, 0..=1) => addBGTileLayer(bgNum, y, &mut layer, mem, &mosaic),
+                (1, 2) | (2, 2..=3) => addBGAffineLayer(bgNum, y, &mut layer, mem, &self.references, &mosaic),
+                (3..=5, 2) => addBGBitmapLayer(videoMode, y, &mut layer, mem, &self.references, &mosaic),
+                // Not part of this mode
+                _ => continue,
             }
+            bgLayers.push(BgLayer {
+                bg: bgNum,
+                priority: bgControl.getBits(BG_PRIORITY_START_BIT as u16, 2, mem),
+                pixels: layer,
+            });
+        }
+
+        let mut objLine: ObjLine = [None; SCREEN_WIDTH];
+        let mut objWindow = [false; SCREEN_WIDTH];
+        if(control.getBit(OBJDisplay_BIT as u16, mem) == 1){
+            let settings = ObjSettings {
+                one_dimensional: control.getBit(ObjectMappingMode_BIT as u16, mem) == 1,
+                bitmap_mode: videoMode >= 3,
+                hblank_free: control.getBit(OAM_HBlank_BIT as u16, mem) == 1,
+                mosaic: mosaic.obj,
+            };
+            sprite::render_line(mem, y, &settings, &mut objLine, &mut objWindow);
         }
 
-        for i in 0..4{
-            if(priorities[4-i-1] != 5){
-                if(control.getBits(VideoMode_START_BIT as u16, 2, mem) == 0 || control.getBits(VideoMode_START_BIT as u16, 2, mem) == 1){
-                    addBGTileLayer(priorities[(4 - i - 1) as usize], &mut screen, mem);
-                }
-            }
-            for sprite in prioritySprites[(4 - i - 1)as usize].iter().rev() {
-                let x = *sprite;
-                let controlCopy = Register {
-                    value: mem.get_halfword(REG_DISPCNT_ADDR).little_endian(),
-                    address: REG_DISPCNT_ADDR,
-                };
-                if(x != 9){
-                    drawTiledSprite(x, &mut screen, mem, controlCopy);
-                }
-            }
-        }
+        Compositor::new(bgLayers).compose(self.frame.line_mut(y), &objLine, &objWindow, &windows, &effects, Pixel::backdrop(mem));
     }
-    
-    let tex_creator = canvas.texture_creator();
-    let l = unsafe { screen.get_pixels() };
-    //File::create("pixbuf.hex").unwrap().write_all(l).unwrap();
-    let surf = Surface::from_data(l, 960, 640, screen.get_rowstride() as u32, sdl2::pixels::PixelFormatEnum::RGB24).unwrap();
-    canvas.copy(&surf.as_texture(&tex_creator).unwrap(), None, None).unwrap();
-    canvas.present();
 }
 
 
-pub fn addBGTileLayer(bgNum: usize, screen: &mut Pixbuf, mem: &mut Mem) {
-    for y in 0..160 {
-        for x in 0..240 {
-            let currentPixelColor = getCurrentPixelColor(x, y, bgNum, false, mem);
-            let blueComp: u16 = (currentPixelColor >> 10) & 0b11111;
-            let greenComp: u16 = (currentPixelColor >> 5) & 0b11111; 
-            let redComp: u16 = currentPixelColor & 0b11111;
-            // screen[(720*y + 3*x) as usize] = (redComp as u8) << 3;
-            // screen[((720*y + 3*x) + 1) as usize] = (greenComp as u8) << 3;
-            // screen[((720*y + 3*x) + 2) as usize] = (blueComp as u8) << 3;
-            
-            for x1 in 0..4{
-                for y1 in 0..4{
-                    // screen[((960*(4*y+y1)  + (4*x+x1)) * 3) as usize] = (redComp as u8) << 3;
-                    // screen[((960*(4*y+y1)  + (4*x+x1)) * 3 + 1) as usize] = (greenComp as u8) << 3;
-                    // screen[((960*(4*y+y1)  + (4*x+x1)) * 3 + 2) as usize] = (blueComp as u8) << 3;
-                    if(redComp != 0 || greenComp != 0 || blueComp != 0){
-                        screen.put_pixel((4*x + x1) as u32, (4 * y + y1) as u32, (redComp as u8) << 3 , (greenComp as u8) << 3, (blueComp as u8) << 3, 1);
-                    }
-                }
-            } 
-                      
-        }
-    }
-    
+// The mosaic block size for a BG, 1x1 when its mosaic bit is off
+fn bgMosaic(bgNum: usize, mem: &Mem, mosaic: &Mosaic) -> (usize, usize) {
+    let mut bgControl = Register {
+        value: mem.get_halfword(BG_CNTRL_ADDR[bgNum]).little_endian(),
+        address: BG_CNTRL_ADDR[bgNum]
+    };
+    if bgControl.getBit(MOSAIC_BIT as u16, mem) == 1 { mosaic.bg } else { (1, 1) }
 }
 
-pub fn drawTiledSprite(spriteNum: usize, screen: &mut Pixbuf, mem: &mut Mem, mut control: Register) {
-    
-    let attr0 = mem.get_halfword(OAM_START + 8 * spriteNum + 0 * 2).little_endian();
-    let attr1 = mem.get_halfword(OAM_START + 8 * spriteNum + 1 * 2).little_endian();
-    let attr2 = mem.get_halfword(OAM_START + 8 * spriteNum + 2 * 2).little_endian();
-    let attr3 = mem.get_halfword(OAM_START + 8 * spriteNum + 3 * 2).little_endian();
-    let sizeMode = attr0 >> 14;
-    let doubleSize = attr0 >> 8 & 0b11 == 3;
-    let baseTile = attr2 & 0b11111_11111;
-    let colorMode = (attr0 >> 13) & 0b1;
-    let tileIndexingMode = if control.getBit(ObjectMappingMode_BIT as u16, mem) == 1 { 0 } else { 1 };
-    let mut xCoord = attr1 & 0b11111_1111;
-    let yCoord = attr0 & 0b1111_1111;
-    let horizontalFlip = (attr1 >> 12) & 0b1;
-    let verticalFlip = (attr2 >> 13) & 0b1;
-
-    let spriteShape = attr0 >> 14;
-    let spriteSize = attr1 >> 14;
-
-    let mut xDim = 0;
-    let mut yDim = 0;
-    
-    if(spriteShape ==  0){
-        if(spriteSize == 0){
-            xDim = 8;
-            yDim = 8;
-        }
-        else if(spriteSize == 1){
-            xDim = 16;
-            yDim = 16;
-        }
-        else if(spriteSize == 2){
-            xDim = 32;
-            yDim = 32;
-        }
-        else if(spriteSize == 3){
-            xDim = 64;
-            yDim = 64;
-        }
-    }
-    else if(spriteShape == 1){
-        if(spriteSize == 0){
-            xDim = 16;
-            yDim = 8;
-        }
-        else if(spriteSize == 1){
-            xDim = 32;
-            yDim = 8;
-        }
-        else if(spriteSize == 2){
-            xDim = 32;
-            yDim = 16;
-        }
-        else if(spriteSize == 3){
-            xDim = 64;
-            yDim = 32;
-        }
-    }
-    else if(spriteShape == 2){
-        if(spriteSize == 0){
-            xDim = 8;
-            yDim = 16;
-        }
-        else if(spriteSize == 1){
-            xDim = 8;
-            yDim = 32;
-        }
-        else if(spriteSize == 2){
-            xDim = 16;
-            yDim = 32;
-        }
-        else if(spriteSize == 3){
-            xDim = 32;
-            yDim = 64;
-        }
-    }
-    let mut lastX = xCoord + xDim;
-    let mut lastY = yCoord + yDim;
+// Affine BGs under a mosaic repeat the first line of each block, which started y % height lines ago
+fn mosaicOrigin(references: &ReferencePoints, params: &Params, affine: usize, y: usize, blockHeight: usize) -> (i32, i32) {
+    let (originX, originY) = references.origin(affine);
+    let linesBack = (y % blockHeight) as i32;
+    (originX.wrapping_sub(params.pb * linesBack), originY.wrapping_sub(params.pd * linesBack))
+}
 
-    if doubleSize {
-        lastX += xDim;
-        lastY += yDim;
+pub fn addBGTileLayer(bgNum: usize, y: usize, layer: &mut Layer, mem: &Mem, mosaic: &Mosaic) {
+    let (blockWidth, blockHeight) = bgMosaic(bgNum, mem, mosaic);
+    let mosaicY = block_start(y, blockHeight);
+    for x in 0..SCREEN_WIDTH {
+        layer[x] = getCurrentPixelColor(block_start(x, blockWidth), mosaicY, bgNum, false, mem);
     }
-    
-    // if(xCoord + xDim > 240){
-    //     lastX = 240;
-    // }
-    // if(yCoord + yDim > 160){
-    //     lastY = 160;
-    // }
-    // if xCoord > 240 || yCoord > 160 {
-    //     return;
-    // }
-    for mut x in xCoord..lastX{
-        for mut y in yCoord..lastY{
-
-            let mut spriteX = x - xCoord;
-            let mut spriteY = y - yCoord;
-
-            if((attr0 >> 8) & 0b11 == 1 || (attr0 >> 8) & 0b11 == 3){
-
-                let centeredX = spriteX as i16 - xDim as i16;
-                let centeredY = spriteY as i16 - yDim as i16;
-
-                let affineIndex = (attr1 >> 9) & 0b11111;
-                let pA = mem.get_halfword(OAM_START + (0x20 * affineIndex) as usize + 0x6).little_endian();
-                let pA = if pA >> 15 == 1 { -1. } else { 1. } * (pA as f32 / 256f32);
-                let pB = mem.get_halfword(OAM_START + (0x20 * affineIndex) as usize + 0xE).little_endian();
-                let pB = if pB >> 15 == 1 { -1. } else { 1. } * (pB as f32 / 256f32);
-                let pC = mem.get_halfword(OAM_START + (0x20 * affineIndex) as usize + 0x16).little_endian();
-                let pC = if pC >> 15 == 1 { -1. } else { 1. } * (pC as f32 / 256f32);
-                let pD = mem.get_halfword(OAM_START + (0x20 * affineIndex) as usize + 0x1E).little_endian();
-                let pD = if pD >> 15 == 1 { -1. } else { 1. } * (pD as f32 / 256f32);
-                
-                spriteX = ((xDim / 2) as i16 + (pA * centeredX as f32) as i16 + (pB * centeredY as f32) as i16) as u16;
-                spriteY = ((yDim / 2) as i16 + (pC * centeredX as f32) as i16 + (pD * centeredY as f32) as i16) as u16;
-            }
-
-            if (spriteX as i16) < 0 || (spriteY as i16) < 0 || spriteX > xDim || spriteY > yDim {
-                continue;
-            }
-            
-            let currentTile = (spriteY / 8) * (xDim / 8) + (spriteX/8);
-            let mut currentTileWithinMemory = 0;
-            if(tileIndexingMode == 1){
-                if(colorMode == 1){
-                    currentTileWithinMemory = baseTile + (spriteY / 8) * 32 + (spriteX / 8) * 2;
-                }
-                else{
-                    currentTileWithinMemory = baseTile + (spriteY/8) * 32 + (spriteX / 8);
-                }
-            }
-            else if(tileIndexingMode == 0){
-                if colorMode == 1 {
-                    currentTileWithinMemory = baseTile + currentTile * 2;
-                }
-                else{
-                    currentTileWithinMemory = baseTile + currentTile;
-                }
-    
-            }
-            
-            let xWithinTile = spriteX % 8;
-            let yWithinTile = spriteY % 8;
-            let mut currentPixelData = 0;
-            let mut currentPixelColor = 0;
-            let currentPixelNum = yWithinTile * 8 + xWithinTile;
-            //256-color palette
-            if(colorMode == 1){
-                currentPixelData = mem.get_byte(SPRITE_TILE_DATA_ADDR + (0x20 * currentTileWithinMemory as usize + currentPixelNum as usize) as usize); 
-                currentPixelColor = mem.get_halfword(SPRITE_PRAM_ADDR + (2 * currentPixelData as usize) as usize).little_endian();
-            }
-            //16 color palette
-            else if(colorMode == 0){
-                let paletteNum = attr2 >> 12;
-                if(currentPixelNum % 2 == 0){
-                    currentPixelData = mem.get_byte(SPRITE_TILE_DATA_ADDR + (0x20 * currentTileWithinMemory as usize + (currentPixelNum/2) as usize) as usize) & 0b1111;
-                } else{
-                    currentPixelData = mem.get_byte(SPRITE_TILE_DATA_ADDR + (0x20 * currentTileWithinMemory as usize + (currentPixelNum/2) as usize) as usize) >> 4;
-                }
-                currentPixelColor = mem.get_halfword(SPRITE_PRAM_ADDR + (paletteNum * 32 + (currentPixelData * 2) as u16) as usize).little_endian();
+}
 
-            }
-            let blueComp: u16 = currentPixelColor >> 10 & 0b11111;
-            let greenComp: u16 = currentPixelColor >> 5 & 0b11111; 
-            let redComp: u16 = (currentPixelColor) & 0b11111;
-            
-            let mut screenX = x;
-            let mut screenY = y;
-            if(horizontalFlip == 1){
-                screenX = lastX - (x - xCoord + 1);
-            }
-            if(verticalFlip == 1){
-                screenY = lastY - (y - yCoord + 1);
-            }
-            // screen[((240*(y)  + (x)) * 3) as usize] = (redComp as u8) << 3;
-            // screen[((240*(y)  + (x)) * 3 + 1) as usize] = (greenComp as u8) << 3;
-            // screen[((240*(y)  + (x)) * 3 + 2) as usize] = (blueComp as u8) << 3;
-            if(currentPixelData == 0){
-                continue;
-            }
-            if redComp != 0 || greenComp != 0 || blueComp != 0 {
-                for x1 in 0..4{
-                    for y1 in 0..4{
-                        // screen[((960*(4*y+y1)  + (4*x+x1)) * 3) as usize] = (redComp as u8) << 3;
-                        // screen[((960*(4*y+y1)  + (4*x+x1)) * 3 + 1) as usize] = (greenComp as u8) << 3;
-                        // screen[((960*(4*y+y1)  + (4*x+x1)) * 3 + 2) as usize] = (blueComp as u8) << 3;
-                        y %= 256;
-                        if(x < 240 && y < 160){
-                            screen.put_pixel((4*x + x1) as u32, (4 * y + y1) as u32, (redComp as u8) << 3 , (greenComp as u8) << 3, (blueComp as u8) << 3, 1);                            
-                        }                   
-                    }
-                }
-            }
-                         
-        }
+// BG2 and BG3 in modes 1 and 2: square maps 128 to 1024 pixels across, which either
+// wrap or leave transparent whatever falls outside them
+pub fn addBGAffineLayer(bgNum: usize, y: usize, layer: &mut Layer, mem: &Mem, references: &ReferencePoints, mosaic: &Mosaic) {
+    let mut bgControl = Register {
+        value: mem.get_halfword(BG_CNTRL_ADDR[bgNum]).little_endian(),
+        address: BG_CNTRL_ADDR[bgNum]
+    };
+    let mapSize: i32 = 128 << bgControl.getBits(SCREEN_SIZE_START_BIT as u16, 2, mem);
+    let wraparound = bgControl.getBit(WRAPAROUND_BIT as u16, mem) == 1;
+    let params = Params::read(mem, bgNum - 2);
+    let (blockWidth, blockHeight) = bgMosaic(bgNum, mem, mosaic);
+    let (originX, originY) = mosaicOrigin(references, &params, bgNum - 2, y, blockHeight);
+    for x in 0..SCREEN_WIDTH {
+        let sampleX = block_start(x, blockWidth) as i32;
+        let mut mapX = originX.wrapping_add(params.pa * sampleX) >> 8;
+        let mut mapY = originY.wrapping_add(params.pc * sampleX) >> 8;
+        if wraparound {
+            mapX &= mapSize - 1;
+            mapY &= mapSize - 1;
+        }
+        else if mapX < 0 || mapY < 0 || mapX >= mapSize || mapY >= mapSize {
+            continue;
+        }
+        layer[x] = getCurrentPixelColor(mapX as usize, mapY as usize, bgNum, true, mem);
     }
 }
 
-
-
-pub fn addBGBitmapLayer(screen: &mut Pixbuf, mem: &mut Mem) {
-    let mut bgControl = Register {
-        value: mem.get_halfword(BG_CNTRL_ADDR[2]).little_endian(),
-        address: BG_CNTRL_ADDR[2]
+// Modes 3 and 5 are direct color, mode 4 is 8 bit palette indices. All of them go through
+// BG2's rotation/scaling, and nothing wraps: outside the bitmap is transparent.
+pub fn addBGBitmapLayer(videoMode: u16, y: usize, layer: &mut Layer, mem: &Mem, references: &ReferencePoints, mosaic: &Mosaic) {
+    let mut control = Register {
+        value: mem.get_halfword(REG_DISPCNT_ADDR).little_endian(),
+        address: REG_DISPCNT_ADDR,
     };
-    let xOffset: usize = mem.get_halfword(BG_HORIZONTAL_OFFSET_ADDR[2]).little_endian() as usize;
-    let yOffset: usize = mem.get_halfword(BG_VERTICAL_OFFSET_ADDR[2]).little_endian() as usize;
-    for x in 0..240{
-        for y in 0..160{
-            let currentPixelColor = mem.get_halfword(TILE_DATA_ADDR + ((yOffset + y) * 160 + (xOffset + x)) * 2).little_endian(); 
-            let blueComp: u16 = (currentPixelColor >> 10) & 0b11111;
-            let greenComp: u16 = (currentPixelColor >> 5) & 0b11111; 
-            let redComp: u16 = currentPixelColor & 0b11111;
-            // screen[((240*(y)  + (x)) * 3) as usize] = (redComp as u8) << 3;
-            // screen[((240*(y)  + (x)) * 3 + 1) as usize] = (greenComp as u8) << 3;
-            // screen[((240*(y)  + (x)) * 3 + 2) as usize] = (blueComp as u8) << 3;
-            
-            for x1 in 0..4{
-                for y1 in 0..4{
-                    // screen[((960*(4*y+y1)  + (4*x+x1)) * 3) as usize] = (redComp as u8) << 3;
-                    // screen[((960*(4*y+y1)  + (4*x+x1)) * 3 + 1) as usize] = (greenComp as u8) << 3;
-                    // screen[((960*(4*y+y1)  + (4*x+x1)) * 3 + 2) as usize] = (blueComp as u8) << 3;
-                    if(redComp != 0 || greenComp != 0 || blueComp != 0){
-                        screen.put_pixel((4*x + x1) as u32, (4 * y + y1) as u32, (redComp as u8) << 3 , (greenComp as u8) << 3, (blueComp as u8) << 3, 1);
-                    }                }
+    let page = if videoMode != 3 && control.getBit(PageSelect_BIT as u16, mem) == 1 { BITMAP_PAGE_SIZE } else { 0 };
+    let (width, height) = if videoMode == 5 { (160, 128) } else { (240, 160) };
+    let params = Params::read(mem, 0);
+    let (blockWidth, blockHeight) = bgMosaic(2, mem, mosaic);
+    let (originX, originY) = mosaicOrigin(references, &params, 0, y, blockHeight);
+    for x in 0..SCREEN_WIDTH {
+        let sampleX = block_start(x, blockWidth) as i32;
+        let bitmapX = originX.wrapping_add(params.pa * sampleX) >> 8;
+        let bitmapY = originY.wrapping_add(params.pc * sampleX) >> 8;
+        if bitmapX < 0 || bitmapY < 0 || bitmapX >= width || bitmapY >= height {
+            continue;
+        }
+        let pixelNum = (bitmapY * width + bitmapX) as usize;
+        if videoMode == 4 {
+            let paletteIndex = mem.get_byte(BITMAP_DATA_ADDR + page + pixelNum) as usize;
+            if paletteIndex != 0 {
+                layer[x] = Some(mem.get_halfword(PRAM_START + paletteIndex * 2).little_endian());
             }
-               
-                         
-
+        }
+        else {
+            layer[x] = Some(mem.get_halfword(BITMAP_DATA_ADDR + page + pixelNum * 2).little_endian());
         }
     }
-
 }
 
-pub fn getCurrentPixelColor(x: usize, y: usize, bgNum: usize, affine: bool, mem: &mut Mem) -> (u16){
+// None where the tile pixel is palette index 0, which is always transparent
+pub fn getCurrentPixelColor(x: usize, y: usize, bgNum: usize, affine: bool, mem: &Mem) -> Option<u16>{
     let mut bgControl = Register {
         value: mem.get_halfword(BG_CNTRL_ADDR[bgNum]).little_endian(),
         address: BG_CNTRL_ADDR[bgNum]
@@ -528,14 +334,19 @@ pub fn getCurrentPixelColor(x: usize, y: usize, bgNum: usize, affine: bool, mem:
     let charBase: usize = bgControl.getBits(2, 2, mem) as usize;
     let screenBase: usize = bgControl.getBits(8, 5, mem) as usize;
     let sizeMode: usize = bgControl.getBits(14,2, mem) as usize;
-    let colorMode: usize = bgControl.getBit(PALETTES_BIT as u16, mem) as usize;
+    // Affine maps are always 256 colors
+    let colorMode: usize = if affine { 1 } else { bgControl.getBit(PALETTES_BIT as u16, mem) as usize };
     let startTile = (yOffset/8) * 32 + (xOffset/8);
 
     let mut currentTile: usize = 0;
-    let mut xWithinTile = 0;
-    let mut yWithinTile = 0;
+    let mut xWithinTile: u8;
+    let mut yWithinTile: u8;
     if(affine){
-
+        // x and y are already in map pixels. The map is a square of one byte tile numbers.
+        let mapTiles = 16 << sizeMode;
+        currentTile = (y / 8) * mapTiles + (x / 8);
+        xWithinTile = (x % 8) as u8;
+        yWithinTile = (y % 8) as u8;
     }
     else{
         if(sizeMode == 0){
@@ -559,14 +370,16 @@ pub fn getCurrentPixelColor(x: usize, y: usize, bgNum: usize, affine: bool, mem:
         yWithinTile= ((yOffset + y) % 8) as u8;
     }
     let tileMapAddr: usize = TILE_DATA_ADDR + screenBase * 2048;   
-    let currentTileAddr = tileMapAddr + 2 * currentTile;
-    let currentTileData = mem.get_halfword(currentTileAddr).little_endian();
-    let horizontalFlipping = (currentTileData >> 10) % 2;
-    let verticalFlipping = (currentTileData >> 11) % 2;
-    if(affine){
-        
+    let currentTileData = if(affine){
+        mem.get_byte(tileMapAddr + currentTile) as u16
     }
     else{
+        mem.get_halfword(tileMapAddr + 2 * currentTile).little_endian()
+    };
+    let horizontalFlipping = (currentTileData >> 10) % 2;
+    let verticalFlipping = (currentTileData >> 11) % 2;
+    // Affine tiles can't be flipped, their map entries are only a tile number
+    if(!affine){
         if(horizontalFlipping == 1){
             xWithinTile = 7 - xWithinTile;
         }
@@ -594,5 +407,73 @@ pub fn getCurrentPixelColor(x: usize, y: usize, bgNum: usize, affine: bool, mem:
         currentPixelColor= mem.get_halfword(PRAM_START + ((currentTileData >> 12) * 32) as usize + (currentPixelData * 2) as usize).little_endian();
     }
 
-    currentPixelColor
+    if(currentPixelData == 0){
+        return None;
+    }
+    Some(currentPixelColor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graphics::sink::{CaptureSink, VideoSink};
+    use std::io;
+
+    const OAM_START: usize = 0x7000000;
+    const OBJ_PALETTE: usize = PRAM_START + 0x200;
+    // Bitmap modes keep sprite tiles from 512 on
+    const OBJ_TILE_512: usize = VRAM_START + 0x14000;
+
+    fn set(mem: &mut Mem, address: usize, value: u16) {
+        mem.set_halfword(address, HalfWord::from_u16_le(value));
+    }
+
+    fn run_frame(gpu: &mut Gpu, mem: &mut Mem, sink: &mut CaptureSink) {
+        while !gpu.step(mem, 2) {}
+        sink.frame(gpu.frame()).unwrap();
+    }
+
+    #[test]
+    fn renders_a_bitmap_and_a_sprite_into_a_sink() {
+        let mut mem = Mem::new(OAM_START + 0x400);
+        mem.load(0, io::repeat(0)).unwrap();
+        // Mode 3 with BG2 and 1D sprites on, and BG2 unscaled
+        set(&mut mem, REG_DISPCNT_ADDR, 3 | 1 << BG2Display_BIT | 1 << OBJDisplay_BIT | 1 << ObjectMappingMode_BIT);
+        set(&mut mem, 0x4000020, 0x100);
+        set(&mut mem, 0x4000026, 0x100);
+        set(&mut mem, BITMAP_DATA_ADDR, 0x001F);
+        set(&mut mem, BITMAP_DATA_ADDR + 2 * (159 * 240 + 239), 0x03E0);
+        // One 8x8 sprite at (40, 32), every pixel palette entry 1, and the rest hidden
+        for index in 1..128 {
+            set(&mut mem, OAM_START + 8 * index, 1 << 9);
+        }
+        set(&mut mem, OAM_START, 32);
+        set(&mut mem, OAM_START + 2, 40);
+        set(&mut mem, OAM_START + 4, 512);
+        for offset in (0..32).step_by(2) {
+            set(&mut mem, OBJ_TILE_512 + offset, 0x1111);
+        }
+        set(&mut mem, OBJ_PALETTE + 2, 0x7C00);
+
+        let mut gpu = Gpu::new();
+        let mut sink = CaptureSink::new(2);
+        run_frame(&mut gpu, &mut mem, &mut sink);
+        let frame = sink.last().unwrap();
+        assert_eq!(frame.pixel(0, 0), 0x001F);
+        assert_eq!(frame.line(159)[239], 0x03E0);
+        assert_eq!(frame.pixel(40, 32), 0x7C00);
+        assert_eq!(frame.pixel(47, 39), 0x7C00);
+        assert_eq!(frame.pixel(48, 39), 0);
+        assert_eq!(frame.pixel(40, 40), 0);
+
+        // Only the last two frames are kept, both with the sprite moved
+        set(&mut mem, OAM_START + 2, 100);
+        run_frame(&mut gpu, &mut mem, &mut sink);
+        run_frame(&mut gpu, &mut mem, &mut sink);
+        assert_eq!(sink.frames().len(), 2);
+        for frame in sink.frames() {
+            assert_eq!(frame.pixel(40, 32), 0);
+            assert_eq!(frame.pixel(100, 32), 0x7C00);
+        }
+    }
 }
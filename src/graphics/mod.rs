@@ -1 +1,10 @@
-pub mod gpu;
\ No newline at end of file
+pub mod affine;
+pub mod blend;
+pub mod compositor;
+pub mod framebuffer;
+pub mod gpu;
+pub mod mosaic;
+pub mod sink;
+pub mod sprite;
+pub mod timing;
+pub mod window;
@@ -0,0 +1,26 @@
+use crate::arm::mem::Mem;
+
+const REG_MOSAIC: usize = 0x400004C;
+
+// Block sizes in pixels, for BGs and OBJs with their mosaic bit set
+#[derive(Copy, Clone, Debug)]
+pub struct Mosaic {
+    pub bg: (usize, usize),
+    pub obj: (usize, usize),
+}
+
+impl Mosaic {
+    pub fn new(mem: &Mem) -> Self {
+        let sizes = mem.get_halfword(REG_MOSAIC).little_endian() as usize;
+        Mosaic {
+            bg: ((sizes & 0xF) + 1, (sizes >> 4 & 0xF) + 1),
+            obj: ((sizes >> 8 & 0xF) + 1, (sizes >> 12 & 0xF) + 1),
+        }
+    }
+}
+
+// Blocks line up with the top left of the screen, and every pixel in one takes the
+// color of the block's first pixel
+pub fn block_start(pos: usize, size: usize) -> usize {
+    pos - pos % size
+}
@@ -0,0 +1,147 @@
+use anyhow::{anyhow, Context, Result};
+use image::RgbImage;
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::render::Canvas;
+use sdl2::surface::Surface;
+use sdl2::video::Window;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::graphics::framebuffer::Framebuffer;
+use crate::graphics::gpu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+// Where finished frames go
+pub trait VideoSink {
+    fn frame(&mut self, frame: &Framebuffer) -> Result<()>;
+}
+
+// Every sink in the list gets every frame
+impl VideoSink for Vec<Box<dyn VideoSink>> {
+    fn frame(&mut self, frame: &Framebuffer) -> Result<()> {
+        for sink in self.iter_mut() {
+            sink.frame(frame)?;
+        }
+        Ok(())
+    }
+}
+
+// Stretches frames over a window
+pub struct SdlSink {
+    canvas: Canvas<Window>,
+}
+
+impl SdlSink {
+    pub fn new(canvas: Canvas<Window>) -> Self {
+        SdlSink { canvas }
+    }
+}
+
+impl VideoSink for SdlSink {
+    fn frame(&mut self, frame: &Framebuffer) -> Result<()> {
+        let mut pixels = frame.to_rgb888();
+        let surface = Surface::from_data(
+            &mut pixels,
+            SCREEN_WIDTH as u32,
+            SCREEN_HEIGHT as u32,
+            SCREEN_WIDTH as u32 * 3,
+            PixelFormatEnum::RGB24,
+        )
+        .map_err(|e| anyhow!(e))?;
+        let texture_creator = self.canvas.texture_creator();
+        let texture = surface.as_texture(&texture_creator).map_err(|e| anyhow!(e))?;
+        self.canvas.copy(&texture, None, None).map_err(|e| anyhow!(e))?;
+        self.canvas.present();
+        Ok(())
+    }
+}
+
+// Saves every nth frame as dir/frame_<number>.png
+pub struct PngSink {
+    dir: PathBuf,
+    every: usize,
+    count: usize,
+}
+
+impl PngSink {
+    pub fn new(dir: &Path, every: usize) -> Result<Self> {
+        fs::create_dir_all(dir).with_context(|| format!("could not create {}", dir.display()))?;
+        Ok(PngSink {
+            dir: dir.to_path_buf(),
+            every: every.max(1),
+            count: 0,
+        })
+    }
+}
+
+impl VideoSink for PngSink {
+    fn frame(&mut self, frame: &Framebuffer) -> Result<()> {
+        let number = self.count;
+        self.count += 1;
+        if !number.is_multiple_of(self.every) {
+            return Ok(());
+        }
+        let path = self.dir.join(format!("frame_{:06}.png", number));
+        let image = RgbImage::from_raw(SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32, frame.to_rgb888())
+            .context("frame is the wrong size for an image")?;
+        image.save(&path).with_context(|| format!("could not write {}", path.display()))
+    }
+}
+
+// Raw RGBA8888 frames back to back, which ffmpeg can encode with
+//   ffmpeg -f rawvideo -pix_fmt rgba -s 240x160 -r 59.7275 -i <file> out.mp4
+pub struct RecordingSink<W: Write> {
+    out: W,
+}
+
+impl<W: Write> RecordingSink<W> {
+    pub fn new(out: W) -> Self {
+        RecordingSink { out }
+    }
+}
+
+impl RecordingSink<BufWriter<File>> {
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = File::create(path).with_context(|| format!("could not create {}", path.display()))?;
+        Ok(RecordingSink::new(BufWriter::new(file)))
+    }
+}
+
+impl<W: Write> VideoSink for RecordingSink<W> {
+    fn frame(&mut self, frame: &Framebuffer) -> Result<()> {
+        self.out.write_all(&frame.to_rgba8888()).context("could not write video frame")
+    }
+}
+
+// Keeps the last few frames in memory, for tests to look at
+pub struct CaptureSink {
+    frames: Vec<Framebuffer>,
+    limit: usize,
+}
+
+impl CaptureSink {
+    pub fn new(limit: usize) -> Self {
+        CaptureSink {
+            frames: Vec::new(),
+            limit: limit.max(1),
+        }
+    }
+
+    pub fn frames(&self) -> &[Framebuffer] {
+        &self.frames
+    }
+
+    pub fn last(&self) -> Option<&Framebuffer> {
+        self.frames.last()
+    }
+}
+
+impl VideoSink for CaptureSink {
+    fn frame(&mut self, frame: &Framebuffer) -> Result<()> {
+        if self.frames.len() == self.limit {
+            self.frames.remove(0);
+        }
+        self.frames.push(frame.clone());
+        Ok(())
+    }
+}
@@ -0,0 +1,302 @@
+use crate::arm::mem::Mem;
+use crate::graphics::compositor::{put_obj_pixel, ObjLine, ObjPixel};
+use crate::graphics::gpu::SCREEN_WIDTH;
+
+const OAM_START: usize = 0x7000000;
+const SPRITE_COUNT: usize = 128;
+const SPRITE_TILE_DATA_ADDR: usize = 0x6010000;
+const SPRITE_PRAM_ADDR: usize = 0x5000200;
+// Bitmap modes take the first half of OBJ VRAM for the frame buffers
+const BITMAP_MODE_FIRST_TILE: u16 = 512;
+const TILE_COUNT: u16 = 1024;
+
+// In 2D mapping OBJ VRAM is a 32x32 grid of 4bpp tiles
+const TILES_PER_ROW_2D: u16 = 32;
+
+// Cycles the OBJ engine gets per line, less if it has to leave H-Blank free for OAM access
+const LINE_CYCLES: usize = 1210;
+const LINE_CYCLES_HBLANK_FREE: usize = 954;
+const AFFINE_SETUP_CYCLES: usize = 10;
+
+// Width and height for each shape and size; the fourth shape is prohibited
+const SIZES: [[(usize, usize); 4]; 3] = [
+    [(8, 8), (16, 16), (32, 32), (64, 64)],
+    [(16, 8), (32, 8), (32, 16), (64, 32)],
+    [(8, 16), (8, 32), (16, 32), (32, 64)],
+];
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum ObjMode {
+    Normal,
+    SemiTransparent,
+    Window,
+}
+
+// The DISPCNT and MOSAIC settings sprites care about
+#[derive(Copy, Clone, Debug)]
+pub struct ObjSettings {
+    pub one_dimensional: bool,
+    pub bitmap_mode: bool,
+    pub hblank_free: bool,
+    pub mosaic: (usize, usize),
+}
+
+struct Sprite {
+    x: i32,
+    y: usize,
+    width: usize,
+    height: usize,
+    // PA, PB, PC, PD, for affine sprites
+    affine: Option<[i32; 4]>,
+    double_size: bool,
+    mode: ObjMode,
+    mosaic: bool,
+    eight_bit: bool,
+    h_flip: bool,
+    v_flip: bool,
+    tile: u16,
+    priority: u16,
+    palette: u16,
+}
+
+impl Sprite {
+    // None for hidden sprites and prohibited shapes and modes
+    fn read(mem: &Mem, index: usize) -> Option<Self> {
+        let attr = |n: usize| mem.get_halfword(OAM_START + 8 * index + 2 * n).little_endian();
+        let (attr0, attr1, attr2) = (attr(0), attr(1), attr(2));
+        let affine = attr0 >> 8 & 1 == 1;
+        // Without affine, bit 9 hides the sprite
+        if !affine && attr0 >> 9 & 1 == 1 {
+            return None;
+        }
+        let mode = match attr0 >> 10 & 0b11 {
+            0 => ObjMode::Normal,
+            1 => ObjMode::SemiTransparent,
+            2 => ObjMode::Window,
+            _ => return None,
+        };
+        let (width, height) = *SIZES.get((attr0 >> 14) as usize)?.get((attr1 >> 14) as usize)?;
+        let affine = if affine {
+            // Each group of parameters is spread over the fourth halfword of four OAM entries
+            let group = OAM_START + 0x20 * (attr1 >> 9 & 0b11111) as usize;
+            let param = |n: usize| mem.get_halfword(group + 8 * n + 6).little_endian() as i16 as i32;
+            Some([param(0), param(1), param(2), param(3)])
+        } else {
+            None
+        };
+        Some(Sprite {
+            x: ((attr1 << 7) as i16 >> 7) as i32,
+            y: (attr0 & 0xFF) as usize,
+            width,
+            height,
+            double_size: affine.is_some() && attr0 >> 9 & 1 == 1,
+            affine,
+            mode,
+            mosaic: attr0 >> 12 & 1 == 1,
+            eight_bit: attr0 >> 13 & 1 == 1,
+            h_flip: affine.is_none() && attr1 >> 12 & 1 == 1,
+            v_flip: affine.is_none() && attr1 >> 13 & 1 == 1,
+            tile: attr2 & 0x3FF,
+            priority: attr2 >> 10 & 0b11,
+            palette: attr2 >> 12,
+        })
+    }
+
+    // The box it takes up on screen, which double size makes twice as big
+    fn bounds(&self) -> (usize, usize) {
+        if self.double_size {
+            (2 * self.width, 2 * self.height)
+        } else {
+            (self.width, self.height)
+        }
+    }
+
+    fn cycles(&self) -> usize {
+        match self.affine {
+            Some(_) => AFFINE_SETUP_CYCLES + 2 * self.bounds().0,
+            None => self.width,
+        }
+    }
+
+    // Palette index at a pixel within the sprite
+    fn texel(&self, mem: &Mem, x: usize, y: usize, settings: &ObjSettings) -> u8 {
+        // 8bpp tiles are two 4bpp tiles long
+        let tile_units = if self.eight_bit { 2 } else { 1 };
+        let row_tiles = if settings.one_dimensional {
+            (self.width / 8) as u16 * tile_units
+        } else {
+            TILES_PER_ROW_2D
+        };
+        let tile = (self.tile + (y / 8) as u16 * row_tiles + (x / 8) as u16 * tile_units) % TILE_COUNT;
+        if settings.bitmap_mode && tile < BITMAP_MODE_FIRST_TILE {
+            return 0;
+        }
+        let tile_addr = SPRITE_TILE_DATA_ADDR + 32 * tile as usize;
+        let (x, y) = (x % 8, y % 8);
+        if self.eight_bit {
+            mem.get_byte(tile_addr + 8 * y + x)
+        } else {
+            mem.get_byte(tile_addr + 4 * y + x / 2) >> (4 * (x % 2)) & 0xF
+        }
+    }
+
+    fn color(&self, mem: &Mem, index: u8) -> u16 {
+        let entry = if self.eight_bit {
+            index as usize
+        } else {
+            16 * self.palette as usize + index as usize
+        };
+        mem.get_halfword(SPRITE_PRAM_ADDR + 2 * entry).little_endian()
+    }
+
+    fn draw(&self, mem: &Mem, line: usize, row: usize, settings: &ObjSettings, objs: &mut ObjLine, window: &mut [bool; SCREEN_WIDTH]) {
+        let (bounds_width, bounds_height) = self.bounds();
+        let (block_width, block_height) = if self.mosaic { settings.mosaic } else { (1, 1) };
+        // Mosaic blocks line up with the screen, but never reach back before the sprite
+        let row = row.saturating_sub(line % block_height) as i32;
+        for offset in 0..bounds_width as i32 {
+            let screen_x = self.x + offset;
+            if screen_x < 0 || screen_x >= SCREEN_WIDTH as i32 {
+                continue;
+            }
+            let column = (offset - screen_x.rem_euclid(block_width as i32)).max(0);
+            let (x, y) = match self.affine {
+                Some([pa, pb, pc, pd]) => {
+                    // Rotate about the middle of the box, in 8.8 fixed point
+                    let from_center_x = column - bounds_width as i32 / 2;
+                    let from_center_y = row - bounds_height as i32 / 2;
+                    let x = ((pa * from_center_x + pb * from_center_y) >> 8) + self.width as i32 / 2;
+                    let y = ((pc * from_center_x + pd * from_center_y) >> 8) + self.height as i32 / 2;
+                    if x < 0 || y < 0 || x >= self.width as i32 || y >= self.height as i32 {
+                        continue;
+                    }
+                    (x as usize, y as usize)
+                }
+                None => {
+                    let (x, y) = (column as usize, row as usize);
+                    (
+                        if self.h_flip { self.width - 1 - x } else { x },
+                        if self.v_flip { self.height - 1 - y } else { y },
+                    )
+                }
+            };
+            let index = self.texel(mem, x, y, settings);
+            if index == 0 {
+                continue;
+            }
+            let screen_x = screen_x as usize;
+            if self.mode == ObjMode::Window {
+                window[screen_x] = true;
+                continue;
+            }
+            put_obj_pixel(
+                objs,
+                screen_x,
+                ObjPixel {
+                    color: self.color(mem, index),
+                    priority: self.priority,
+                    semi_transparent: self.mode == ObjMode::SemiTransparent,
+                },
+            );
+        }
+    }
+}
+
+// Draws the sprites on one line in OAM order until the line's cycles run out
+pub fn render_line(mem: &Mem, line: usize, settings: &ObjSettings, objs: &mut ObjLine, window: &mut [bool; SCREEN_WIDTH]) {
+    let mut cycles = if settings.hblank_free { LINE_CYCLES_HBLANK_FREE } else { LINE_CYCLES };
+    for index in 0..SPRITE_COUNT {
+        let sprite = match Sprite::read(mem, index) {
+            Some(sprite) => sprite,
+            None => continue,
+        };
+        // Y is 8 bits, so sprites hanging off the bottom come back in at the top
+        let row = line.wrapping_sub(sprite.y) & 0xFF;
+        if row >= sprite.bounds().1 {
+            continue;
+        }
+        if sprite.cycles() > cycles {
+            break;
+        }
+        cycles -= sprite.cycles();
+        sprite.draw(mem, line, row, settings, objs, window);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arm::common::HalfWord;
+    use std::io;
+
+    const AFFINE: u16 = 1 << 8;
+    const DOUBLE_SIZE: u16 = 1 << 9;
+    const SIZE_64: u16 = 3 << 14;
+
+    fn set(mem: &mut Mem, address: usize, value: u16) {
+        mem.set_halfword(address, HalfWord::from_u16_le(value));
+    }
+
+    fn set_sprite(mem: &mut Mem, index: usize, attrs: [u16; 3]) {
+        for (n, &attr) in attrs.iter().enumerate() {
+            set(mem, OAM_START + 8 * index + 2 * n, attr);
+        }
+    }
+
+    // Tile 1 solid in palette entry 1, and every sprite hidden
+    fn oam() -> Mem {
+        let mut mem = Mem::new(OAM_START + 0x400);
+        mem.load(0, io::repeat(0)).unwrap();
+        for offset in (0..32).step_by(2) {
+            set(&mut mem, SPRITE_TILE_DATA_ADDR + 32 + offset, 0x1111);
+        }
+        set(&mut mem, SPRITE_PRAM_ADDR + 2, 0x001F);
+        for index in 0..SPRITE_COUNT {
+            set_sprite(&mut mem, index, [DOUBLE_SIZE, 0, 0]);
+        }
+        mem
+    }
+
+    // Puts big 64x64 sprites just off the left edge, which cost cycles but show nothing, then a
+    // row of 8x8 sprites from x = 0. Returns how many of the row were drawn.
+    fn drawn_after(big: usize, hblank_free: bool) -> usize {
+        let mut mem = oam();
+        for index in 0..big {
+            set_sprite(&mut mem, index, [0, SIZE_64 | (-64i16 as u16 & 0x1FF), 0]);
+        }
+        for column in 0..8 {
+            set_sprite(&mut mem, big + column, [0, 8 * column as u16, 1]);
+        }
+        let settings = ObjSettings {
+            one_dimensional: true,
+            bitmap_mode: false,
+            hblank_free,
+            mosaic: (1, 1),
+        };
+        let mut objs: ObjLine = [None; SCREEN_WIDTH];
+        let mut window = [false; SCREEN_WIDTH];
+        render_line(&mem, 0, &settings, &mut objs, &mut window);
+        objs.iter().filter(|pixel| pixel.is_some()).count() / 8
+    }
+
+    #[test]
+    fn sprites_stop_when_the_line_runs_out_of_cycles() {
+        // 18 * 64 = 1152 leaves 58 of 1210, and 14 * 64 = 896 leaves 58 of 954: room for 7 more
+        assert_eq!(drawn_after(18, false), 7);
+        assert_eq!(drawn_after(14, true), 7);
+        assert_eq!(drawn_after(18, true), 0);
+        assert_eq!(drawn_after(0, true), 8);
+    }
+
+    #[test]
+    fn affine_sprites_cost_ten_plus_two_per_pixel_of_width() {
+        let mut mem = oam();
+        set_sprite(&mut mem, 0, [0, 0, 0]);
+        set_sprite(&mut mem, 1, [AFFINE, 0, 0]);
+        set_sprite(&mut mem, 2, [AFFINE | DOUBLE_SIZE, 0, 0]);
+        set_sprite(&mut mem, 3, [AFFINE, SIZE_64, 0]);
+        set_sprite(&mut mem, 4, [0, SIZE_64, 0]);
+        let cycles: Vec<usize> = (0..5).map(|index| Sprite::read(&mem, index).unwrap().cycles()).collect();
+        assert_eq!(cycles, vec![8, 26, 42, 138, 64]);
+    }
+}
@@ -0,0 +1,134 @@
+pub const REG_DISPSTAT: usize = 0x4000004;
+pub const REG_VCOUNT: usize = 0x4000006;
+// The VBlank, HBlank and VCount match flags, which the game can't write
+pub const DISPSTAT_FLAGS: u16 = 0b111;
+
+pub const HDRAW_CYCLES: usize = 960;
+pub const HBLANK_CYCLES: usize = 272;
+pub const LINE_CYCLES: usize = HDRAW_CYCLES + HBLANK_CYCLES;
+pub const VDRAW_LINES: u16 = 160;
+pub const VBLANK_LINES: u16 = 68;
+pub const FRAME_LINES: u16 = VDRAW_LINES + VBLANK_LINES;
+
+const VBLANK_FLAG: u16 = 1 << 0;
+const HBLANK_FLAG: u16 = 1 << 1;
+const VCOUNT_FLAG: u16 = 1 << 2;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Edge {
+    // HDraw just ended on this line
+    HBlank(u16),
+    // This line just started
+    Line(u16),
+}
+
+#[derive(Default)]
+pub struct DisplayTiming {
+    line: u16,
+    // Cycles into the current line
+    dot: usize,
+}
+
+impl DisplayTiming {
+    pub fn new() -> Self {
+        DisplayTiming { line: 0, dot: 0 }
+    }
+
+    pub fn line(&self) -> u16 {
+        self.line
+    }
+
+    // Uses up cycles until the next edge and returns it, or None if they run out first
+    pub fn advance(&mut self, cycles: &mut usize) -> Option<Edge> {
+        let next = if self.dot < HDRAW_CYCLES { HDRAW_CYCLES } else { LINE_CYCLES };
+        if *cycles < next - self.dot {
+            self.dot += *cycles;
+            *cycles = 0;
+            return None;
+        }
+        *cycles -= next - self.dot;
+        if next == HDRAW_CYCLES {
+            self.dot = HDRAW_CYCLES;
+            return Some(Edge::HBlank(self.line));
+        }
+        self.dot = 0;
+        self.line = (self.line + 1) % FRAME_LINES;
+        Some(Edge::Line(self.line))
+    }
+
+    // DISPSTAT's read-only bits for the current position, given the line the game asked to match
+    pub fn flags(&self, match_line: u16) -> u16 {
+        let mut flags = 0;
+        // The last line of VBlank reads as drawing again
+        if self.line >= VDRAW_LINES && self.line < FRAME_LINES - 1 {
+            flags |= VBLANK_FLAG;
+        }
+        // Set in every line, VBlank included
+        if self.dot >= HDRAW_CYCLES {
+            flags |= HBLANK_FLAG;
+        }
+        if self.line == match_line {
+            flags |= VCOUNT_FLAG;
+        }
+        flags
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_frame_alternates_hblank_and_line_edges() {
+        let mut timing = DisplayTiming::new();
+        let mut cycles = LINE_CYCLES * FRAME_LINES as usize;
+        let mut edges = Vec::new();
+        while let Some(edge) = timing.advance(&mut cycles) {
+            edges.push((edge, cycles));
+        }
+        let mut expected = Vec::new();
+        let mut left = LINE_CYCLES * FRAME_LINES as usize;
+        for line in 0..FRAME_LINES {
+            left -= HDRAW_CYCLES;
+            expected.push((Edge::HBlank(line), left));
+            left -= HBLANK_CYCLES;
+            expected.push((Edge::Line((line + 1) % FRAME_LINES), left));
+        }
+        assert_eq!(edges, expected);
+        assert_eq!(timing.line(), 0);
+    }
+
+    #[test]
+    fn cycles_carry_over_between_calls() {
+        let mut timing = DisplayTiming::new();
+        let mut cycles = HDRAW_CYCLES - 1;
+        assert_eq!(timing.advance(&mut cycles), None);
+        assert_eq!(cycles, 0);
+        cycles = 2;
+        assert_eq!(timing.advance(&mut cycles), Some(Edge::HBlank(0)));
+        assert_eq!(cycles, 1);
+        assert_eq!(timing.advance(&mut cycles), None);
+        cycles = HBLANK_CYCLES - 1;
+        assert_eq!(timing.advance(&mut cycles), Some(Edge::Line(1)));
+        assert_eq!(cycles, 0);
+    }
+
+    #[test]
+    fn flags_follow_the_position() {
+        let mut timing = DisplayTiming::new();
+        assert_eq!(timing.flags(0), VCOUNT_FLAG);
+        let mut cycles = HDRAW_CYCLES;
+        timing.advance(&mut cycles);
+        assert_eq!(timing.flags(1), HBLANK_FLAG);
+
+        let mut cycles = LINE_CYCLES * VDRAW_LINES as usize - HDRAW_CYCLES;
+        while timing.advance(&mut cycles).is_some() {}
+        assert_eq!(timing.line(), VDRAW_LINES);
+        assert_eq!(timing.flags(0), VBLANK_FLAG);
+
+        let mut cycles = LINE_CYCLES * (VBLANK_LINES - 1) as usize;
+        while timing.advance(&mut cycles).is_some() {}
+        assert_eq!(timing.line(), FRAME_LINES - 1);
+        assert_eq!(timing.flags(0), 0);
+    }
+}
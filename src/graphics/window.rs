@@ -0,0 +1,152 @@
+use crate::arm::mem::Mem;
+
+const REG_DISPCNT: usize = 0x4000000;
+const REG_WINH: [usize; 2] = [0x4000040, 0x4000042];
+const REG_WINV: [usize; 2] = [0x4000044, 0x4000046];
+const REG_WININ: usize = 0x4000048;
+const REG_WINOUT: usize = 0x400004A;
+
+const WIN0_DISPLAY_BIT: u16 = 13;
+const OBJ_WINDOW_DISPLAY_BIT: u16 = 15;
+
+// Layer bits as WININ and WINOUT lay them out
+pub const OBJ_LAYER: usize = 4;
+pub const EFFECTS_BIT: usize = 5;
+const ALL_LAYERS: u8 = 0b11_1111;
+
+// Whether pos is in [start, end), reading end < start as wrapping round the edge of the screen
+fn in_range(pos: usize, start: usize, end: usize) -> bool {
+    if start <= end {
+        start <= pos && pos < end
+    } else {
+        pos >= start || pos < end
+    }
+}
+
+// The window settings for one line, read when it is drawn
+pub struct Windows {
+    enabled: bool,
+    // WIN0 and WIN1's horizontal extent, if they are on and cover the line
+    ranges: [Option<(usize, usize)>; 2],
+    obj_window: bool,
+    // Enables for WIN0, WIN1, the OBJ window and everything outside them
+    enables: [u8; 4],
+}
+
+impl Windows {
+    pub fn new(mem: &Mem, y: usize) -> Self {
+        let control = mem.get_halfword(REG_DISPCNT).little_endian();
+        let inside = mem.get_halfword(REG_WININ).little_endian();
+        let outside = mem.get_halfword(REG_WINOUT).little_endian();
+        let mut ranges = [None; 2];
+        for (window, range) in ranges.iter_mut().enumerate() {
+            if control >> (WIN0_DISPLAY_BIT + window as u16) & 1 == 0 {
+                continue;
+            }
+            let horizontal = mem.get_halfword(REG_WINH[window]).little_endian();
+            let vertical = mem.get_halfword(REG_WINV[window]).little_endian();
+            if in_range(y, (vertical >> 8) as usize, (vertical & 0xFF) as usize) {
+                *range = Some(((horizontal >> 8) as usize, (horizontal & 0xFF) as usize));
+            }
+        }
+        Windows {
+            enabled: control >> WIN0_DISPLAY_BIT & 0b111 != 0,
+            ranges,
+            obj_window: control >> OBJ_WINDOW_DISPLAY_BIT & 1 == 1,
+            enables: [inside as u8, (inside >> 8) as u8, (outside >> 8) as u8, outside as u8],
+        }
+    }
+
+    // Which layers, and whether color effects, show at x. WIN0 wins over WIN1, which wins
+    // over the OBJ window.
+    pub fn enables(&self, x: usize, in_obj_window: bool) -> u8 {
+        if !self.enabled {
+            return ALL_LAYERS;
+        }
+        for (window, range) in self.ranges.iter().enumerate() {
+            if let Some((start, end)) = *range {
+                if in_range(x, start, end) {
+                    return self.enables[window] & ALL_LAYERS;
+                }
+            }
+        }
+        if self.obj_window && in_obj_window {
+            return self.enables[2] & ALL_LAYERS;
+        }
+        self.enables[3] & ALL_LAYERS
+    }
+
+    pub fn shows(&self, x: usize, in_obj_window: bool, layer: usize) -> bool {
+        self.enables(x, in_obj_window) >> layer & 1 == 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arm::common::HalfWord;
+
+    const WIN0: u16 = 1 << WIN0_DISPLAY_BIT;
+    const WIN1: u16 = 1 << (WIN0_DISPLAY_BIT + 1);
+    const OBJ_WINDOW: u16 = 1 << OBJ_WINDOW_DISPLAY_BIT;
+
+    fn io() -> Mem {
+        Mem::new(0x4000400)
+    }
+
+    fn set(mem: &mut Mem, address: usize, value: u16) {
+        mem.set_halfword(address, HalfWord::from_u16_le(value));
+    }
+
+    // Each window gets its own layer bit so the tests can tell which one won
+    fn set_enables(mem: &mut Mem) {
+        set(mem, REG_WININ, 0b0010 << 8 | 0b0001);
+        set(mem, REG_WINOUT, 0b0100 << 8 | 0b1000);
+    }
+
+    #[test]
+    fn windows_wrap_round_the_screen_edges() {
+        let mut mem = io();
+        set(&mut mem, REG_DISPCNT, WIN0 | WIN1);
+        set_enables(&mut mem);
+        // WIN0 covers x 200-239 and 0-39, on lines 150-159 and 0-9
+        set(&mut mem, REG_WINH[0], 200 << 8 | 40);
+        set(&mut mem, REG_WINV[0], 150 << 8 | 10);
+        // WIN1 is the normal way round, x 60-99
+        set(&mut mem, REG_WINH[1], 60 << 8 | 100);
+        set(&mut mem, REG_WINV[1], 160);
+
+        let windows = Windows::new(&mem, 5);
+        for &(x, expected) in &[(0, 0b0001), (39, 0b0001), (40, 0b1000), (60, 0b0010), (100, 0b1000), (200, 0b0001)] {
+            assert_eq!(windows.enables(x, false), expected, "x = {}", x);
+        }
+        // Between WIN0's rows only WIN1 is left
+        let windows = Windows::new(&mem, 80);
+        assert_eq!(windows.enables(0, false), 0b1000);
+        assert_eq!(windows.enables(99, false), 0b0010);
+        assert_eq!(Windows::new(&mem, 155).enables(239, false), 0b0001);
+    }
+
+    #[test]
+    fn win0_beats_win1_which_beats_the_obj_window() {
+        let mut mem = io();
+        set(&mut mem, REG_DISPCNT, WIN0 | WIN1 | OBJ_WINDOW);
+        set_enables(&mut mem);
+        set(&mut mem, REG_WINH[0], 20);
+        set(&mut mem, REG_WINV[0], 160);
+        set(&mut mem, REG_WINH[1], 10 << 8 | 30);
+        set(&mut mem, REG_WINV[1], 160);
+
+        let windows = Windows::new(&mem, 0);
+        assert_eq!(windows.enables(15, true), 0b0001);
+        assert_eq!(windows.enables(25, true), 0b0010);
+        assert_eq!(windows.enables(35, true), 0b0100);
+        assert_eq!(windows.enables(35, false), 0b1000);
+
+        // Sprite pixels are only a window while the OBJ window is on
+        set(&mut mem, REG_DISPCNT, WIN0);
+        assert_eq!(Windows::new(&mem, 0).enables(35, true), 0b1000);
+        set(&mut mem, REG_DISPCNT, 0);
+        assert_eq!(Windows::new(&mem, 0).enables(35, true), ALL_LAYERS);
+    }
+}
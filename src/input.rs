@@ -0,0 +1,280 @@
+use anyhow::{anyhow, bail, Context, Result};
+use sdl2::controller::{Axis, Button, GameController};
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::Keycode;
+use sdl2::{EventPump, GameControllerSubsystem, Sdl};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::arm::mem::{Key, Mem};
+
+const DEFAULT_KEY_BINDINGS: [(Keycode, Key); 10] = [
+    (Keycode::Z, Key::A),
+    (Keycode::X, Key::B),
+    (Keycode::Backspace, Key::Select),
+    (Keycode::Return, Key::Start),
+    (Keycode::Right, Key::Right),
+    (Keycode::Left, Key::Left),
+    (Keycode::Up, Key::Up),
+    (Keycode::Down, Key::Down),
+    (Keycode::S, Key::R),
+    (Keycode::A, Key::L),
+];
+
+// GBA A/B sit where B/A are on an Xbox style pad, which matches the SNES layout
+const DEFAULT_BUTTON_BINDINGS: [(Button, Key); 10] = [
+    (Button::B, Key::A),
+    (Button::A, Key::B),
+    (Button::Back, Key::Select),
+    (Button::Start, Key::Start),
+    (Button::DPadRight, Key::Right),
+    (Button::DPadLeft, Key::Left),
+    (Button::DPadUp, Key::Up),
+    (Button::DPadDown, Key::Down),
+    (Button::RightShoulder, Key::R),
+    (Button::LeftShoulder, Key::L),
+];
+
+const KEYS: [Key; 10] = [
+    Key::A,
+    Key::B,
+    Key::Select,
+    Key::Start,
+    Key::Right,
+    Key::Left,
+    Key::Up,
+    Key::Down,
+    Key::R,
+    Key::L,
+];
+
+// F1-F12 turn the cheats in the game's cheat list on and off
+const CHEAT_KEYS: [Keycode; 12] = [
+    Keycode::F1,
+    Keycode::F2,
+    Keycode::F3,
+    Keycode::F4,
+    Keycode::F5,
+    Keycode::F6,
+    Keycode::F7,
+    Keycode::F8,
+    Keycode::F9,
+    Keycode::F10,
+    Keycode::F11,
+    Keycode::F12,
+];
+
+const DEFAULT_DEADZONE: i16 = 8000;
+const PAD_PREFIX: &str = "pad:";
+
+// A key can be held from more than one place at once
+const SOURCE_KEYBOARD: u8 = 1 << 0;
+const SOURCE_BUTTON: u8 = 1 << 1;
+const SOURCE_STICK: u8 = 1 << 2;
+
+pub fn parse_key(name: &str) -> Result<Key> {
+    match name.to_ascii_lowercase().as_str() {
+        "a" => Ok(Key::A),
+        "b" => Ok(Key::B),
+        "select" => Ok(Key::Select),
+        "start" => Ok(Key::Start),
+        "right" => Ok(Key::Right),
+        "left" => Ok(Key::Left),
+        "up" => Ok(Key::Up),
+        "down" => Ok(Key::Down),
+        "r" => Ok(Key::R),
+        "l" => Ok(Key::L),
+        _ => Err(anyhow!("unknown GBA key '{}'", name)),
+    }
+}
+
+fn key_index(key: Key) -> usize {
+    KEYS.iter().position(|&k| k == key).unwrap()
+}
+
+pub struct KeyBindings {
+    keys: HashMap<Keycode, Key>,
+    buttons: HashMap<Button, Key>,
+    deadzone: i16,
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        KeyBindings {
+            keys: DEFAULT_KEY_BINDINGS.iter().cloned().collect(),
+            buttons: DEFAULT_BUTTON_BINDINGS.iter().cloned().collect(),
+            deadzone: DEFAULT_DEADZONE,
+        }
+    }
+}
+
+impl KeyBindings {
+    // Reads "<gba key> = <binding>" lines, e.g. "start = Return" or "a = pad:b". Keyboard
+    // names are SDL's, pad buttons are SDL GameController names. "deadzone = <0-32767>"
+    // sets how far the stick has to move before it counts as the D-pad. Keys the file
+    // doesn't mention keep their defaults.
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path).with_context(|| format!("could not read {}", path.display()))?;
+        let mut bindings = KeyBindings::default();
+        let mut rebound_keys = [false; 10];
+        let mut rebound_buttons = [false; 10];
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let location = format!("{}:{}", path.display(), number + 1);
+            let (name, binding) = match line.find('=') {
+                Some(index) => (line[..index].trim(), line[index + 1..].trim()),
+                None => bail!("{}: expected <gba key> = <binding>", location),
+            };
+            if name == "deadzone" {
+                bindings.deadzone = binding
+                    .parse::<u16>()
+                    .ok()
+                    .filter(|&deadzone| deadzone <= i16::MAX as u16)
+                    .with_context(|| format!("{}: deadzone must be between 0 and 32767", location))?
+                    as i16;
+                continue;
+            }
+            let key = parse_key(name).context(location.clone())?;
+            if let Some(button) = binding.strip_prefix(PAD_PREFIX) {
+                let button = Button::from_string(button)
+                    .with_context(|| format!("{}: unknown controller button '{}'", location, button))?;
+                if !rebound_buttons[key_index(key)] {
+                    bindings.buttons.retain(|_, &mut bound| bound != key);
+                    rebound_buttons[key_index(key)] = true;
+                }
+                bindings.buttons.insert(button, key);
+            } else {
+                let keycode = Keycode::from_name(binding)
+                    .with_context(|| format!("{}: unknown keyboard key '{}'", location, binding))?;
+                if !rebound_keys[key_index(key)] {
+                    bindings.keys.retain(|_, &mut bound| bound != key);
+                    rebound_keys[key_index(key)] = true;
+                }
+                bindings.keys.insert(keycode, key);
+            }
+        }
+        Ok(bindings)
+    }
+}
+
+pub struct Input {
+    event_pump: EventPump,
+    controller_subsystem: GameControllerSubsystem,
+    controllers: HashMap<u32, GameController>,
+    bindings: KeyBindings,
+    held: [u8; 10],
+    cheat_toggles: Vec<usize>,
+}
+
+impl Input {
+    pub fn new(sdl_context: &Sdl, bindings: KeyBindings) -> Result<Self> {
+        // Controllers already plugged in show up as ControllerDeviceAdded events too
+        Ok(Input {
+            event_pump: sdl_context.event_pump().map_err(|e| anyhow!(e))?,
+            controller_subsystem: sdl_context.game_controller().map_err(|e| anyhow!(e))?,
+            controllers: HashMap::new(),
+            bindings,
+            held: [0; 10],
+            cheat_toggles: Vec::new(),
+        })
+    }
+
+    fn set_key(&mut self, mem: &mut Mem, key: Key, source: u8, down: bool) {
+        let held = &mut self.held[key_index(key)];
+        let was_down = *held != 0;
+        if down {
+            *held |= source;
+        } else {
+            *held &= !source;
+        }
+        if was_down != (*held != 0) {
+            mem.key_event(key, down);
+        }
+    }
+
+    fn release_source(&mut self, mem: &mut Mem, source: u8) {
+        for &key in KEYS.iter() {
+            self.set_key(mem, key, source, false);
+        }
+    }
+
+    fn stick_moved(&mut self, mem: &mut Mem, axis: Axis, value: i16) {
+        let (negative, positive) = match axis {
+            Axis::LeftX => (Key::Left, Key::Right),
+            Axis::LeftY => (Key::Up, Key::Down),
+            _ => return,
+        };
+        let deadzone = self.bindings.deadzone;
+        self.set_key(mem, negative, SOURCE_STICK, value < -deadzone);
+        self.set_key(mem, positive, SOURCE_STICK, value > deadzone);
+    }
+
+    // Cheats the user asked to toggle since the last call, by index
+    pub fn take_cheat_toggles(&mut self) -> Vec<usize> {
+        std::mem::take(&mut self.cheat_toggles)
+    }
+
+    // Returns false once the user has asked to quit
+    pub fn poll(&mut self, mem: &mut Mem) -> bool {
+        let events: Vec<Event> = self.event_pump.poll_iter().collect();
+        for event in events {
+            match event {
+                Event::Quit { .. }
+                | Event::Window {
+                    win_event: WindowEvent::Close,
+                    ..
+                } => return false,
+                Event::KeyDown {
+                    keycode: Some(keycode),
+                    repeat: false,
+                    ..
+                } => {
+                    if let Some(&key) = self.bindings.keys.get(&keycode) {
+                        self.set_key(mem, key, SOURCE_KEYBOARD, true);
+                    } else if let Some(index) = CHEAT_KEYS.iter().position(|&cheat_key| cheat_key == keycode) {
+                        self.cheat_toggles.push(index);
+                    }
+                }
+                Event::KeyUp {
+                    keycode: Some(keycode), ..
+                } => {
+                    if let Some(&key) = self.bindings.keys.get(&keycode) {
+                        self.set_key(mem, key, SOURCE_KEYBOARD, false);
+                    }
+                }
+                Event::ControllerDeviceAdded { which, .. } => match self.controller_subsystem.open(which) {
+                    Ok(controller) => {
+                        println!("Controller connected: {}", controller.name());
+                        self.controllers.insert(controller.instance_id(), controller);
+                    }
+                    Err(e) => println!("Could not open controller {}: {}", which, e),
+                },
+                Event::ControllerDeviceRemoved { which, .. } => {
+                    if let Some(controller) = self.controllers.remove(&which) {
+                        println!("Controller disconnected: {}", controller.name());
+                    }
+                    if self.controllers.is_empty() {
+                        self.release_source(mem, SOURCE_BUTTON | SOURCE_STICK);
+                    }
+                }
+                Event::ControllerButtonDown { button, .. } => {
+                    if let Some(&key) = self.bindings.buttons.get(&button) {
+                        self.set_key(mem, key, SOURCE_BUTTON, true);
+                    }
+                }
+                Event::ControllerButtonUp { button, .. } => {
+                    if let Some(&key) = self.bindings.buttons.get(&button) {
+                        self.set_key(mem, key, SOURCE_BUTTON, false);
+                    }
+                }
+                Event::ControllerAxisMotion { axis, value, .. } => self.stick_moved(mem, axis, value),
+                _ => {}
+            }
+        }
+        true
+    }
+}
@@ -0,0 +1,245 @@
+use anyhow::{Context, Result};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+pub const JOYBUS_START: usize = 0x4000140;
+pub const JOYBUS_END: usize = 0x400015B;
+
+// Offsets from JOYBUS_START
+const REG_JOYCNT: usize = 0x0;
+const REG_JOY_RECV_L: usize = 0x10;
+const REG_JOY_RECV_H: usize = 0x12;
+const REG_JOY_TRANS_L: usize = 0x14;
+const REG_JOY_TRANS_H: usize = 0x16;
+const REG_JOYSTAT: usize = 0x18;
+
+const CNT_RESET_BIT: u8 = 0;
+const CNT_RECEIVE_BIT: u8 = 1;
+const CNT_SEND_BIT: u8 = 2;
+const CNT_IRQ_BIT: u8 = 6;
+const CNT_FLAGS: u16 = 0b111; // write 1 to clear
+
+const STAT_RECEIVE_BIT: u8 = 1;
+const STAT_SEND_BIT: u8 = 3;
+const STAT_GENERAL: u16 = 0b11_0000; // the only bits the GBA can write
+
+const CMD_STATUS: u8 = 0x00;
+const CMD_READ: u8 = 0x14; // console reads JOY_TRANS
+const CMD_WRITE: u8 = 0x15; // console writes JOY_RECV
+const CMD_RESET: u8 = 0xFF;
+
+// What a GBA answers to reset and status commands, ahead of JOYSTAT
+const DEVICE_TYPE: [u8; 2] = [0x00, 0x04];
+
+const POLL_CYCLES: usize = 1024;
+
+// The console side connects over TCP. Each frame, either way, is a length byte followed
+// by that many bytes: a command and its data going in, the GBA's answer coming out.
+pub struct ConsolePort {
+    listener: TcpListener,
+    console: Option<TcpStream>,
+    buffer: Vec<u8>,
+}
+
+impl ConsolePort {
+    pub fn listen(address: &str) -> Result<Self> {
+        let listener = TcpListener::bind(address).with_context(|| format!("could not listen on {}", address))?;
+        listener.set_nonblocking(true)?;
+        Ok(ConsolePort {
+            listener,
+            console: None,
+            buffer: Vec::new(),
+        })
+    }
+
+    fn next_command(&mut self) -> Option<Vec<u8>> {
+        if self.console.is_none() {
+            let (stream, address) = self.listener.accept().ok()?;
+            stream.set_nonblocking(true).ok()?;
+            stream.set_nodelay(true).ok()?;
+            println!("JOY Bus: console connected from {}", address);
+            self.console = Some(stream);
+            self.buffer.clear();
+        }
+        let console = self.console.as_mut()?;
+        let mut chunk = [0; 64];
+        loop {
+            match console.read(&mut chunk) {
+                Ok(0) => {
+                    println!("JOY Bus: console disconnected");
+                    self.console = None;
+                    return None;
+                }
+                Ok(count) => self.buffer.extend_from_slice(&chunk[..count]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    println!("JOY Bus: console disconnected: {}", e);
+                    self.console = None;
+                    return None;
+                }
+            }
+        }
+        let len = 1 + *self.buffer.first()? as usize;
+        if self.buffer.len() < len {
+            return None;
+        }
+        let frame = self.buffer[1..len].to_vec();
+        self.buffer.drain(..len);
+        Some(frame)
+    }
+
+    fn reply(&mut self, response: &[u8]) {
+        let mut frame = vec![response.len() as u8];
+        frame.extend_from_slice(response);
+        if let Some(console) = &mut self.console {
+            // Replies are a handful of bytes, so just wait out a full socket buffer
+            let _ = console.set_nonblocking(false);
+            let result = console.write_all(&frame);
+            let _ = console.set_nonblocking(true);
+            if let Err(e) = result {
+                println!("JOY Bus: console disconnected: {}", e);
+                self.console = None;
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct JoyBus {
+    control: u16,
+    receive: u32,
+    transmit: u32,
+    status: u16,
+    port: Option<ConsolePort>,
+    poll_cycles: usize,
+}
+
+impl JoyBus {
+    pub fn new() -> Self {
+        JoyBus {
+            control: 0,
+            receive: 0,
+            transmit: 0,
+            status: 0,
+            port: None,
+            poll_cycles: 0,
+        }
+    }
+
+    pub fn attach(&mut self, port: ConsolePort) {
+        self.port = Some(port);
+    }
+
+    pub fn read(&self, offset: usize) -> u16 {
+        match offset {
+            REG_JOYCNT => self.control,
+            REG_JOY_RECV_L => self.receive as u16,
+            REG_JOY_RECV_H => (self.receive >> 16) as u16,
+            REG_JOY_TRANS_L => self.transmit as u16,
+            REG_JOY_TRANS_H => (self.transmit >> 16) as u16,
+            REG_JOYSTAT => self.status,
+            _ => 0,
+        }
+    }
+
+    // A CPU load of JOY_RECV clears the receive flag
+    pub fn loaded(&mut self, offset: usize) {
+        if offset == REG_JOY_RECV_H {
+            self.status &= !(1 << STAT_RECEIVE_BIT);
+        }
+    }
+
+    pub fn write(&mut self, offset: usize, data: u16, mask: u16) {
+        match offset {
+            REG_JOYCNT => {
+                self.control &= !(data & mask & CNT_FLAGS);
+                if mask & 0xFF != 0 {
+                    self.control = self.control & !(1 << CNT_IRQ_BIT) | data & 1 << CNT_IRQ_BIT;
+                }
+            }
+            REG_JOY_RECV_L => self.receive = self.receive & 0xFFFF_0000 | data as u32,
+            REG_JOY_RECV_H => self.receive = self.receive & 0xFFFF | (data as u32) << 16,
+            REG_JOY_TRANS_L => self.transmit = self.transmit & 0xFFFF_0000 | data as u32,
+            REG_JOY_TRANS_H => {
+                self.transmit = self.transmit & 0xFFFF | (data as u32) << 16;
+                self.status |= 1 << STAT_SEND_BIT;
+            }
+            REG_JOYSTAT => self.status = self.status & !STAT_GENERAL | data & STAT_GENERAL,
+            _ => {}
+        }
+    }
+
+    // Returns the answer to send back and whether the command raises an IRQ
+    fn command(&mut self, frame: &[u8]) -> (Vec<u8>, bool) {
+        let mut response = Vec::new();
+        let flag = match frame.first() {
+            Some(&CMD_RESET) => {
+                response.extend_from_slice(&DEVICE_TYPE);
+                Some(CNT_RESET_BIT)
+            }
+            Some(&CMD_STATUS) => {
+                response.extend_from_slice(&DEVICE_TYPE);
+                None
+            }
+            Some(&CMD_READ) => {
+                response.extend_from_slice(&self.transmit.to_le_bytes());
+                self.status &= !(1 << STAT_SEND_BIT);
+                Some(CNT_SEND_BIT)
+            }
+            Some(&CMD_WRITE) if frame.len() >= 5 => {
+                self.receive = u32::from_le_bytes([frame[1], frame[2], frame[3], frame[4]]);
+                self.status |= 1 << STAT_RECEIVE_BIT;
+                Some(CNT_RECEIVE_BIT)
+            }
+            // A real GBA stays silent on anything else, which goes out as an empty frame
+            _ => return (response, false),
+        };
+        response.push(self.status as u8);
+        match flag {
+            Some(bit) => {
+                self.control |= 1 << bit;
+                (response, self.control >> CNT_IRQ_BIT & 1 == 1)
+            }
+            None => (response, false),
+        }
+    }
+
+    // Returns true when a console command raised an IRQ
+    pub fn step(&mut self, cycles: usize) -> bool {
+        self.poll_cycles += cycles;
+        if self.poll_cycles < POLL_CYCLES {
+            return false;
+        }
+        self.poll_cycles = 0;
+        let mut irq = false;
+        while let Some(frame) = self.port.as_mut().and_then(|port| port.next_command()) {
+            let (response, raised) = self.command(&frame);
+            irq |= raised;
+            if let Some(port) = &mut self.port {
+                port.reply(&response);
+            }
+        }
+        irq
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_loads_of_joy_recv_clear_the_receive_flag() {
+        let mut joybus = JoyBus::new();
+        let (response, irq) = joybus.command(&[CMD_WRITE, 0x78, 0x56, 0x34, 0x12]);
+        assert_eq!(response, vec![1 << STAT_RECEIVE_BIT]);
+        assert!(!irq);
+        assert_eq!(joybus.read(REG_JOY_RECV_L), 0x5678);
+        assert_eq!(joybus.read(REG_JOY_RECV_H), 0x1234);
+        assert_eq!(joybus.read(REG_JOYSTAT), 1 << STAT_RECEIVE_BIT);
+        joybus.loaded(REG_JOY_RECV_L);
+        assert_eq!(joybus.read(REG_JOYSTAT), 1 << STAT_RECEIVE_BIT);
+        joybus.loaded(REG_JOY_RECV_H);
+        assert_eq!(joybus.read(REG_JOYSTAT), 0);
+    }
+}
@@ -0,0 +1,4 @@
+pub mod joybus;
+pub mod sio;
+pub mod transport;
+pub mod uart;
@@ -0,0 +1,611 @@
+use anyhow::Result;
+
+use crate::link::joybus::{ConsolePort, JoyBus};
+use crate::link::transport::{self, ChannelTransport, Listener, Message, Transport};
+use crate::link::uart::{HostPort, Uart};
+
+pub const SIO_START: usize = 0x4000120;
+pub const SIO_END: usize = 0x400012B;
+pub const REG_RCNT: usize = 0x4000134;
+
+// Offsets from SIO_START
+const REG_SIOMULTI_END: usize = 0x7; // SIOMULTI0-3, or SIODATA32 in its first two halfwords
+const REG_SIOCNT: usize = 0x8;
+const REG_SIOMLT_SEND: usize = 0xA; // SIODATA8 in normal mode
+
+const CNT_INTERNAL_CLOCK_BIT: u8 = 0;
+const CNT_FAST_CLOCK_BIT: u8 = 1;
+const CNT_SI_BIT: u8 = 2;
+const CNT_SO_BIT: u8 = 3; // SD, everyone ready, in multiplayer mode
+const CNT_ID_SHIFT: u8 = 4;
+const CNT_START_BIT: u8 = 7;
+const CNT_MODE_SHIFT: u8 = 12;
+const CNT_IRQ_BIT: u8 = 14;
+const CNT_MULTIPLAYER_STATUS: u16 = 0b0111_1100; // SI, SD, ID and error
+
+const RCNT_JOYBUS_BIT: u8 = 14;
+const RCNT_GENERAL_BIT: u8 = 15;
+
+// Cycles per bit at 256KHz and 2MHz
+const NORMAL_BIT_CYCLES: [usize; 2] = [64, 8];
+// Cycles per bit at 9600, 38400, 57600 and 115200 baud, for multiplayer and UART modes
+pub const BAUD_BIT_CYCLES: [usize; 4] = [1748, 437, 291, 146];
+// Start bit, 16 data bits and a stop bit from each unit
+const MULTIPLAYER_FRAME_BITS: usize = 18;
+
+const MAX_UNITS: usize = 4;
+// Sockets are only checked this often unless a transfer is waiting on them
+const POLL_CYCLES: usize = 1024;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Mode {
+    Normal8,
+    Normal32,
+    Multiplayer,
+    Uart,
+    GeneralPurpose,
+    JoyBus,
+}
+
+// The parent is unit 0 and accepts everyone else; units talk only to the parent
+pub enum Link {
+    Parent {
+        listener: Option<Listener>,
+        children: [Option<Box<dyn Transport>>; MAX_UNITS - 1],
+    },
+    Child {
+        parent: Box<dyn Transport>,
+        id: u8,
+    },
+}
+
+impl Link {
+    pub fn listen(address: &str) -> Result<Self> {
+        Ok(Link::Parent {
+            listener: Some(Listener::bind(address)?),
+            children: [None, None, None],
+        })
+    }
+
+    pub fn connect(address: &str) -> Result<Self> {
+        Ok(Link::Child {
+            parent: transport::connect(address)?,
+            // Filled in by the parent's welcome
+            id: 0,
+        })
+    }
+
+    // Already connected links for several instances in one process, parent first
+    pub fn local(units: usize) -> Vec<Link> {
+        let mut children: [Option<Box<dyn Transport>>; MAX_UNITS - 1] = [None, None, None];
+        let mut links = Vec::new();
+        for id in 1..units.min(MAX_UNITS) {
+            let (parent_end, child_end) = ChannelTransport::pair();
+            children[id - 1] = Some(Box::new(parent_end));
+            links.push(Link::Child {
+                parent: Box::new(child_end),
+                id: id as u8,
+            });
+        }
+        links.insert(0, Link::Parent { listener: None, children });
+        links
+    }
+}
+
+struct Transfer {
+    cycles: usize,
+    // Units we still need a reply from
+    waiting: [bool; MAX_UNITS],
+    // Incoming data in normal mode, every unit's data in multiplayer mode
+    received: [u32; MAX_UNITS],
+}
+
+pub struct Sio {
+    multi: [u16; 4],
+    control: u16,
+    send: u16,
+    rcnt: u16,
+    si: bool,
+    link: Option<Link>,
+    transfer: Option<Transfer>,
+    poll_cycles: usize,
+    uart: Uart,
+    joybus: JoyBus,
+}
+
+impl Default for Sio {
+    fn default() -> Self {
+        Sio::new()
+    }
+}
+
+impl Sio {
+    pub fn new() -> Self {
+        Sio {
+            multi: [0; 4],
+            control: 0,
+            send: 0,
+            rcnt: 0,
+            // SI is pulled high with nothing plugged in
+            si: true,
+            link: None,
+            transfer: None,
+            poll_cycles: 0,
+            uart: Uart::new(),
+            joybus: JoyBus::new(),
+        }
+    }
+
+    pub fn attach(&mut self, link: Link) {
+        self.link = Some(link);
+    }
+
+    pub fn attach_uart(&mut self, port: HostPort) {
+        self.uart.attach(port);
+    }
+
+    pub fn attach_joybus(&mut self, port: ConsolePort) {
+        self.joybus.attach(port);
+    }
+
+    fn bit(&self, bit: u8) -> bool {
+        self.control >> bit & 1 == 1
+    }
+
+    fn mode(&self) -> Mode {
+        if self.rcnt >> RCNT_GENERAL_BIT & 1 == 1 {
+            if self.rcnt >> RCNT_JOYBUS_BIT & 1 == 1 {
+                Mode::JoyBus
+            } else {
+                Mode::GeneralPurpose
+            }
+        } else {
+            match self.control >> CNT_MODE_SHIFT & 0b11 {
+                0 => Mode::Normal8,
+                1 => Mode::Normal32,
+                2 => Mode::Multiplayer,
+                _ => Mode::Uart,
+            }
+        }
+    }
+
+    fn is_parent(&self) -> bool {
+        !matches!(self.link, Some(Link::Child { .. }))
+    }
+
+    fn id(&self) -> u8 {
+        match self.link {
+            Some(Link::Child { id, .. }) => id,
+            _ => 0,
+        }
+    }
+
+    // Which other units we can reach
+    fn peers(&self) -> [bool; MAX_UNITS] {
+        let mut peers = [false; MAX_UNITS];
+        match &self.link {
+            Some(Link::Parent { children, .. }) => {
+                for (i, child) in children.iter().enumerate() {
+                    peers[i + 1] = child.is_some();
+                }
+            }
+            Some(Link::Child { .. }) => peers[0] = true,
+            None => {}
+        }
+        peers
+    }
+
+    pub fn read(&self, offset: usize) -> u16 {
+        match offset {
+            0..=REG_SIOMULTI_END => self.multi[offset / 2],
+            REG_SIOCNT => self.read_control(),
+            REG_SIOMLT_SEND if self.mode() == Mode::Uart => self.uart.read_data(),
+            REG_SIOMLT_SEND => self.send,
+            _ => 0,
+        }
+    }
+
+    // mask has the bits of data that were actually written
+    pub fn write(&mut self, offset: usize, data: u16, mask: u16) {
+        match offset {
+            0..=REG_SIOMULTI_END => self.multi[offset / 2] = data,
+            REG_SIOCNT => self.write_control(data),
+            REG_SIOMLT_SEND if self.mode() == Mode::Uart => self.uart.write_data(data, mask, self.control),
+            REG_SIOMLT_SEND => self.send = data,
+            _ => {}
+        }
+    }
+
+    // Reads with side effects, which only CPU loads trigger
+    pub fn loaded(&mut self, offset: usize) {
+        if offset == REG_SIOMLT_SEND && self.mode() == Mode::Uart {
+            self.uart.data_loaded();
+        }
+    }
+
+    // The JOY Bus registers only answer the console while RCNT selects JOY Bus mode, otherwise
+    // reads see whatever was last written
+    pub fn read_joybus(&self, offset: usize) -> Option<u16> {
+        if self.mode() == Mode::JoyBus {
+            Some(self.joybus.read(offset))
+        } else {
+            None
+        }
+    }
+
+    pub fn joybus_loaded(&mut self, offset: usize) {
+        if self.mode() == Mode::JoyBus {
+            self.joybus.loaded(offset);
+        }
+    }
+
+    pub fn write_joybus(&mut self, offset: usize, data: u16, mask: u16) {
+        self.joybus.write(offset, data, mask);
+    }
+
+    pub fn rcnt(&self) -> u16 {
+        self.rcnt
+    }
+
+    pub fn set_rcnt(&mut self, data: u16) {
+        self.rcnt = data;
+    }
+
+    fn read_control(&self) -> u16 {
+        let control = self.control;
+        match self.mode() {
+            Mode::Normal8 | Mode::Normal32 => control & !(1 << CNT_SI_BIT) | (self.si as u16) << CNT_SI_BIT,
+            Mode::Multiplayer => {
+                let ready = self.peers().iter().any(|&peer| peer);
+                control & !CNT_MULTIPLAYER_STATUS
+                    | (!self.is_parent() as u16) << CNT_SI_BIT
+                    | (ready as u16) << CNT_SO_BIT
+                    | (self.id() as u16) << CNT_ID_SHIFT
+            }
+            Mode::Uart => self.uart.status(control),
+            _ => control,
+        }
+    }
+
+    fn write_control(&mut self, data: u16) {
+        let old = self.control;
+        let busy = self.transfer.is_some();
+        self.control = data;
+        // The start bit doubles as the busy flag, so only we get to clear it mid transfer.
+        // In UART mode it is the data length instead.
+        if self.mode() != Mode::Uart {
+            self.control = data & !(1 << CNT_START_BIT) | old & 1 << CNT_START_BIT;
+        }
+        let start = data >> CNT_START_BIT & 1 == 1;
+        match self.mode() {
+            Mode::Normal8 | Mode::Normal32 => {
+                if (old ^ data) >> CNT_SO_BIT & 1 == 1 {
+                    self.broadcast(&Message::Signal(data >> CNT_SO_BIT & 1 == 1));
+                }
+                if !start {
+                    self.control &= !(1 << CNT_START_BIT);
+                    self.transfer = None;
+                } else if !busy {
+                    self.control |= 1 << CNT_START_BIT;
+                    // With the external clock we wait for the other side to start
+                    if self.bit(CNT_INTERNAL_CLOCK_BIT) {
+                        self.start_normal();
+                    }
+                }
+            }
+            // Only the parent can start a multiplayer transfer
+            Mode::Multiplayer if start && !busy && self.is_parent() => {
+                self.control |= 1 << CNT_START_BIT;
+                self.start_multiplayer();
+            }
+            Mode::Uart => self.uart.control_written(old, data),
+            _ => {}
+        }
+    }
+
+    fn normal_data(&self) -> u32 {
+        if self.mode() == Mode::Normal32 {
+            self.multi[0] as u32 | (self.multi[1] as u32) << 16
+        } else {
+            self.send as u32 & 0xFF
+        }
+    }
+
+    fn start_normal(&mut self) {
+        let bits = if self.mode() == Mode::Normal32 { 32 } else { 8 };
+        let cycles = bits * NORMAL_BIT_CYCLES[self.bit(CNT_FAST_CLOCK_BIT) as usize];
+        self.transfer = Some(Transfer {
+            cycles,
+            waiting: self.peers(),
+            // Nothing on the other end shifts in ones
+            received: [!0; MAX_UNITS],
+        });
+        let data = self.normal_data();
+        self.broadcast(&Message::Start {
+            multiplayer: false,
+            data,
+            cycles: cycles as u32,
+        });
+    }
+
+    fn start_multiplayer(&mut self) {
+        let peers = self.peers();
+        let units = 1 + peers.iter().filter(|&&peer| peer).count();
+        let cycles = units * MULTIPLAYER_FRAME_BITS * BAUD_BIT_CYCLES[(self.control & 0b11) as usize];
+        let mut received = [0xFFFF; MAX_UNITS];
+        received[0] = self.send as u32;
+        self.multi = [0xFFFF; 4];
+        self.transfer = Some(Transfer {
+            cycles,
+            waiting: peers,
+            received,
+        });
+        self.broadcast(&Message::Start {
+            multiplayer: true,
+            data: self.send as u32,
+            cycles: cycles as u32,
+        });
+    }
+
+    // Another unit started a transfer. Returns what we send back, if we take part.
+    fn start_remote(&mut self, multiplayer: bool, data: u32, cycles: u32) -> Option<u32> {
+        if self.transfer.is_some() {
+            return None;
+        }
+        match self.mode() {
+            Mode::Multiplayer if multiplayer => {
+                self.control |= 1 << CNT_START_BIT;
+                self.multi = [0xFFFF; 4];
+                // Everything arrives at once in the parent's Done
+                self.transfer = Some(Transfer {
+                    cycles: 0,
+                    waiting: [true, false, false, false],
+                    received: [0xFFFF; MAX_UNITS],
+                });
+                Some(self.send as u32)
+            }
+            Mode::Normal8 | Mode::Normal32
+                if !multiplayer && self.bit(CNT_START_BIT) && !self.bit(CNT_INTERNAL_CLOCK_BIT) =>
+            {
+                let mut received = [!0; MAX_UNITS];
+                received[0] = data;
+                self.transfer = Some(Transfer {
+                    cycles: cycles as usize,
+                    waiting: [false; MAX_UNITS],
+                    received,
+                });
+                Some(self.normal_data())
+            }
+            _ => None,
+        }
+    }
+
+    fn handle(&mut self, from: usize, message: Message) {
+        let mode = self.mode();
+        match message {
+            Message::Welcome(new_id) => {
+                if let Some(Link::Child { id, .. }) = &mut self.link {
+                    *id = new_id;
+                    println!("Link cable: connected as unit {}", new_id);
+                }
+            }
+            Message::Signal(level) => self.si = level,
+            Message::Start {
+                multiplayer,
+                data,
+                cycles,
+            } => {
+                let reply = self.start_remote(multiplayer, data, cycles);
+                self.send_to(from, &Message::Reply(reply));
+            }
+            Message::Reply(data) => {
+                if let Some(transfer) = &mut self.transfer {
+                    if transfer.waiting[from] {
+                        transfer.waiting[from] = false;
+                        let slot = if mode == Mode::Multiplayer { from } else { 0 };
+                        if let Some(data) = data {
+                            transfer.received[slot] = data;
+                        }
+                    }
+                }
+            }
+            Message::Done(data) => {
+                if let Some(transfer) = &mut self.transfer {
+                    if mode == Mode::Multiplayer && transfer.waiting[0] {
+                        transfer.waiting[0] = false;
+                        for (received, &unit) in transfer.received.iter_mut().zip(data.iter()) {
+                            *received = unit as u32;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn send_to(&mut self, to: usize, message: &Message) {
+        let result = match &mut self.link {
+            Some(Link::Parent { children, .. }) if to > 0 => match &mut children[to - 1] {
+                Some(child) => child.send(message),
+                None => return,
+            },
+            Some(Link::Child { parent, .. }) if to == 0 => parent.send(message),
+            _ => return,
+        };
+        if let Err(e) = result {
+            self.disconnect(to, e);
+        }
+    }
+
+    fn broadcast(&mut self, message: &Message) {
+        let peers = self.peers();
+        for (unit, &connected) in peers.iter().enumerate() {
+            if connected {
+                self.send_to(unit, message);
+            }
+        }
+    }
+
+    fn disconnect(&mut self, unit: usize, error: anyhow::Error) {
+        println!("Link cable: lost unit {}: {}", unit, error);
+        match &mut self.link {
+            Some(Link::Parent { children, .. }) if unit > 0 => children[unit - 1] = None,
+            _ => self.link = None,
+        }
+        // Don't wait forever on a unit that is gone
+        if let Some(transfer) = &mut self.transfer {
+            transfer.waiting[unit] = false;
+        }
+        self.si = true;
+    }
+
+    fn poll(&mut self) {
+        let mut messages = Vec::new();
+        let mut lost = Vec::new();
+        match &mut self.link {
+            Some(Link::Parent { listener, children }) => {
+                if let Some(listener) = listener {
+                    while let Ok(Some(mut transport)) = listener.accept() {
+                        // Anyone past the fourth unit is turned away
+                        if let Some(slot) = children.iter().position(|child| child.is_none()) {
+                            let id = slot + 1;
+                            if transport.send(&Message::Welcome(id as u8)).is_ok() {
+                                println!("Link cable: unit {} connected", id);
+                                children[slot] = Some(transport);
+                            }
+                        }
+                    }
+                }
+                for (slot, child) in children.iter_mut().enumerate() {
+                    if let Some(transport) = child {
+                        loop {
+                            match transport.try_recv() {
+                                Ok(Some(message)) => messages.push((slot + 1, message)),
+                                Ok(None) => break,
+                                Err(e) => {
+                                    lost.push((slot + 1, e));
+                                    break;
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            Some(Link::Child { parent, .. }) => loop {
+                match parent.try_recv() {
+                    Ok(Some(message)) => messages.push((0, message)),
+                    Ok(None) => break,
+                    Err(e) => {
+                        lost.push((0, e));
+                        break;
+                    }
+                }
+            },
+            None => {}
+        }
+        // Messages that arrived before a unit dropped still count
+        for (from, message) in messages {
+            self.handle(from, message);
+        }
+        for (unit, error) in lost {
+            self.disconnect(unit, error);
+        }
+    }
+
+    // Returns true when a transfer finishes with its IRQ enabled
+    pub fn step(&mut self, cycles: usize) -> bool {
+        if self.mode() == Mode::JoyBus {
+            return self.joybus.step(cycles);
+        }
+        if self.mode() == Mode::Uart && self.uart.step(cycles, self.control) {
+            return true;
+        }
+        self.poll_cycles += cycles;
+        let waiting = matches!(&self.transfer, Some(transfer) if transfer.waiting.iter().any(|&unit| unit));
+        if self.poll_cycles >= POLL_CYCLES || waiting {
+            self.poll_cycles = 0;
+            self.poll();
+        }
+        match &mut self.transfer {
+            Some(transfer) => {
+                transfer.cycles = transfer.cycles.saturating_sub(cycles);
+                if transfer.cycles > 0 || transfer.waiting.iter().any(|&unit| unit) {
+                    return false;
+                }
+            }
+            None => return false,
+        }
+        let transfer = self.transfer.take().unwrap();
+        self.finish(transfer)
+    }
+
+    // Data registers are all in place before the busy bit drops and the IRQ goes out
+    fn finish(&mut self, transfer: Transfer) -> bool {
+        match self.mode() {
+            Mode::Multiplayer => {
+                for (multi, &received) in self.multi.iter_mut().zip(transfer.received.iter()) {
+                    *multi = received as u16;
+                }
+                if self.is_parent() {
+                    let data = self.multi;
+                    self.broadcast(&Message::Done(data));
+                }
+            }
+            Mode::Normal32 => {
+                self.multi[0] = transfer.received[0] as u16;
+                self.multi[1] = (transfer.received[0] >> 16) as u16;
+            }
+            _ => self.send = self.send & 0xFF00 | transfer.received[0] as u16 & 0xFF,
+        }
+        self.control &= !(1 << CNT_START_BIT);
+        self.bit(CNT_IRQ_BIT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MULTIPLAYER_115200: u16 = 2 << CNT_MODE_SHIFT | 1 << CNT_IRQ_BIT | 0b11;
+
+    fn unit(link: Link, send: u16) -> Sio {
+        let mut sio = Sio::new();
+        sio.attach(link);
+        sio.write(REG_SIOMLT_SEND, send, 0xFFFF);
+        sio.write(REG_SIOCNT, MULTIPLAYER_115200, 0xFFFF);
+        sio
+    }
+
+    #[test]
+    fn multiplayer_transfer_over_local_link() {
+        let mut links = Link::local(2);
+        let mut child = unit(links.pop().unwrap(), 0x2222);
+        let mut parent = unit(links.pop().unwrap(), 0x1111);
+        assert_eq!(parent.read(REG_SIOCNT) >> CNT_ID_SHIFT & 0b11, 0);
+        assert_eq!(child.read(REG_SIOCNT) >> CNT_ID_SHIFT & 0b11, 1);
+        assert_eq!(parent.read(REG_SIOCNT) >> CNT_SO_BIT & 1, 1);
+
+        parent.write(REG_SIOCNT, MULTIPLAYER_115200 | 1 << CNT_START_BIT, 0xFFFF);
+        let mut irqs = Vec::new();
+        for _ in 0..1000 {
+            for (id, sio) in [&mut parent, &mut child].iter_mut().enumerate() {
+                if sio.step(64) {
+                    // SIOMULTI is filled in and the busy bit is down by the time the IRQ fires
+                    assert_eq!(sio.read(REG_SIOCNT) >> CNT_START_BIT & 1, 0);
+                    let multi: Vec<u16> = (0..4).map(|unit| sio.read(2 * unit)).collect();
+                    irqs.push((id, multi));
+                }
+            }
+        }
+        let expected = vec![0x1111, 0x2222, 0xFFFF, 0xFFFF];
+        assert_eq!(irqs, vec![(0, expected.clone()), (1, expected)]);
+    }
+
+    #[test]
+    fn children_cannot_start_a_multiplayer_transfer() {
+        let mut links = Link::local(2);
+        let mut child = unit(links.pop().unwrap(), 0x2222);
+        child.write(REG_SIOCNT, MULTIPLAYER_115200 | 1 << CNT_START_BIT, 0xFFFF);
+        assert_eq!(child.read(REG_SIOCNT) >> CNT_START_BIT & 1, 0);
+        assert!(!child.step(100_000));
+    }
+}
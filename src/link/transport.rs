@@ -0,0 +1,245 @@
+use anyhow::{anyhow, bail, Context, Result};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::thread;
+
+// Addresses are host:port for TCP, or unix:<path> for a Unix socket
+const UNIX_PREFIX: &str = "unix:";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Message {
+    // Parent to a newly connected unit: its multiplayer ID
+    Welcome(u8),
+    // Level of our SO line, which the other side reads as SI in normal mode
+    Signal(bool),
+    // A transfer started: the sender's outgoing data and how many cycles it takes
+    Start { multiplayer: bool, data: u32, cycles: u32 },
+    // The receiver's outgoing data, or None if it wasn't ready to transfer
+    Reply(Option<u32>),
+    // What every unit sent in a multiplayer transfer
+    Done([u16; 4]),
+}
+
+impl Message {
+    // Frames are a type byte, a length byte and then the payload
+    fn encode(&self) -> Vec<u8> {
+        let (tag, payload) = match *self {
+            Message::Welcome(id) => (0, vec![id]),
+            Message::Signal(level) => (1, vec![level as u8]),
+            Message::Start { multiplayer, data, cycles } => {
+                let mut payload = vec![multiplayer as u8];
+                payload.extend_from_slice(&data.to_le_bytes());
+                payload.extend_from_slice(&cycles.to_le_bytes());
+                (2, payload)
+            }
+            Message::Reply(data) => {
+                let mut payload = vec![data.is_some() as u8];
+                payload.extend_from_slice(&data.unwrap_or(!0).to_le_bytes());
+                (3, payload)
+            }
+            Message::Done(data) => (4, data.iter().flat_map(|unit| unit.to_le_bytes().to_vec()).collect()),
+        };
+        let mut frame = vec![tag, payload.len() as u8];
+        frame.extend(payload);
+        frame
+    }
+
+    fn decode(tag: u8, payload: &[u8]) -> Result<Self> {
+        let expected = match tag {
+            0 | 1 => 1,
+            2 => 9,
+            3 => 5,
+            4 => 8,
+            _ => bail!("unknown link message type {}", tag),
+        };
+        if payload.len() != expected {
+            bail!("link message type {} should be {} bytes, got {}", tag, expected, payload.len());
+        }
+        let word = |i: usize| u32::from_le_bytes([payload[i], payload[i + 1], payload[i + 2], payload[i + 3]]);
+        Ok(match tag {
+            0 => Message::Welcome(payload[0]),
+            1 => Message::Signal(payload[0] != 0),
+            2 => Message::Start {
+                multiplayer: payload[0] != 0,
+                data: word(1),
+                cycles: word(5),
+            },
+            3 => Message::Reply(if payload[0] != 0 { Some(word(1)) } else { None }),
+            _ => {
+                let mut data = [0; 4];
+                for (i, unit) in data.iter_mut().enumerate() {
+                    *unit = u16::from_le_bytes([payload[i * 2], payload[i * 2 + 1]]);
+                }
+                Message::Done(data)
+            }
+        })
+    }
+}
+
+// One end of a point to point connection. Neither call waits on the other side.
+pub trait Transport {
+    fn send(&mut self, message: &Message) -> Result<()>;
+    fn try_recv(&mut self) -> Result<Option<Message>>;
+}
+
+// For instances running in the same process
+pub struct ChannelTransport {
+    sender: Sender<Message>,
+    receiver: Receiver<Message>,
+}
+
+impl ChannelTransport {
+    pub fn pair() -> (Self, Self) {
+        let (a_sender, b_receiver) = channel();
+        let (b_sender, a_receiver) = channel();
+        (
+            ChannelTransport {
+                sender: a_sender,
+                receiver: a_receiver,
+            },
+            ChannelTransport {
+                sender: b_sender,
+                receiver: b_receiver,
+            },
+        )
+    }
+}
+
+impl Transport for ChannelTransport {
+    fn send(&mut self, message: &Message) -> Result<()> {
+        self.sender.send(message.clone()).map_err(|_| anyhow!("other side hung up"))
+    }
+
+    fn try_recv(&mut self) -> Result<Option<Message>> {
+        match self.receiver.try_recv() {
+            Ok(message) => Ok(Some(message)),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => bail!("other side hung up"),
+        }
+    }
+}
+
+// A non-blocking TCP or Unix socket
+pub struct StreamTransport<S> {
+    stream: S,
+    buffer: Vec<u8>,
+    closed: bool,
+}
+
+impl<S: Read + Write> StreamTransport<S> {
+    fn new(stream: S) -> Self {
+        StreamTransport {
+            stream,
+            buffer: Vec::new(),
+            closed: false,
+        }
+    }
+}
+
+impl<S: Read + Write> Transport for StreamTransport<S> {
+    fn send(&mut self, message: &Message) -> Result<()> {
+        let frame = message.encode();
+        let mut written = 0;
+        while written < frame.len() {
+            match self.stream.write(&frame[written..]) {
+                Ok(0) => bail!("other side hung up"),
+                Ok(count) => written += count,
+                // Frames are tiny, so a full socket buffer drains almost at once
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::Interrupted => {
+                    thread::yield_now()
+                }
+                Err(e) => return Err(e).context("could not send to the other side"),
+            }
+        }
+        Ok(())
+    }
+
+    fn try_recv(&mut self) -> Result<Option<Message>> {
+        let mut chunk = [0; 64];
+        while !self.closed {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => self.closed = true,
+                Ok(count) => self.buffer.extend_from_slice(&chunk[..count]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e).context("could not read from the other side"),
+            }
+        }
+        // Hand over whatever full frames arrived before the other side closed
+        if self.buffer.len() < 2 || self.buffer.len() < 2 + self.buffer[1] as usize {
+            if self.closed {
+                bail!("other side hung up");
+            }
+            return Ok(None);
+        }
+        let len = 2 + self.buffer[1] as usize;
+        let message = Message::decode(self.buffer[0], &self.buffer[2..len])?;
+        self.buffer.drain(..len);
+        Ok(Some(message))
+    }
+}
+
+pub fn connect(address: &str) -> Result<Box<dyn Transport>> {
+    #[cfg(unix)]
+    {
+        if let Some(path) = address.strip_prefix(UNIX_PREFIX) {
+            let stream = UnixStream::connect(path).with_context(|| format!("could not connect to {}", address))?;
+            stream.set_nonblocking(true)?;
+            return Ok(Box::new(StreamTransport::new(stream)));
+        }
+    }
+    let stream = TcpStream::connect(address).with_context(|| format!("could not connect to {}", address))?;
+    stream.set_nodelay(true)?;
+    stream.set_nonblocking(true)?;
+    Ok(Box::new(StreamTransport::new(stream)))
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    pub fn bind(address: &str) -> Result<Self> {
+        #[cfg(unix)]
+        {
+            if let Some(path) = address.strip_prefix(UNIX_PREFIX) {
+                // A socket file left behind by an earlier run would make bind fail
+                if std::path::Path::new(path).exists() {
+                    std::fs::remove_file(path).with_context(|| format!("could not remove old socket {}", path))?;
+                }
+                let listener = UnixListener::bind(path).with_context(|| format!("could not listen on {}", address))?;
+                listener.set_nonblocking(true)?;
+                return Ok(Listener::Unix(listener));
+            }
+        }
+        let listener = TcpListener::bind(address).with_context(|| format!("could not listen on {}", address))?;
+        listener.set_nonblocking(true)?;
+        Ok(Listener::Tcp(listener))
+    }
+
+    // Returns None when nobody is waiting to connect
+    pub fn accept(&self) -> Result<Option<Box<dyn Transport>>> {
+        let result: std::io::Result<Box<dyn Transport>> = match self {
+            Listener::Tcp(listener) => listener.accept().and_then(|(stream, _)| {
+                stream.set_nodelay(true)?;
+                stream.set_nonblocking(true)?;
+                Ok(Box::new(StreamTransport::new(stream)) as Box<dyn Transport>)
+            }),
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener.accept().and_then(|(stream, _)| {
+                stream.set_nonblocking(true)?;
+                Ok(Box::new(StreamTransport::new(stream)) as Box<dyn Transport>)
+            }),
+        };
+        match result {
+            Ok(transport) => Ok(Some(transport)),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e).context("could not accept a link connection"),
+        }
+    }
+}
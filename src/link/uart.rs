@@ -0,0 +1,230 @@
+use anyhow::{Context, Result};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Read, Write};
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+
+use crate::link::sio::BAUD_BIT_CYCLES;
+
+const CNT_CTS_BIT: u8 = 2;
+const CNT_SEND_FULL_BIT: u8 = 4;
+const CNT_RECEIVE_EMPTY_BIT: u8 = 5;
+const CNT_EIGHT_BITS_BIT: u8 = 7;
+const CNT_FIFO_BIT: u8 = 8;
+const CNT_PARITY_BIT: u8 = 9;
+const CNT_SEND_BIT: u8 = 10;
+const CNT_RECEIVE_BIT: u8 = 11;
+const CNT_IRQ_BIT: u8 = 14;
+const CNT_STATUS: u16 = 0b0111_0000; // send full, receive empty and error
+
+const FIFO_SIZE: usize = 4;
+const FILE_PREFIX: &str = "file:";
+
+fn bit(control: u16, bit: u8) -> bool {
+    control >> bit & 1 == 1
+}
+
+// Start bit, data, optional parity and a stop bit
+fn frame_cycles(control: u16) -> usize {
+    let bits = 2 + if bit(control, CNT_EIGHT_BITS_BIT) { 8 } else { 7 } + bit(control, CNT_PARITY_BIT) as usize;
+    bits * BAUD_BIT_CYCLES[(control & 0b11) as usize]
+}
+
+// Where the other end of the serial line goes: "stdio", "file:<path>" for output only,
+// or the path of a terminal device such as a pseudo-terminal made with socat
+pub struct HostPort {
+    output: Box<dyn Write>,
+    input: Option<Receiver<u8>>,
+}
+
+impl HostPort {
+    pub fn open(spec: &str) -> Result<Self> {
+        if spec == "stdio" {
+            return Ok(HostPort {
+                output: Box::new(io::stdout()),
+                input: Some(spawn_reader(io::stdin())),
+            });
+        }
+        if let Some(path) = spec.strip_prefix(FILE_PREFIX) {
+            let file = File::create(path).with_context(|| format!("could not create {}", path))?;
+            return Ok(HostPort {
+                output: Box::new(file),
+                input: None,
+            });
+        }
+        let device = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(spec)
+            .with_context(|| format!("could not open serial device {}", spec))?;
+        let reader = device.try_clone()?;
+        Ok(HostPort {
+            output: Box::new(device),
+            input: Some(spawn_reader(reader)),
+        })
+    }
+
+    fn write(&mut self, byte: u8) {
+        // A host that stops listening just drops the output, like an unplugged cable
+        let _ = self.output.write_all(&[byte]).and_then(|_| self.output.flush());
+    }
+
+    fn try_read(&mut self) -> Option<u8> {
+        self.input.as_ref()?.try_recv().ok()
+    }
+}
+
+// Reads block, so they happen on their own thread
+fn spawn_reader(reader: impl Read + Send + 'static) -> Receiver<u8> {
+    let (sender, receiver) = channel();
+    thread::spawn(move || {
+        for byte in BufReader::new(reader).bytes() {
+            match byte {
+                Ok(byte) if sender.send(byte).is_ok() => {}
+                _ => break,
+            }
+        }
+    });
+    receiver
+}
+
+#[derive(Default)]
+pub struct Uart {
+    port: Option<HostPort>,
+    send: VecDeque<u8>,
+    receive: VecDeque<u8>,
+    send_cycles: usize,
+    shifting: Option<u8>,
+    receive_cycles: usize,
+}
+
+impl Uart {
+    pub fn new() -> Self {
+        Uart {
+            port: None,
+            send: VecDeque::new(),
+            receive: VecDeque::new(),
+            send_cycles: 0,
+            shifting: None,
+            receive_cycles: 0,
+        }
+    }
+
+    pub fn attach(&mut self, port: HostPort) {
+        self.port = Some(port);
+    }
+
+    fn capacity(control: u16) -> usize {
+        if bit(control, CNT_FIFO_BIT) {
+            FIFO_SIZE
+        } else {
+            1
+        }
+    }
+
+    pub fn status(&self, control: u16) -> u16 {
+        let full = self.send.len() >= Uart::capacity(control);
+        let empty = self.receive.is_empty();
+        control & !CNT_STATUS | (full as u16) << CNT_SEND_FULL_BIT | (empty as u16) << CNT_RECEIVE_EMPTY_BIT
+    }
+
+    pub fn control_written(&mut self, old: u16, control: u16) {
+        // Turning the FIFO off empties it
+        if bit(old, CNT_FIFO_BIT) && !bit(control, CNT_FIFO_BIT) {
+            self.send.clear();
+            self.receive.clear();
+        }
+    }
+
+    // SIODATA8 shows the oldest byte received, and a CPU load of it takes it off the queue
+    pub fn read_data(&self) -> u16 {
+        self.receive.front().copied().unwrap_or(0) as u16
+    }
+
+    pub fn data_loaded(&mut self) {
+        self.receive.pop_front();
+    }
+
+    // SIODATA8 is the low byte, so a store to just the high byte sends nothing
+    pub fn write_data(&mut self, data: u16, mask: u16, control: u16) {
+        if mask & 0xFF != 0 && self.send.len() < Uart::capacity(control) {
+            self.send.push_back(data as u8);
+        }
+    }
+
+    // Returns true when a byte arrives, or the last queued byte has gone out, with the IRQ enabled
+    pub fn step(&mut self, cycles: usize, control: u16) -> bool {
+        let mut irq = false;
+
+        // Nobody can assert CTS without a host on the other end
+        let clear_to_send = !bit(control, CNT_CTS_BIT) || self.port.is_some();
+        if self.shifting.is_none() && bit(control, CNT_SEND_BIT) && clear_to_send {
+            self.shifting = self.send.pop_front();
+            self.send_cycles = frame_cycles(control);
+        }
+        if let Some(byte) = self.shifting {
+            self.send_cycles = self.send_cycles.saturating_sub(cycles);
+            if self.send_cycles == 0 {
+                let byte = if bit(control, CNT_EIGHT_BITS_BIT) { byte } else { byte & 0x7F };
+                if let Some(port) = &mut self.port {
+                    port.write(byte);
+                }
+                self.shifting = None;
+                irq |= self.send.is_empty();
+            }
+        }
+
+        self.receive_cycles = self.receive_cycles.saturating_sub(cycles);
+        let room = self.receive.len() < Uart::capacity(control);
+        if bit(control, CNT_RECEIVE_BIT) && self.receive_cycles == 0 && room {
+            if let Some(byte) = self.port.as_mut().and_then(|port| port.try_read()) {
+                self.receive.push_back(byte);
+                // The next byte can't arrive any sooner than a frame later
+                self.receive_cycles = frame_cycles(control);
+                irq = true;
+            }
+        }
+
+        irq && bit(control, CNT_IRQ_BIT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RECEIVE_115200: u16 = 1 << CNT_RECEIVE_BIT | 1 << CNT_FIFO_BIT | 1 << CNT_EIGHT_BITS_BIT | 0b11;
+
+    #[test]
+    fn only_loads_take_received_bytes() {
+        let (sender, receiver) = channel();
+        let mut uart = Uart::new();
+        uart.attach(HostPort {
+            output: Box::new(io::sink()),
+            input: Some(receiver),
+        });
+        for &byte in b"hi".iter() {
+            sender.send(byte).unwrap();
+        }
+        uart.step(1, RECEIVE_115200);
+        uart.step(frame_cycles(RECEIVE_115200), RECEIVE_115200);
+        assert_eq!(uart.read_data(), b'h' as u16);
+        assert_eq!(uart.read_data(), b'h' as u16);
+        uart.data_loaded();
+        assert_eq!(uart.read_data(), b'i' as u16);
+        uart.data_loaded();
+        assert_eq!(uart.status(RECEIVE_115200) >> CNT_RECEIVE_EMPTY_BIT & 1, 1);
+    }
+
+    #[test]
+    fn only_stores_to_the_low_byte_send() {
+        let mut uart = Uart::new();
+        let control = 1 << CNT_FIFO_BIT;
+        uart.write_data(0x4142, 0xFF00, control);
+        assert!(uart.send.is_empty());
+        uart.write_data(0x0043, 0x00FF, control);
+        uart.write_data(0x4445, 0xFFFF, control);
+        assert_eq!(uart.send, [0x43, 0x45]);
+    }
+}
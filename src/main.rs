@@ -1,62 +1,263 @@
 pub mod arm;
 pub mod audio;
+pub mod cart;
+pub mod cheat;
 pub mod graphics;
-extern crate gio;
+pub mod input;
+pub mod link;
 
-use rodio::OutputStream;
-use gio::prelude::*;
-
-use std::{env, thread::sleep};
+use std::env;
 use std::fs::File;
 use std::fs;
-use std::io::Read;
-use std::time::{Duration, Instant};
-use graphics::gpu::{draw};
-use anyhow::Result;
-use arm::{cpu, mem};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use graphics::gpu::Gpu;
+use graphics::sink::{PngSink, RecordingSink, SdlSink, VideoSink};
+use anyhow::{bail, Context, Result};
+use arm::mem;
 use arm::cpu::Cpu;
-use audio::apu::APU;
+use cart::archive;
+use cart::backup::SaveType;
+use cart::cartridge::Cartridge;
+use cart::patch;
+use cart::rtc::ClockSource;
+use cheat::list::CheatList;
+use cheat::search;
+use input::{Input, KeyBindings};
+use link::joybus::ConsolePort;
+use link::sio::Link;
+use link::uart::HostPort;
+
+const FRAME_CYCLES: usize = 280_896;
 
 fn main() -> Result<()> {
     let args: Vec<String> = env::args().collect();
 
-    //let mut f = File::open("memdump.txt").expect("no file found");
+    let mut save_type_override = None;
+    let mut rtc_clock = None;
+    let mut patch_path = None;
+    let mut rom_entry = None;
+    let mut bindings_path = None;
+    let mut link = None;
+    let mut serial_spec = None;
+    let mut console_port = None;
+    let mut cheats_path = None;
+    let mut ram_search_console = false;
+    let mut headless = false;
+    let mut screenshot_dir = None;
+    let mut record_path = None;
+    let mut options = args.iter().skip(3);
+    while let Some(option) = options.next() {
+        match option.as_str() {
+            "--save-type" => {
+                let save_type = options.next().context("--save-type needs a value")?;
+                save_type_override = Some(save_type.parse::<SaveType>()?);
+            }
+            "--rtc" => {
+                let clock = options.next().context("--rtc needs a value")?;
+                rtc_clock = Some(clock.parse::<ClockSource>()?);
+            }
+            "--patch" => {
+                let path = options.next().context("--patch needs a value")?;
+                patch_path = Some(PathBuf::from(path));
+            }
+            "--rom-entry" => {
+                let entry = options.next().context("--rom-entry needs a value")?;
+                rom_entry = Some(entry.as_str());
+            }
+            "--bindings" => {
+                let path = options.next().context("--bindings needs a value")?;
+                bindings_path = Some(PathBuf::from(path));
+            }
+            // host:port, or unix:<path> for a Unix socket
+            "--link-host" => {
+                let address = options.next().context("--link-host needs an address")?;
+                link = Some(Link::listen(address)?);
+            }
+            "--link-join" => {
+                let address = options.next().context("--link-join needs an address")?;
+                link = Some(Link::connect(address)?);
+            }
+            // stdio, file:<path>, or a terminal device to bridge UART mode to
+            "--serial" => {
+                let spec = options.next().context("--serial needs a value")?;
+                serial_spec = Some(spec.as_str());
+            }
+            // host:port for the console side of JOY Bus mode to connect to
+            "--joybus" => {
+                let address = options.next().context("--joybus needs an address")?;
+                console_port = Some(ConsolePort::listen(address)?);
+            }
+            "--cheats" => {
+                let path = options.next().context("--cheats needs a value")?;
+                cheats_path = Some(PathBuf::from(path));
+            }
+            // Take RAM search commands on stdin, see cheat::search::command
+            "--ram-search" => ram_search_console = true,
+            // No window, for running under scripts and CI
+            "--headless" => headless = true,
+            // Every frame as a PNG in this directory
+            "--screenshots" => {
+                let path = options.next().context("--screenshots needs a directory")?;
+                screenshot_dir = Some(PathBuf::from(path));
+            }
+            // Raw RGBA frames, see graphics::sink::RecordingSink
+            "--record" => {
+                let path = options.next().context("--record needs a value")?;
+                record_path = Some(PathBuf::from(path));
+            }
+            _ => bail!("unknown option {}", option),
+        }
+    }
 
-    let sdl_context = sdl2::init().unwrap();
-    let video_subsystem = sdl_context.video().unwrap();
+    // Both would read lines from stdin, and each would only get some of them
+    if ram_search_console && serial_spec == Some("stdio") {
+        bail!("--serial stdio and --ram-search both read stdin, so they can't be used together");
+    }
+    let serial_port = serial_spec.map(HostPort::open).transpose()?;
+    let search_console = if ram_search_console { Some(search::spawn_console()) } else { None };
 
-    let window = video_subsystem.window("rust-sdl2 demo", 960, 640)
-        .position_centered()
-        .build()
-        .unwrap();
+    //let mut f = File::open("memdump.txt").expect("no file found");
 
-    let mut canvas = window.into_canvas().build().unwrap();
-    canvas.clear();
-    canvas.present();
+    let bindings = match &bindings_path {
+        Some(path) => KeyBindings::load(path)?,
+        None => KeyBindings::default(),
+    };
+
+    // Headless runs don't touch SDL at all, so they work without a display
+    let (mut input, mut canvas) = if headless {
+        (None, None)
+    } else {
+        let sdl_context = sdl2::init().unwrap();
+        let video_subsystem = sdl_context.video().unwrap();
+        let input = Input::new(&sdl_context, bindings)?;
+        let window = video_subsystem.window("rust-sdl2 demo", 960, 640)
+            .position_centered()
+            .build()
+            .unwrap();
+        let mut canvas = window.into_canvas().build().unwrap();
+        canvas.clear();
+        canvas.present();
+        (Some(input), Some(canvas))
+    };
 
     let mut cpu = Cpu::new();
     let mut ram = mem::Mem::new(235_000_000);
     println!("Loading memory...");
-    ram.load(0, File::open(&args[1]).unwrap()).unwrap();
-    ram.load(0x8000000, File::open(&args[2]).unwrap()).unwrap();
+    let bios = archive::read_image(Path::new(&args[1]), None, "bin")?;
+    ram.load(0, &bios[..])?;
+    let mut rom = archive::read_image(Path::new(&args[2]), rom_entry, "gba")?;
+    if let Some(path) = patch_path.or_else(|| patch::find_patch(Path::new(&args[2]))) {
+        println!("Applying patch {}", path.display());
+        let data = fs::read(&path).with_context(|| format!("could not read patch {}", path.display()))?;
+        rom = patch::apply(rom, &data).with_context(|| format!("could not apply patch {}", path.display()))?;
+    }
+    let cart = Cartridge::new(rom)?;
+    println!("Cartridge: {} ({}), maker {}, version {}", cart.title, cart.game_code, cart.maker_code, cart.version);
+    for warning in cart.validate() {
+        println!("Warning: {}", warning);
+    }
+    if let Some(canvas) = &mut canvas {
+        canvas.window_mut().set_title(&cart.window_title())?;
+    }
+    ram.load_rom(cart.rom());
+
+    let settings = cart.settings()?;
+    let save_type = save_type_override
+        .or(settings.save_type)
+        .unwrap_or_else(|| SaveType::detect(cart.rom()));
+    println!("Save type: {:?}", save_type);
+    ram.set_save_type(save_type);
+    if let Some(clock) = rtc_clock.or(settings.rtc) {
+        ram.attach_rtc(clock);
+    }
+    if let Some(link) = link {
+        ram.attach_link(link);
+    }
+    if let Some(port) = serial_port {
+        ram.attach_serial_port(port);
+    }
+    if let Some(port) = console_port {
+        ram.attach_joybus(port);
+    }
+    let mut cheats = match &cheats_path {
+        Some(path) => CheatList::load(path)?,
+        None => CheatList::for_game(&cart.game_code)?,
+    };
+    for skipped in cheats.skipped() {
+        println!("Warning: {}", skipped);
+    }
+    for (index, cheat) in cheats.cheats().iter().enumerate() {
+        println!("Cheat F{}: {} ({})", index + 1, cheat.name, if cheats.is_enabled(index) { "on" } else { "off" });
+    }
+    let save_path = cart.save_path(Path::new(&args[2]));
+    if save_path.exists() {
+        ram.backup_mut().load(File::open(&save_path)?)?;
+    }
     /*for x in 0x06000000..0x06017FFF{
         print!("{}", memory[x]);
     }*/
     println!("Starting simulation.");
     cpu.reset();
     //cpu.toggle_debug();
-    let two_clock_cycles = Duration::from_nanos(5);
     let gpu_cycle_start = Instant::now();
     //let (_stream, stream_handle) = OutputStream::try_default().unwrap();
     //let mut apu = APU::new(&stream_handle);
+    let mut gpu = Gpu::new();
+    let mut video: Vec<Box<dyn VideoSink>> = Vec::new();
+    if let Some(canvas) = canvas {
+        video.push(Box::new(SdlSink::new(canvas)));
+    }
+    if let Some(dir) = &screenshot_dir {
+        video.push(Box::new(PngSink::new(dir, 1)?));
+    }
+    if let Some(path) = &record_path {
+        video.push(Box::new(RecordingSink::create(path)?));
+    }
+    let mut ram_search = None;
     let mut cycles = 0;
+    // pc stays put while the CPU is halted or stalled, so only arriving at the hook counts
+    let mut last_pc = None;
     while cycles < 100_000_000 {
-        if cpu.step(&mut ram, cycles).is_none() {
+        if !ram.stalled() && cpu.step(&mut ram, cycles).is_none() {
             break;
         }
-        if cycles % 100 == 0 {
-            draw(&mut ram, cycles, &mut canvas);
+        let pc = cpu.pc();
+        if last_pc != Some(pc) && cheats.hook() == Some(pc) {
+            cheats.apply(&mut ram);
         }
+        last_pc = Some(pc);
+        if cycles % FRAME_CYCLES == 0 {
+            if let Some(input) = &mut input {
+                if !input.poll(&mut ram) {
+                    break;
+                }
+                for index in input.take_cheat_toggles() {
+                    if let Some(enabled) = cheats.toggle(index, &mut ram) {
+                        println!("Cheat {}: {}", cheats.cheats()[index].name, if enabled { "on" } else { "off" });
+                    }
+                }
+            }
+            if cheats.hook().is_none() {
+                cheats.apply(&mut ram);
+            }
+            if let Some(console) = &search_console {
+                for line in console.try_iter() {
+                    match search::command(&mut ram_search, &ram, &line) {
+                        Ok(output) => println!("{}", output),
+                        Err(e) => println!("Error: {}", e),
+                    }
+                }
+            }
+        }
+        // Stop rather than return so the save below still happens
+        if gpu.step(&mut ram, 2) {
+            if let Err(e) = video.frame(gpu.frame()) {
+                println!("Error: {:#}", e);
+                break;
+            }
+        }
+        ram.step(2);
         //apu.step(&ram);
         cycles += 2;
     }
@@ -65,6 +266,9 @@ fn main() -> Result<()> {
     println!("Ran {} cycles", cycles);
 
     println!("Saving state.");
+    if save_type != SaveType::None {
+        ram.backup().save(File::create(&save_path)?)?;
+    }
     let file = File::create("logs/wram_dump.hex").unwrap();
     ram.save(0x3000000, 0x3007FFF, file).unwrap();
     let file = File::create("logs/palette_dump.hex").unwrap();